@@ -1,30 +1,392 @@
 use cpal::traits::{DeviceTrait, HostTrait};
 use crossterm::{
+    cursor,
     event::{self, Event, KeyCode, KeyEventKind},
-    execute,
-    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+    execute, queue,
+    style::Print,
+    terminal::{
+        self, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
+    },
 };
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{self, BufReader, Read};
+use std::io::{self, BufReader, Read, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 
 use cpal::traits::StreamTrait;
-use ush::audio::{AudioConfig, AudioManager};
-use ush::cli::{AudioSettings, TestCommands};
-use ush::debug::{DebugAnalyzer, DebugAudioBuffer, DebugConfig};
+use ush::audio::{
+    AudioConfig, AudioLink, AudioManager, InputRing, InputRingWriter, input_ring, tap_and_mute,
+};
+use ush::audio_file::{
+    DEFAULT_MP3_BITRATE, DecodedAudio, decode_audio, encode_mp3, load_audio_file, read_audio_file,
+    resample, write_audio_file,
+};
+use ush::base64;
+use ush::carrier::{CarrierSense, CarrierSenseConfig, CarrierStats};
+use ush::cli::{AudioSettings, IdentityCommands, OutputFormat, TestCommands, validate_bitrate};
+use ush::crypto::{PresharedKey, save_session_key, session_key_path};
+use ush::debug::{
+    DebugAnalyzer, DebugAudioBuffer, DebugConfig, scan_channels, write_comparison_report,
+};
+use ush::fec::ConvolutionalCode;
+use ush::identity::{
+    Identity, Sender, TrustStore, from_hex, identity_path, known_peers_path, to_hex,
+};
+use ush::inspector::{InspectEvent, Inspector};
 use ush::modulation::{
-    FskDemodulator, FskModulator, ModulationConfig, apply_bandpass_filter, detect_signal_start,
+    BandpassFilter, Channel, FskDemodulator, FskModulator, MAX_PRE_EMPHASIS_DB, ModulationConfig,
+    SymbolPowers, apply_bandpass_filter, tone_balance,
+};
+use ush::output::EventRecord;
+use ush::pairing::{PairingConfig, PairingRole};
+use ush::protocol::{
+    Address, BROADCAST, DATA_CHUNK_SIZE, Message, MessageType, ProtocolDecoder, ProtocolEncoder,
+    ReplayPolicy,
 };
-use ush::protocol::{Message, MessageType, ProtocolDecoder, ProtocolEncoder};
+use ush::quality::{LinkQuality, LinkQualityMonitor};
+use ush::receiver::{MessageReceiver, ReceiverEvent};
+use ush::stego::{Embedder, Extractor, StegoConfig};
+use ush::tx_level::{LISTEN_WINDOW, TxLevel};
+use ush::visualizer::{Panels, Visualizer};
 use ush::{UshError, UshResult};
 
+/// Seconds of audio the input ring can hold before the callback starts dropping samples
+const INPUT_RING_SECONDS: usize = 10;
+
+/// How often `listen` logs the measured link quality
+const LINK_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bitrates `ush test codec` tries when none are given
+const CODEC_TEST_BITRATES: [u32; 6] = [96, 128, 160, 192, 256, 320];
+
+/// Symbols per run of one tone in `ush test calibrate`
+const CALIBRATION_RUN: usize = 20;
+
+/// Tone imbalance in a link report worth suggesting pre-emphasis for
+const TONE_BALANCE_HINT_DB: f32 = 3.0;
+
+/// Signal detection threshold for chat and debug, the same default `listen` uses
+const DEFAULT_THRESHOLD: f32 = 0.1;
+
+/// Options for [`UshApp::listen_for_messages`]
+pub struct ListenOptions<'a> {
+    pub timeout_secs: Option<u32>,
+    pub save_wav: Option<&'a Path>,
+    pub from_file: Option<&'a Path>,
+    pub filter: bool,
+    pub threshold: f32,
+    pub debug: bool,
+    pub debug_output: Option<&'a Path>,
+    pub format: OutputFormat,
+}
+
+/// What the decode thread hands back once listening stops
+struct DecodeTaskOutput {
+    recording: Option<Vec<f32>>,
+    debug_buffer: Option<DebugAudioBuffer>,
+}
+
+/// Microphone capture feeding a decode thread, shared by listen and chat
+struct LiveReceiver {
+    input_stream: cpal::Stream,
+    /// Copy of the captured audio for carrier sense
+    tap: Option<InputRing>,
+    events: mpsc::UnboundedReceiver<ReceiverEvent>,
+    stop: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
+    /// Measured by the decode thread from everything it captures
+    link: Arc<Mutex<LinkQualityMonitor>>,
+    task: thread::JoinHandle<DecodeTaskOutput>,
+}
+
+impl LiveReceiver {
+    /// Events the decode thread produced since the last call
+    fn drain_events(&mut self) -> Vec<ReceiverEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn link_quality(&self) -> LinkQuality {
+        self.link.lock().unwrap().quality()
+    }
+
+    /// Stop capturing, wait for the decode thread and collect its final events
+    fn finish(mut self) -> UshResult<(DecodeTaskOutput, Vec<ReceiverEvent>)> {
+        drop(self.input_stream);
+        self.stop.store(true, Ordering::Relaxed);
+        let output = self.task.join().map_err(|_| UshError::Decoding {
+            message: "Decode task panicked".to_string(),
+        })?;
+
+        let mut events = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            events.push(event);
+        }
+        Ok((output, events))
+    }
+}
+
+/// The default speaker and microphone as an [`AudioLink`]
+struct DeviceLink<'a> {
+    app: &'a UshApp,
+    _input_stream: cpal::Stream,
+    ring: InputRing,
+}
+
+impl AudioLink for DeviceLink<'_> {
+    async fn transmit(&mut self, samples: &[f32]) -> UshResult<()> {
+        self.app.play_samples(samples).await?;
+
+        // Drop what the microphone picked up of our own transmission
+        let mut echo = Vec::new();
+        self.ring.pop_into(&mut echo);
+        Ok(())
+    }
+
+    async fn capture(&mut self) -> UshResult<Vec<f32>> {
+        let mut samples = Vec::new();
+        self.ring.pop_into(&mut samples);
+        Ok(samples)
+    }
+}
+
+/// Log the frames `receiver` ignored or rejected; `hint` suggests
+/// `--allow-replay`, which only helps with recordings
+fn log_receiver_summary(receiver: &MessageReceiver, hint: bool) {
+    if receiver.duplicates_dropped() > 0 {
+        info!(
+            "Ignored {} repeated frame(s)",
+            receiver.duplicates_dropped()
+        );
+    }
+    if receiver.not_addressed() > 0 {
+        info!(
+            "Ignored {} frame(s) for other nodes or groups (see --monitor-all)",
+            receiver.not_addressed()
+        );
+    }
+    if receiver.replays_rejected() > 0 {
+        let hint = if hint {
+            "; use --allow-replay for old recordings"
+        } else {
+            ""
+        };
+        warn!(
+            "Rejected {} replayed or stale frame(s){}",
+            receiver.replays_rejected(),
+            hint
+        );
+    }
+}
+
+/// Everything currently buffered in `ring`
+fn drain_ring(ring: &mut InputRing) -> Vec<f32> {
+    let mut samples = Vec::new();
+    ring.pop_into(&mut samples);
+    samples
+}
+
+/// How a frame fared through a lossy codec in `ush test codec`
+enum CodecOutcome {
+    Decoded {
+        snr_db: f32,
+    },
+    /// A transmission was detected but the frame did not decode
+    Garbled,
+    /// Nothing was detected
+    Silent,
+}
+
+impl std::fmt::Display for CodecOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecOutcome::Decoded { snr_db } => write!(f, "ok {:.0}dB", snr_db),
+            CodecOutcome::Garbled => write!(f, "garbled"),
+            CodecOutcome::Silent => write!(f, "lost"),
+        }
+    }
+}
+
+/// What `ush inspect` saw overall
+#[derive(Default)]
+struct InspectSummary {
+    transmissions: usize,
+    frames: usize,
+    failures: usize,
+}
+
+impl InspectSummary {
+    fn count(&mut self, event: &InspectEvent) {
+        match event {
+            InspectEvent::SignalStart { .. } => self.transmissions += 1,
+            InspectEvent::Frame { .. } => self.frames += 1,
+            InspectEvent::Failure { .. } => self.failures += 1,
+            InspectEvent::Transition { transition, .. } if transition.failure => self.failures += 1,
+            _ => {}
+        }
+    }
+}
+
+/// One timeline line of `ush inspect`, or `None` for a hidden symbol
+fn describe_inspect_event(
+    event: &InspectEvent,
+    sample_rate: u32,
+    show_symbols: bool,
+) -> Option<String> {
+    let sample_offset = event.sample_offset();
+    let at = format!(
+        "{:>10} {:>9.4}s",
+        sample_offset,
+        sample_offset as f64 / sample_rate as f64
+    );
+    let line = match event {
+        InspectEvent::SignalStart { .. } => format!("{}  signal detected", at),
+        InspectEvent::Symbol(symbol) => {
+            if !show_symbols {
+                return None;
+            }
+            format!(
+                "{}    symbol  p0 {:>9.5}  p1 {:>9.5}  → {}  LLR {:+6.1}{}",
+                at,
+                symbol.power_0,
+                symbol.power_1,
+                symbol.bit as u8,
+                symbol.llr,
+                if symbol.is_signal { "" } else { "  (weak)" }
+            )
+        }
+        InspectEvent::Byte(byte) => {
+            let shown = if byte.value.is_ascii_graphic() {
+                format!(" '{}'", byte.value as char)
+            } else {
+                String::new()
+            };
+            format!("{}  byte 0x{:02X}{}", at, byte.value, shown)
+        }
+        InspectEvent::Transition { transition, .. } => format!(
+            "{}  {} {} → {} ({}) at byte {}",
+            at,
+            if transition.failure { "FAIL " } else { "state" },
+            transition.from,
+            transition.to,
+            transition.note,
+            transition.byte_offset
+        ),
+        InspectEvent::Frame { message, .. } => format!(
+            "{}  frame {:?} #{}: {}",
+            at,
+            message.header.message_type,
+            message.header.sequence_number,
+            message
+                .get_text()
+                .unwrap_or_else(|_| format!("{} bytes", message.payload.len()))
+        ),
+        InspectEvent::Failure { reason, .. } => format!("{}  FAIL  {}", at, reason),
+        InspectEvent::SignalEnd { bytes, quality, .. } => format!(
+            "{}  signal lost after {} bytes ({:.1} dB SNR, {:+.0} ppm)",
+            at, bytes, quality.snr_db, quality.frequency_offset_ppm
+        ),
+    };
+    Some(line)
+}
+
+/// Node ids and group of a frame, e.g. ` [node 3 → 5, group 1]`, or empty
+/// for an unaddressed broadcast
+fn describe_route(header: &ush::protocol::MessageHeader) -> String {
+    let mut parts = Vec::new();
+    match (header.source, header.destination) {
+        (Some(source), Some(destination)) if destination != BROADCAST => {
+            parts.push(format!("node {} → {}", source, destination))
+        }
+        (None, Some(destination)) if destination != BROADCAST => {
+            parts.push(format!("to node {}", destination))
+        }
+        (Some(source), _) => parts.push(format!("node {}", source)),
+        (None, _) => {}
+    }
+    if let Some(group) = header.group {
+        parts.push(format!("group {}", group));
+    }
+
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", parts.join(", "))
+    }
+}
+
+/// Show the local identity and manage the trust store
+pub fn run_identity_command(action: &IdentityCommands) -> UshResult<()> {
+    let config_error = || UshError::Config {
+        message: "Cannot locate the config directory (HOME is not set)".to_string(),
+    };
+    let peers_path = known_peers_path().ok_or_else(config_error)?;
+
+    match action {
+        IdentityCommands::Show => {
+            let identity = Identity::load_or_create(&identity_path().ok_or_else(config_error)?)?;
+            println!("{}", to_hex(&identity.public_key()));
+        }
+        IdentityCommands::Trust { name, key } => {
+            let key = from_hex(key).ok_or_else(|| UshError::Config {
+                message: "Public key must be 64 hex digits (see `ush identity show`)".to_string(),
+            })?;
+            let mut trust = TrustStore::load(&peers_path)?;
+            trust.add(name, key)?;
+            trust.save(&peers_path)?;
+            println!("Trusting {} as {}", to_hex(&key), name);
+        }
+        IdentityCommands::Untrust { name } => {
+            let mut trust = TrustStore::load(&peers_path)?;
+            if trust.remove(name) {
+                trust.save(&peers_path)?;
+                println!("No longer trusting {}", name);
+            } else {
+                println!("{} is not a known peer", name);
+            }
+        }
+        IdentityCommands::Peers => {
+            let trust = TrustStore::load(&peers_path)?;
+            let mut empty = true;
+            for (name, key) in trust.peers() {
+                println!("{:<16} {}", name, to_hex(key));
+                empty = false;
+            }
+            if empty {
+                println!("No trusted peers; add one with `ush identity trust <name> <key>`");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Delete the session key stored by `ush pair`
+pub fn forget_pairing() -> UshResult<()> {
+    let Some(path) = session_key_path() else {
+        return Err(UshError::Config {
+            message: "Cannot locate the config directory (HOME is not set)".to_string(),
+        });
+    };
+
+    match std::fs::remove_file(&path) {
+        Ok(()) => println!("Forgot session key at {:?}", path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => println!("Not paired"),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
 pub struct UshApp {
     audio_manager: AudioManager,
     modulator: FskModulator,
@@ -32,6 +394,23 @@ pub struct UshApp {
     _encoder: ProtocolEncoder,
     _decoder: ProtocolDecoder,
     settings: AudioSettings,
+    key: Option<PresharedKey>,
+    identity: Option<Identity>,
+    trust: TrustStore,
+    replay: ReplayPolicy,
+    address: Address,
+    destination: Option<u16>,
+    monitor_all: bool,
+    carrier: tokio::sync::Mutex<CarrierSense>,
+    carrier_sense: bool,
+    /// Audio from a running [`LiveReceiver`], so carrier sense doesn't need
+    /// to open the microphone a second time
+    channel_tap: Mutex<Option<InputRing>>,
+    /// Silences that receiver's decoder while we play, so it doesn't decode
+    /// our own transmission
+    decoder_mute: Mutex<Option<Arc<AtomicBool>>>,
+    fec: Option<ConvolutionalCode>,
+    mp3_bitrate: u32,
 }
 
 impl UshApp {
@@ -51,7 +430,10 @@ impl UshApp {
         };
 
         let audio_manager = AudioManager::with_config(audio_config)?;
-        let modulator = FskModulator::new(modulation_config.clone());
+        let modulator = FskModulator::new(modulation_config.clone())
+            .with_amplitude(settings.tx_level.amplitude())
+            .with_pre_emphasis(settings.pre_emphasis_db);
+        let carrier = CarrierSense::new(&modulation_config, CarrierSenseConfig::default());
         let demodulator = FskDemodulator::new(modulation_config);
         let encoder = ProtocolEncoder::new();
         let decoder = ProtocolDecoder::new();
@@ -63,34 +445,201 @@ impl UshApp {
             _encoder: encoder,
             _decoder: decoder,
             settings,
+            key: None,
+            identity: None,
+            trust: TrustStore::new(),
+            replay: ReplayPolicy::default(),
+            address: Address::default(),
+            destination: None,
+            monitor_all: false,
+            carrier: tokio::sync::Mutex::new(carrier),
+            carrier_sense: true,
+            channel_tap: Mutex::new(None),
+            decoder_mute: Mutex::new(None),
+            fec: None,
+            mp3_bitrate: DEFAULT_MP3_BITRATE,
         })
     }
 
+    /// Encrypt everything sent, and require it on everything received
+    pub fn with_key(mut self, key: Option<PresharedKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sign everything sent with this identity
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Peers whose signed messages are shown as verified
+    pub fn with_trust_store(mut self, trust: TrustStore) -> Self {
+        self.trust = trust;
+        self
+    }
+
+    /// How receivers treat replayed and stale frames
+    pub fn with_replay_policy(mut self, replay: ReplayPolicy) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    /// This node's id and group
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Send to one node instead of broadcasting
+    pub fn with_destination(mut self, destination: Option<u16>) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    /// Show frames addressed to other nodes and groups too
+    pub fn with_monitor_all(mut self, monitor_all: bool) -> Self {
+        self.monitor_all = monitor_all;
+        self
+    }
+
+    /// Listen before each transmission and back off while the channel is busy
+    pub fn with_carrier_sense(mut self, enabled: bool) -> Self {
+        self.carrier_sense = enabled;
+        self
+    }
+
+    /// Encode frames with this convolutional code, and expect it on receive
+    pub fn with_fec(mut self, fec: Option<ConvolutionalCode>) -> Self {
+        self.fec = fec;
+        self
+    }
+
+    /// Bitrate in kbps for audio saved as MP3
+    pub fn with_mp3_bitrate(mut self, bitrate: u32) -> Self {
+        self.mp3_bitrate = bitrate;
+        self
+    }
+
+    /// Turn frame bytes into audio, through the FEC encoder if one is set
+    fn modulate(&self, frame_data: &[u8]) -> Vec<f32> {
+        match &self.fec {
+            Some(code) => self.modulator.encode_bits(&code.encode_bytes(frame_data)),
+            None => self.modulator.encode_bytes(frame_data),
+        }
+    }
+
+    fn protocol_encoder(&self) -> ProtocolEncoder {
+        let mut encoder = ProtocolEncoder::new().with_address(self.address);
+        if let Some(destination) = self.destination {
+            encoder = encoder.with_destination(destination);
+        }
+        let encoder = match &self.identity {
+            Some(identity) => encoder.with_identity(identity.clone()),
+            None => encoder,
+        };
+        match &self.key {
+            Some(key) => encoder.with_key(key.clone()),
+            None => encoder,
+        }
+    }
+
+    fn link_monitor(&self) -> LinkQualityMonitor {
+        LinkQualityMonitor::new(
+            self.settings.sample_rate,
+            self.settings.freq_0,
+            self.settings.freq_1,
+        )
+    }
+
+    fn message_receiver(&self, threshold: f32) -> MessageReceiver {
+        let receiver = MessageReceiver::new(self.demodulator.config().clone(), threshold)
+            .with_trust_store(self.trust.clone())
+            .with_replay_policy(self.replay);
+        let receiver = if self.monitor_all {
+            receiver.monitor_all()
+        } else {
+            receiver.with_address(self.address)
+        };
+        let receiver = match &self.fec {
+            Some(code) => receiver.with_fec(code.clone()),
+            None => receiver,
+        };
+        match &self.key {
+            Some(key) => receiver.with_key(key.clone()),
+            None => receiver,
+        }
+    }
+
     pub async fn send_message(
         &self,
         message: &str,
         repeat: Option<u32>,
-        save_wav: Option<&Path>,
+        save_audio: Option<&Path>,
         from_wav: Option<&Path>,
     ) -> UshResult<()> {
         info!("Sending message: \"{}\"", message);
 
         let samples = if let Some(wav_path) = from_wav {
-            info!("Loading audio from WAV file: {:?}", wav_path);
-            self.load_wav_file(wav_path)?
+            info!("Loading audio from file: {:?}", wav_path);
+            self.load_audio_file(wav_path)?
         } else {
-            let mut encoder = ProtocolEncoder::new();
+            let mut encoder = self.protocol_encoder();
             let frame_data = encoder.encode_text(message)?;
-            let samples = self.modulator.encode_bytes(&frame_data);
+            let samples = self.modulate(&frame_data);
 
-            if let Some(wav_path) = save_wav {
-                self.save_wav_file(&samples, wav_path)?;
-                info!("Saved encoded audio to: {:?}", wav_path);
+            if let Some(path) = save_audio {
+                self.save_audio_file(&samples, path)?;
+                info!("Saved encoded audio to: {:?}", path);
             }
 
             samples
         };
 
+        self.transmit(&samples, repeat, from_wav.is_none()).await
+    }
+
+    /// Send everything read from stdin as a sequence of binary frames
+    pub async fn send_stdin(
+        &self,
+        repeat: Option<u32>,
+        save_audio: Option<&Path>,
+    ) -> UshResult<()> {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data)?;
+
+        if data.is_empty() {
+            warn!("Nothing to send: stdin was empty");
+            return Ok(());
+        }
+
+        info!(
+            "Sending {} bytes from stdin in {} frame(s)",
+            data.len(),
+            data.len().div_ceil(DATA_CHUNK_SIZE)
+        );
+
+        let mut encoder = self.protocol_encoder();
+        let frame_data = encoder.encode_data(&data)?;
+        let samples = self.modulate(&frame_data);
+
+        if let Some(path) = save_audio {
+            self.save_audio_file(&samples, path)?;
+            info!("Saved encoded audio to: {:?}", path);
+        }
+
+        self.transmit(&samples, repeat, true).await
+    }
+
+    /// Play encoded samples, repeated with a short silence in between. The
+    /// adaptive level only scales `modulated` samples; a loaded recording
+    /// plays as it is.
+    async fn transmit(
+        &self,
+        samples: &[f32],
+        repeat: Option<u32>,
+        modulated: bool,
+    ) -> UshResult<()> {
         let repeat_count = repeat.unwrap_or(1);
         let mut full_samples = Vec::new();
 
@@ -102,7 +651,7 @@ impl UshApp {
                     (self.settings.sample_rate as f32 * silence_duration) as usize;
                 full_samples.extend(vec![0.0; silence_samples]);
             }
-            full_samples.extend(&samples);
+            full_samples.extend(samples);
         }
 
         info!(
@@ -112,47 +661,142 @@ impl UshApp {
             repeat_count
         );
 
-        self.play_samples(&full_samples).await
+        let gain = self.listen_before_transmit().await?;
+        if modulated && gain < 1.0 {
+            full_samples.iter_mut().for_each(|s| *s *= gain);
+        }
+
+        let muted = self.decoder_mute.lock().unwrap().clone();
+        let set_muted = |value| {
+            if let Some(muted) = &muted {
+                muted.store(value, Ordering::Relaxed);
+            }
+        };
+        set_muted(true);
+        let played = self.play_samples(&full_samples).await;
+        set_muted(false);
+        played
     }
 
-    pub async fn listen_for_messages(
+    /// Listen through the chat receiver if one is running and the
+    /// microphone otherwise: wait until nobody else is transmitting if carrier
+    /// sense is on, and measure the room for an adaptive level. Returns the
+    /// gain to apply to modulated samples.
+    async fn listen_before_transmit(&self) -> UshResult<f32> {
+        let adaptive = match self.settings.tx_level {
+            TxLevel::Adaptive(adaptive) => Some(adaptive),
+            TxLevel::Fixed(_) => None,
+        };
+        if !self.carrier_sense && adaptive.is_none() {
+            return Ok(1.0);
+        }
+
+        let mut carrier = self.carrier.lock().await;
+        let tap = self.channel_tap.lock().unwrap().take();
+
+        let (result, tap) = match tap {
+            Some(mut tap) => {
+                let result = self.listen(&mut carrier, || drain_ring(&mut tap)).await;
+                (result, Some(tap))
+            }
+            None => {
+                let capacity = self.settings.sample_rate as usize;
+                match self.audio_manager.create_input_ring(capacity) {
+                    Ok((input_stream, mut ring)) => {
+                        input_stream.play()?;
+                        let result = self.listen(&mut carrier, || drain_ring(&mut ring)).await;
+                        (result, None)
+                    }
+                    Err(e) => {
+                        warn!("Microphone unavailable, transmitting blind: {}", e);
+                        return Ok(1.0);
+                    }
+                }
+            }
+        };
+
+        *self.channel_tap.lock().unwrap() = tap;
+        let heard = result?;
+
+        let Some(adaptive) = adaptive else {
+            return Ok(1.0);
+        };
+        match adaptive.estimate(&heard, self.demodulator.config()) {
+            Some(estimate) => {
+                info!(
+                    "Transmit level {:.3} ({:.1} dBFS) over {:.1} dBFS of noise at the tones",
+                    estimate.level,
+                    estimate.level_db(),
+                    estimate.noise_db()
+                );
+                Ok(estimate.level / adaptive.max_level)
+            }
+            None => {
+                warn!(
+                    "Heard {} samples, too few to measure the room; sending at {:.3}",
+                    heard.len(),
+                    adaptive.max_level
+                );
+                Ok(1.0)
+            }
+        }
+    }
+
+    /// Wait for a clear channel if carrier sense is on, or just listen for a
+    /// moment, and return everything heard in the last listen window
+    async fn listen(
         &self,
-        timeout_secs: Option<u32>,
-        save_wav: Option<&Path>,
-        from_wav: Option<&Path>,
-        filter: bool,
-        threshold: f32,
-        debug: bool,
-        debug_output: Option<&Path>,
-    ) -> UshResult<()> {
-        if let Some(wav_path) = from_wav {
-            info!("Processing audio from WAV file: {:?}", wav_path);
-            let samples = self.load_wav_file(wav_path)?;
+        carrier: &mut CarrierSense,
+        mut capture: impl FnMut() -> Vec<f32>,
+    ) -> UshResult<Vec<f32>> {
+        if self.carrier_sense {
+            carrier.wait_for_idle(capture).await
+        } else {
+            capture();
+            sleep(LISTEN_WINDOW).await;
+            Ok(capture())
+        }
+    }
+
+    async fn carrier_stats(&self) -> CarrierStats {
+        self.carrier.lock().await.stats()
+    }
+
+    pub async fn listen_for_messages(&self, options: ListenOptions<'_>) -> UshResult<()> {
+        if let Some(path) = options.from_file {
+            info!("Processing audio from file: {:?}", path);
+            let samples = self.load_audio_file(path)?;
 
             // If debug mode is enabled, analyze the WAV file
-            if debug {
-                self.run_debug_analysis(&samples, debug_output).await?;
+            if options.debug {
+                self.run_debug_analysis(&samples, options.debug_output)
+                    .await?;
             }
 
             return self
-                .process_received_samples(&samples, filter, threshold)
+                .process_received_samples(
+                    &samples,
+                    options.filter,
+                    options.threshold,
+                    options.format,
+                )
                 .await;
         }
 
-        info!("Listening for messages (threshold: {:.2})...", threshold);
-        if debug {
+        info!(
+            "Listening for messages (threshold: {:.2})...",
+            options.threshold
+        );
+        if options.debug {
             info!("Debug mode enabled - will capture and analyze all audio");
         }
-        if let Some(timeout) = timeout_secs {
+        if let Some(timeout) = options.timeout_secs {
             info!("Timeout set to {} seconds", timeout);
         }
 
-        let recorded_samples = Arc::new(Mutex::new(Vec::<f32>::new()));
-        let samples_clone = recorded_samples.clone();
-
         // Create debug buffer if debug mode is enabled
-        let debug_buffer = if debug {
-            let max_duration = timeout_secs.unwrap_or(60) as f32 + 10.0; // Add buffer
+        let debug_buffer = if options.debug {
+            let max_duration = options.timeout_secs.unwrap_or(60) as f32 + 10.0; // Add buffer
             Some(DebugAudioBuffer::new(
                 max_duration,
                 self.settings.sample_rate,
@@ -161,115 +805,447 @@ impl UshApp {
             None
         };
 
-        let debug_buffer_clone = debug_buffer.as_ref().map(|db| db.get_buffer_clone());
+        let mut live = self.start_live_receiver(
+            options.filter,
+            options.threshold,
+            options.save_wav.is_some(),
+            debug_buffer,
+        )?;
 
-        let (_tx, _rx) = mpsc::unbounded_channel::<()>();
+        let start_time = Instant::now();
+        let mut last_link_report = Instant::now();
 
-        let input_stream = self.audio_manager.create_input_stream(move |data| {
-            let mut samples = samples_clone.lock().unwrap();
-            samples.extend_from_slice(data);
+        loop {
+            if last_link_report.elapsed() >= LINK_REPORT_INTERVAL {
+                info!("Link: {}", live.link_quality());
+                last_link_report = Instant::now();
+            }
 
-            // Add to debug buffer if debug mode is enabled
-            if let Some(ref debug_buf) = debug_buffer_clone {
-                if let Ok(mut debug_samples) = debug_buf.try_lock() {
-                    debug_samples.extend(data.iter());
+            // Check timeout
+            if let Some(timeout) = options.timeout_secs
+                && start_time.elapsed().as_secs() > timeout as u64
+            {
+                info!("Listen timeout reached");
+                break;
+            }
 
-                    // Limit debug buffer size
-                    let max_samples = 44100 * 120; // 2 minutes max
-                    while debug_samples.len() > max_samples {
-                        debug_samples.pop_front();
-                    }
-                }
+            // Handle everything the decode task produced since the last pass
+            for event in live.drain_events() {
+                self.handle_receiver_event(event, options.format).await?;
             }
 
-            // Process in chunks to avoid memory issues
-            if samples.len() > 44100 * 10 {
-                // 10 seconds of audio
-                samples.drain(..44100 * 5); // Keep last 5 seconds
+            // Check for Ctrl+C
+            if event::poll(Duration::from_millis(50)).unwrap_or(false)
+                && let Ok(Event::Key(key_event)) = event::read()
+                && key_event.kind == KeyEventKind::Press
+                && key_event.code == KeyCode::Char('c')
+                && key_event.modifiers.contains(event::KeyModifiers::CONTROL)
+            {
+                info!("Interrupted by user");
+                break;
             }
-        })?;
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let link = live.link.clone();
+        let (output, events) = live.finish()?;
+        for event in events {
+            self.handle_receiver_event(event, options.format).await?;
+        }
+        self.log_link_quality(&link.lock().unwrap().quality());
+
+        // Run debug analysis if enabled
+        if let Some(debug_buf) = output.debug_buffer {
+            let all_samples = debug_buf.get_all_samples();
+            if !all_samples.is_empty() {
+                info!("Running debug analysis on {} samples", all_samples.len());
+                self.run_debug_analysis(&all_samples, options.debug_output)
+                    .await?;
+            } else {
+                warn!("No audio data captured for debug analysis");
+            }
+        }
+
+        if let (Some(wav_path), Some(recording)) = (options.save_wav, output.recording)
+            && !recording.is_empty()
+        {
+            self.save_audio_file(&recording, wav_path)?;
+            info!("Saved recorded audio to: {:?}", wav_path);
+        }
+
+        Ok(())
+    }
+
+    /// Open the microphone and start decoding everything it captures
+    fn start_live_receiver(
+        &self,
+        filter: bool,
+        threshold: f32,
+        record: bool,
+        debug_buffer: Option<DebugAudioBuffer>,
+    ) -> UshResult<LiveReceiver> {
+        let ring_capacity = self.settings.sample_rate as usize * INPUT_RING_SECONDS;
+        let (input_stream, ring) = self.audio_manager.create_input_ring(ring_capacity)?;
+
+        let (event_tx, events) = mpsc::unbounded_channel();
+        let (tap_writer, tap) = input_ring(self.settings.sample_rate as usize);
+        let stop = Arc::new(AtomicBool::new(false));
+        let muted = Arc::new(AtomicBool::new(false));
+        let link = Arc::new(Mutex::new(self.link_monitor()));
+        let task = self.spawn_decode_task(
+            ring,
+            tap_writer,
+            event_tx,
+            stop.clone(),
+            muted.clone(),
+            link.clone(),
+            filter,
+            threshold,
+            record,
+            debug_buffer,
+        )?;
 
         input_stream.play()?;
 
-        let start_time = Instant::now();
-        let _decoder = ProtocolDecoder::new();
-        let mut last_process_time = Instant::now();
+        Ok(LiveReceiver {
+            input_stream,
+            tap: Some(tap),
+            events,
+            stop,
+            muted,
+            link,
+            task,
+        })
+    }
 
-        loop {
-            // Check timeout
-            if let Some(timeout) = timeout_secs {
-                if start_time.elapsed().as_secs() > timeout as u64 {
-                    info!("Listen timeout reached");
-                    break;
+    /// Start the dedicated thread that drains the input ring and demodulates continuously
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_decode_task(
+        &self,
+        mut ring: InputRing,
+        mut tap: InputRingWriter,
+        event_tx: mpsc::UnboundedSender<ReceiverEvent>,
+        stop: Arc<AtomicBool>,
+        muted: Arc<AtomicBool>,
+        link: Arc<Mutex<LinkQualityMonitor>>,
+        filter: bool,
+        threshold: f32,
+        record: bool,
+        debug_buffer: Option<DebugAudioBuffer>,
+    ) -> UshResult<thread::JoinHandle<DecodeTaskOutput>> {
+        let mut receiver = self.message_receiver(threshold);
+        let mut filter = filter.then(|| {
+            info!("Applying bandpass filter");
+            BandpassFilter::new(
+                self.settings.freq_0 - 1000.0,
+                self.settings.freq_1 + 1000.0,
+                self.settings.sample_rate,
+            )
+        });
+        let mut recording = record.then(Vec::new);
+
+        let handle = thread::Builder::new()
+            .name("ush-decode".to_string())
+            .spawn(move || {
+                let mut chunk = Vec::with_capacity(ring.capacity());
+                let mut last_stats = ring.stats();
+                let mut last_stats_log = Instant::now();
+
+                loop {
+                    let stopping = stop.load(Ordering::Relaxed);
+
+                    chunk.clear();
+                    if ring.pop_into(&mut chunk) > 0 {
+                        // Keep the sample clock running but decode nothing while muted
+                        tap_and_mute(&mut chunk, &mut tap, muted.load(Ordering::Relaxed));
+                        link.lock().unwrap().push_samples(&chunk);
+                        if let Some(filter) = filter.as_mut() {
+                            filter.process(&mut chunk);
+                        }
+                        if let Some(recording) = recording.as_mut() {
+                            recording.extend_from_slice(&chunk);
+                        }
+                        if let Some(debug_buffer) = debug_buffer.as_ref() {
+                            debug_buffer.add_samples(&chunk);
+                        }
+
+                        for event in receiver.push_samples(&chunk) {
+                            link.lock().unwrap().observe(&event);
+                            let _ = event_tx.send(event);
+                        }
+                    } else if stopping {
+                        break;
+                    } else {
+                        thread::sleep(Duration::from_millis(5));
+                    }
+
+                    if last_stats_log.elapsed() >= Duration::from_secs(1) {
+                        let stats = ring.stats();
+                        debug!(
+                            "Input ring: {}/{} samples buffered, {} overruns ({} samples dropped, {} new)",
+                            ring.len(),
+                            ring.capacity(),
+                            stats.overruns,
+                            stats.samples_dropped,
+                            stats.samples_dropped - last_stats.samples_dropped
+                        );
+                        last_stats = stats;
+                        last_stats_log = Instant::now();
+                    }
+                }
+
+                for event in receiver.flush() {
+                    link.lock().unwrap().observe(&event);
+                    let _ = event_tx.send(event);
+                }
+
+                let stats = ring.stats();
+                debug!(
+                    "Decode task finished: {} samples received, {} overruns ({} samples dropped)",
+                    stats.samples_written, stats.overruns, stats.samples_dropped
+                );
+                log_receiver_summary(&receiver, false);
+
+                DecodeTaskOutput {
+                    recording,
+                    debug_buffer,
+                }
+            })?;
+
+        Ok(handle)
+    }
+
+    async fn handle_receiver_event(
+        &self,
+        event: ReceiverEvent,
+        format: OutputFormat,
+    ) -> UshResult<()> {
+        match format {
+            OutputFormat::Json => {
+                println!("{}", EventRecord::from_event(&event).to_json_line()?);
+                return Ok(());
+            }
+            OutputFormat::Raw => {
+                if let ReceiverEvent::Message { message, .. } = &event {
+                    match message.header.message_type {
+                        MessageType::Text | MessageType::File => {
+                            let mut stdout = io::stdout().lock();
+                            stdout.write_all(&message.payload)?;
+                            stdout.flush()?;
+                        }
+                        _ => debug!(
+                            "Not writing {:?} frame (sequence {}) to stdout",
+                            message.header.message_type, message.header.sequence_number
+                        ),
+                    }
+                    return Ok(());
                 }
+                // Everything else is only logged, keeping stdout to payload bytes
             }
+            OutputFormat::Text => {}
+        }
 
-            // Process audio data periodically
-            if last_process_time.elapsed() > Duration::from_millis(100) {
-                let samples = {
-                    let guard = recorded_samples.lock().unwrap();
-                    guard.clone()
-                };
+        match event {
+            ReceiverEvent::SignalDetected { sample_offset } => {
+                info!("Signal detected at sample {}", sample_offset);
+            }
+            ReceiverEvent::Message {
+                message,
+                sender,
+                quality,
+            } => {
+                debug!(
+                    "Link: SNR {:.1} dB, carrier offset {:+.1} Hz ({:+.0} ppm)",
+                    quality.snr_db, quality.frequency_offset_hz, quality.frequency_offset_ppm
+                );
+                self.handle_received_message(&message, &sender).await?
+            }
+            ReceiverEvent::Rejected { reason, .. } => {
+                warn!("Rejected frame: {}", reason);
+            }
+            ReceiverEvent::DecodeFailed {
+                sample_offset,
+                bytes,
+                ..
+            } => {
+                warn!(
+                    "Failed to decode transmission of {} bytes ending at sample {}",
+                    bytes, sample_offset
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the debug analysis on each recording, optionally comparing them
+    pub async fn analyze_recordings(
+        &self,
+        files: &[PathBuf],
+        output: Option<&Path>,
+        window_size: Option<usize>,
+        hop_size: Option<usize>,
+        fft_size: Option<usize>,
+        compare: bool,
+    ) -> UshResult<()> {
+        let defaults = DebugConfig::default();
+        let window_size = window_size.unwrap_or(defaults.window_size);
+        let output_dir = output.map(Path::to_path_buf).unwrap_or(defaults.output_dir);
 
-                if !samples.is_empty() {
-                    if let Some(start_idx) = detect_signal_start(&samples, threshold) {
-                        info!("Signal detected at sample {}", start_idx);
+        let mut captures = Vec::new();
+        for file in files {
+            let DecodedAudio {
+                samples,
+                sample_rate,
+                ..
+            } = read_audio_file(file)?;
+            let config = DebugConfig {
+                sample_rate,
+                freq_0: self.settings.freq_0,
+                freq_1: self.settings.freq_1,
+                output_dir: output_dir.clone(),
+                window_size,
+                hop_size: hop_size.unwrap_or(defaults.hop_size),
+                fft_size: fft_size.unwrap_or(window_size),
+            };
+            let mut analyzer = DebugAnalyzer::new(config)?
+                .with_fec(self.fec.clone())
+                .with_key(self.key.clone());
+            let analysis = analyzer.analyze_audio(&samples)?;
+
+            let passed = analysis.frames.iter().filter(|frame| frame.passed).count();
+            println!(
+                "{}: {:.2}s, {:.1} dB SNR, {} of {} frames passed -> {}",
+                file.display(),
+                analysis.signal_metrics.duration_seconds,
+                analysis.signal_metrics.estimated_snr,
+                passed,
+                analysis.frames.len(),
+                output_dir
+                    .join(&analysis.session_id)
+                    .join("debug_report.html")
+                    .display()
+            );
 
-                        let signal_samples = &samples[start_idx..];
-                        if let Err(e) = self
-                            .process_received_samples(signal_samples, filter, threshold)
-                            .await
-                        {
-                            warn!("Failed to process signal: {}", e);
-                        }
+            let label = file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| file.display().to_string());
+            captures.push((label, analysis));
+        }
 
-                        // Clear processed samples
-                        recorded_samples.lock().unwrap().clear();
-                    }
-                }
+        if compare {
+            let path = write_comparison_report(&output_dir, &captures)?;
+            println!("Comparison: {}", path.display());
+        }
+        Ok(())
+    }
 
-                last_process_time = Instant::now();
-            }
+    /// Hide a text frame in `carrier` under its masking threshold and save the result
+    pub fn embed_message(
+        &self,
+        carrier: &Path,
+        message: &str,
+        out: &Path,
+        secret: Option<&str>,
+        at: Option<f32>,
+        margin_db: Option<f32>,
+    ) -> UshResult<()> {
+        let audio = read_audio_file(carrier)?;
+        if audio.channels > 1 || audio.sample_rate != self.settings.sample_rate {
+            warn!(
+                "{:?} is {} Hz with {} channel(s); the output is mono at {} Hz",
+                carrier, audio.sample_rate, audio.channels, self.settings.sample_rate
+            );
+        }
+        let host = resample(&audio.samples, audio.sample_rate, self.settings.sample_rate);
+        let frame_data = self.protocol_encoder().encode_text(message)?;
+
+        let defaults = StegoConfig::default();
+        let config = StegoConfig {
+            modulation: self.demodulator.config().clone(),
+            margin_db: margin_db.unwrap_or(defaults.margin_db),
+            ..defaults
+        };
+        let mut embedder = Embedder::new(config);
+        if let Some(secret) = secret {
+            embedder = embedder.with_secret(secret);
+        }
+        if let Some(code) = &self.fec {
+            embedder = embedder.with_fec(code.clone());
+        }
 
-            // Check for Ctrl+C
-            if event::poll(Duration::from_millis(50)).unwrap_or(false) {
-                if let Ok(Event::Key(key_event)) = event::read() {
-                    if key_event.kind == KeyEventKind::Press
-                        && key_event.code == KeyCode::Char('c')
-                        && key_event.modifiers.contains(event::KeyModifiers::CONTROL)
-                    {
-                        info!("Interrupted by user");
-                        break;
-                    }
-                }
-            }
+        let sample_rate = self.settings.sample_rate as f32;
+        let at = at.map(|seconds| (seconds.max(0.0) * sample_rate) as usize);
+        let embedding = embedder.embed(&host, &frame_data, at)?;
+        self.save_audio_file(&embedding.samples, out)?;
 
-            sleep(Duration::from_millis(10)).await;
+        println!(
+            "Embedded \"{}\" at {:.2}s for {:.2}s, mean level {:.1} dBFS",
+            message,
+            embedding.start as f32 / sample_rate,
+            embedding.levels.len() as f32 * self.demodulator.config().symbol_duration,
+            embedding.mean_level_db()
+        );
+        if embedding.unmasked > 0 {
+            warn!(
+                "{} of {} symbols are louder than the host masks; they may be audible",
+                embedding.unmasked,
+                embedding.levels.len()
+            );
+        }
+        if embedding.gain < 1.0 {
+            warn!(
+                "Scaled the output by {:.2} to avoid clipping",
+                embedding.gain
+            );
         }
+        println!("Saved to {:?}", out);
+        Ok(())
+    }
 
-        drop(input_stream);
+    /// Find and decode frames hidden in a recording by `embed_message`
+    pub fn extract_messages(&self, path: &Path, secret: Option<&str>) -> UshResult<()> {
+        let samples = self.load_audio_file(path)?;
+        let mut extractor = Extractor::new(self.demodulator.config().clone());
+        if let Some(secret) = secret {
+            extractor = extractor.with_secret(secret);
+        }
+        if let Some(code) = &self.fec {
+            extractor = extractor.with_fec(code.clone());
+        }
+        if let Some(key) = &self.key {
+            extractor = extractor.with_key(key.clone());
+        }
 
-        // Run debug analysis if enabled
-        if debug {
-            if let Some(debug_buf) = debug_buffer {
-                let all_samples = debug_buf.get_all_samples();
-                if !all_samples.is_empty() {
-                    info!("Running debug analysis on {} samples", all_samples.len());
-                    self.run_debug_analysis(&all_samples, debug_output).await?;
-                } else {
-                    warn!("No audio data captured for debug analysis");
+        let sample_rate = self.settings.sample_rate as f32;
+        let mut found = 0;
+        for frame in extractor.extract(&samples)? {
+            let seconds = frame.sample_offset as f32 / sample_rate;
+            match frame.message {
+                Ok(message) => {
+                    found += 1;
+                    match message.header.message_type {
+                        MessageType::Text => println!("{:.2}s: {}", seconds, message.get_text()?),
+                        _ => println!(
+                            "{:.2}s: {:?} {}",
+                            seconds,
+                            message.header.message_type,
+                            base64::encode(&message.payload)
+                        ),
+                    }
                 }
+                Err(e) => warn!(
+                    "Found a frame at {:.2}s (sync {:.2}) but could not decode it: {}",
+                    seconds, frame.sync_score, e
+                ),
             }
         }
 
-        if let Some(wav_path) = save_wav {
-            let samples = recorded_samples.lock().unwrap();
-            if !samples.is_empty() {
-                self.save_wav_file(&samples, wav_path)?;
-                info!("Saved recorded audio to: {:?}", wav_path);
-            }
+        if found == 0 {
+            println!("No hidden messages found");
         }
-
         Ok(())
     }
 
@@ -288,12 +1264,12 @@ impl UshApp {
             freq_0: self.settings.freq_0,
             freq_1: self.settings.freq_1,
             output_dir,
-            window_size: 1024,
-            hop_size: 256,
-            fft_size: 512,
+            ..DebugConfig::default()
         };
 
-        let mut analyzer = DebugAnalyzer::new(debug_config)?;
+        let mut analyzer = DebugAnalyzer::new(debug_config)?
+            .with_fec(self.fec.clone())
+            .with_key(self.key.clone());
         let analysis_result = analyzer.analyze_audio(samples)?;
 
         info!("Debug analysis complete!");
@@ -322,7 +1298,8 @@ impl UshApp {
         &self,
         samples: &[f32],
         filter: bool,
-        _threshold: f32,
+        threshold: f32,
+        format: OutputFormat,
     ) -> UshResult<()> {
         let processed_samples = if filter {
             info!("Applying bandpass filter");
@@ -336,27 +1313,58 @@ impl UshApp {
             samples.to_vec()
         };
 
-        match self.demodulator.decode_bytes(&processed_samples) {
-            Ok(frame_data) => {
-                let mut decoder = ProtocolDecoder::new();
-                let messages = decoder.feed_data(&frame_data);
-                for message in messages {
-                    self.handle_received_message(&message).await?;
-                }
-            }
-            Err(e) => {
-                warn!("Failed to decode audio: {}", e);
-            }
+        let mut receiver = self.message_receiver(threshold);
+        let mut link = self.link_monitor();
+        link.push_samples(samples);
+        let mut events = receiver.push_samples(&processed_samples);
+        events.extend(receiver.flush());
+
+        for event in events {
+            link.observe(&event);
+            self.handle_receiver_event(event, format).await?;
         }
+        self.log_link_quality(&link.quality());
+
+        log_receiver_summary(&receiver, true);
 
         Ok(())
     }
 
-    async fn handle_received_message(&self, message: &Message) -> UshResult<()> {
+    /// Log link quality, and what the sender could do about a weak tone
+    fn log_link_quality(&self, link: &LinkQuality) {
+        info!("Link: {}", link);
+        if let Some(balance) = link.tone_balance_db
+            && balance.abs() >= TONE_BALANCE_HINT_DB
+        {
+            info!(
+                "{} Hz arrived {:.1} dB {} than {} Hz; the sender can even them out with --pre-emphasis {:.1}",
+                self.settings.freq_1,
+                balance.abs(),
+                if balance > 0.0 { "weaker" } else { "stronger" },
+                self.settings.freq_0,
+                balance
+            );
+        }
+    }
+
+    async fn handle_received_message(&self, message: &Message, sender: &Sender) -> UshResult<()> {
+        if *sender == Sender::Invalid {
+            warn!(
+                "Message {} has an invalid signature; its sender may be forged",
+                message.header.sequence_number
+            );
+        }
+
         match &message.header.message_type {
             MessageType::Text => {
                 let text = message.get_text()?;
-                println!("Received: {}", text);
+                let route = describe_route(&message.header);
+                match sender {
+                    Sender::Verified(_) | Sender::Untrusted(_) => {
+                        println!("Received from {}{}: {}", sender, route, text)
+                    }
+                    _ => println!("Received ({}){}: {}", sender, route, text),
+                }
             }
             MessageType::Ping => {
                 println!(
@@ -376,6 +1384,12 @@ impl UshApp {
                     message.header.sequence_number
                 );
             }
+            MessageType::PairRequest | MessageType::PairResponse => {
+                println!(
+                    "Received pairing message (sequence {}); run `ush pair` to pair",
+                    message.header.sequence_number
+                );
+            }
         }
 
         Ok(())
@@ -386,17 +1400,27 @@ impl UshApp {
         username: Option<&str>,
         enable_ack: bool,
         timeout_mins: Option<u32>,
+        format: OutputFormat,
     ) -> UshResult<()> {
         let username = username.unwrap_or("user");
         info!("Starting chat mode as '{}' (ACK: {})", username, enable_ack);
 
+        // JSON output is meant for pipes, so keep stdout free of screen control
+        let alternate_screen = format == OutputFormat::Text;
+
         enable_raw_mode()?;
-        execute!(io::stdout(), EnterAlternateScreen)?;
+        if alternate_screen {
+            execute!(io::stdout(), EnterAlternateScreen)?;
+        }
 
-        let result = self.run_chat_loop(username, enable_ack, timeout_mins).await;
+        let result = self
+            .run_chat_loop(username, enable_ack, timeout_mins, format)
+            .await;
 
         disable_raw_mode()?;
-        execute!(io::stdout(), LeaveAlternateScreen)?;
+        if alternate_screen {
+            execute!(io::stdout(), LeaveAlternateScreen)?;
+        }
 
         result
     }
@@ -406,21 +1430,67 @@ impl UshApp {
         username: &str,
         _enable_ack: bool,
         timeout_mins: Option<u32>,
+        format: OutputFormat,
     ) -> UshResult<()> {
-        println!("Chat Mode - Press Ctrl+C to exit");
-        println!("Type your message and press Enter to send\n");
+        // In JSON mode stdout carries only events, so status text goes to stderr
+        let status = |text: &str| match format {
+            OutputFormat::Text => println!("{}", text),
+            OutputFormat::Json | OutputFormat::Raw => eprintln!("{}", text),
+        };
+
+        status("Chat Mode - Press Ctrl+C to exit, type /link for link quality");
+        status("Type your message and press Enter to send\n");
+
+        let mut live = self.start_live_receiver(false, DEFAULT_THRESHOLD, false, None)?;
+        *self.channel_tap.lock().unwrap() = live.tap.take();
+        *self.decoder_mute.lock().unwrap() = Some(live.muted.clone());
+        let result = self
+            .chat_session(&mut live, username, timeout_mins, format, &status)
+            .await;
+
+        let (_, events) = live.finish()?;
+        self.channel_tap.lock().unwrap().take();
+        self.decoder_mute.lock().unwrap().take();
+        for event in events {
+            self.handle_receiver_event(event, format).await?;
+        }
+
+        let stats = self.carrier_stats().await;
+        if stats.busy > 0 {
+            status(&format!(
+                "Channel was busy {} time(s): waited {:.1}s in backoff, dropped {} message(s)",
+                stats.busy,
+                stats.backoff_time.as_secs_f32(),
+                stats.abandoned
+            ));
+        }
+
+        result
+    }
 
+    async fn chat_session(
+        &self,
+        live: &mut LiveReceiver,
+        username: &str,
+        timeout_mins: Option<u32>,
+        format: OutputFormat,
+        status: &dyn Fn(&str),
+    ) -> UshResult<()> {
         let mut input_buffer = String::new();
         let mut message_history = VecDeque::new();
         let start_time = Instant::now();
 
         loop {
             // Check timeout
-            if let Some(timeout) = timeout_mins {
-                if start_time.elapsed().as_secs() > (timeout as u64 * 60) {
-                    println!("\nChat timeout reached");
-                    break;
-                }
+            if let Some(timeout) = timeout_mins
+                && start_time.elapsed().as_secs() > (timeout as u64 * 60)
+            {
+                status("\nChat timeout reached");
+                break;
+            }
+
+            for event in live.drain_events() {
+                self.handle_receiver_event(event, format).await?;
             }
 
             // Handle keyboard input
@@ -431,27 +1501,30 @@ impl UshApp {
                             KeyCode::Char('c')
                                 if key_event.modifiers.contains(event::KeyModifiers::CONTROL) =>
                             {
-                                println!("\nExiting chat mode...");
+                                status("\nExiting chat mode...");
                                 break;
                             }
-                            KeyCode::Enter => {
-                                if !input_buffer.trim().is_empty() {
-                                    let message = format!("{}: {}", username, input_buffer.trim());
-                                    println!("Sending: {}", message);
-
-                                    if let Err(e) =
-                                        self.send_message(&message, None, None, None).await
-                                    {
-                                        println!("Failed to send message: {}", e);
-                                    } else {
-                                        message_history.push_back(message);
-                                        if message_history.len() > 50 {
-                                            message_history.pop_front();
-                                        }
+                            KeyCode::Enter if input_buffer.trim() == "/link" => {
+                                status(&format!("Link: {}", live.link_quality()));
+                                input_buffer.clear();
+                            }
+                            KeyCode::Enter if !input_buffer.trim().is_empty() => {
+                                let message = format!("{}: {}", username, input_buffer.trim());
+                                status(&format!("Sending: {}", message));
+
+                                // Muted for the decoder only while the frame plays
+                                let sent = self.send_message(&message, None, None, None).await;
+
+                                if let Err(e) = sent {
+                                    status(&format!("Failed to send message: {}", e));
+                                } else {
+                                    message_history.push_back(message);
+                                    if message_history.len() > 50 {
+                                        message_history.pop_front();
                                     }
-
-                                    input_buffer.clear();
                                 }
+
+                                input_buffer.clear();
                             }
                             KeyCode::Backspace => {
                                 input_buffer.pop();
@@ -578,6 +1651,20 @@ impl UshApp {
                 let dur = duration.unwrap_or(5.0);
                 self.measure_noise_level(dur).await
             }
+            TestCommands::Calibrate { duration } => self.calibrate(duration.unwrap_or(2.0)).await,
+            TestCommands::Codec { message, bitrates } => {
+                let test_message = message.as_deref().unwrap_or("Hello, World!");
+                let bitrates = if bitrates.is_empty() {
+                    CODEC_TEST_BITRATES.to_vec()
+                } else {
+                    bitrates
+                        .iter()
+                        .map(|&bitrate| validate_bitrate(bitrate))
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| UshError::Config { message: e })?
+                };
+                self.test_codec(test_message, &bitrates)
+            }
         }
     }
 
@@ -704,10 +1791,13 @@ impl UshApp {
         // Encode message
         let mut encoder = ProtocolEncoder::new();
         let frame_data = encoder.encode_text(message)?;
-        let samples = self.modulator.encode_bytes(&frame_data);
+        let samples = self.modulate(&frame_data);
 
         // Decode message
-        let decoded_bytes = self.demodulator.decode_bytes(&samples)?;
+        let decoded_bytes = match &self.fec {
+            Some(code) => code.decode_soft_bytes(&self.demodulator.soft_decode_samples(&samples)?),
+            None => self.demodulator.decode_bytes(&samples)?,
+        };
         let mut decoder = ProtocolDecoder::new();
         let messages = decoder.feed_data(&decoded_bytes);
 
@@ -728,6 +1818,165 @@ impl UshApp {
         Ok(())
     }
 
+    /// Play runs of each tone through the speaker while recording, and
+    /// suggest the pre-emphasis that makes them arrive equally strong
+    async fn calibrate(&self, duration: f32) -> UshResult<()> {
+        let config = self.demodulator.config().clone();
+        println!(
+            "Calibrating: playing {} Hz and {} Hz for {:.1}s...",
+            config.freq_0, config.freq_1, duration
+        );
+
+        // Runs long enough that most symbol windows hold a single tone,
+        // whatever the recording latency
+        let symbols = (duration / config.symbol_duration) as usize;
+        let bits: Vec<bool> = (0..symbols)
+            .map(|i| (i / CALIBRATION_RUN) % 2 == 1)
+            .collect();
+        let samples = FskModulator::new(config)
+            .with_amplitude(self.settings.tx_level.amplitude())
+            .encode_bits(&bits);
+
+        let capacity = samples.len() + self.settings.sample_rate as usize;
+        let (input_stream, mut ring) = self.audio_manager.create_input_ring(capacity)?;
+        input_stream.play()?;
+        self.play_samples(&samples).await?;
+        drop(input_stream);
+        let recorded = drain_ring(&mut ring);
+
+        let powers: Vec<SymbolPowers> = recorded
+            .chunks_exact(self.demodulator.samples_per_symbol())
+            .map(|symbol| self.demodulator.measure_symbol(symbol))
+            .collect();
+        let Some(balance) = tone_balance(&powers) else {
+            println!("✗ Could not hear both tones; turn the volume up and try again");
+            return Ok(());
+        };
+
+        let balance_db = 10.0 * balance.log10();
+        println!(
+            "{} Hz arrives {:.1} dB {} than {} Hz",
+            self.settings.freq_1,
+            balance_db.abs(),
+            if balance_db >= 0.0 {
+                "weaker"
+            } else {
+                "stronger"
+            },
+            self.settings.freq_0
+        );
+        println!(
+            "Send with --pre-emphasis {:.1} to even them out",
+            balance_db.clamp(-MAX_PRE_EMPHASIS_DB, MAX_PRE_EMPHASIS_DB)
+        );
+        Ok(())
+    }
+
+    /// Send a frame through MP3 at each bitrate, on the configured tones and
+    /// every channel of the plan, and report which combinations decode
+    fn test_codec(&self, message: &str, bitrates: &[u32]) -> UshResult<()> {
+        let frame_data = ProtocolEncoder::new().encode_text(message)?;
+        let base = self.demodulator.config().clone();
+        let mut carriers = vec![(
+            "tones".to_string(),
+            (self.settings.freq_0, self.settings.freq_1),
+        )];
+        carriers.extend(
+            Channel::all()
+                .map(|channel| (format!("ch {}", channel.number()), channel.frequencies())),
+        );
+
+        println!("Testing \"{}\" through MP3", message);
+        for (name, (freq_0, freq_1)) in &carriers {
+            println!("  {:>6}: {:.0}/{:.0} Hz", name, freq_0, freq_1);
+        }
+        println!();
+        print!("{:>8}", "kbps");
+        for (name, _) in &carriers {
+            print!(" {:>9}", name);
+        }
+        println!();
+
+        let mut lowest_passing: Vec<Option<u32>> = vec![None; carriers.len()];
+        let mut sorted = bitrates.to_vec();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        for &bitrate in &sorted {
+            print!("{:>8}", bitrate);
+            for (column, (_, (freq_0, freq_1))) in carriers.iter().enumerate() {
+                let config = ModulationConfig {
+                    freq_0: *freq_0,
+                    freq_1: *freq_1,
+                    ..base.clone()
+                };
+                let outcome = self.codec_round_trip(&frame_data, message, config, bitrate)?;
+                if let CodecOutcome::Decoded { .. } = outcome {
+                    lowest_passing[column] = Some(bitrate);
+                }
+                print!(" {:>9}", outcome.to_string());
+            }
+            println!();
+        }
+
+        println!();
+        for ((name, _), lowest) in carriers.iter().zip(&lowest_passing) {
+            match lowest {
+                Some(bitrate) => println!("  {:>6}: lowest bitrate decoded {} kbps", name, bitrate),
+                None => println!("  {:>6}: did not survive any bitrate tested", name),
+            }
+        }
+        Ok(())
+    }
+
+    fn codec_round_trip(
+        &self,
+        frame_data: &[u8],
+        message: &str,
+        config: ModulationConfig,
+        bitrate: u32,
+    ) -> UshResult<CodecOutcome> {
+        let modulator = FskModulator::new(config.clone());
+        let signal = match &self.fec {
+            Some(code) => modulator.encode_bits(&code.encode_bytes(frame_data)),
+            None => modulator.encode_bytes(frame_data),
+        };
+        // Silence either side absorbs the encoder delay and padding
+        let padding = vec![0.0; self.settings.sample_rate as usize / 5];
+        let samples = [padding.as_slice(), &signal, &padding].concat();
+
+        let mp3 = encode_mp3(&samples, self.settings.sample_rate, bitrate)?;
+        let decoded = decode_audio(mp3, Some("mp3"))?;
+        let samples = resample(
+            &decoded.samples,
+            decoded.sample_rate,
+            self.settings.sample_rate,
+        );
+
+        let mut receiver = MessageReceiver::new(config, DEFAULT_THRESHOLD);
+        if let Some(code) = &self.fec {
+            receiver = receiver.with_fec(code.clone());
+        }
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+
+        let mut outcome = CodecOutcome::Silent;
+        for event in events {
+            match event {
+                ReceiverEvent::Message {
+                    message: received,
+                    quality,
+                    ..
+                } if received.get_text().is_ok_and(|text| text == message) => {
+                    return Ok(CodecOutcome::Decoded {
+                        snr_db: quality.snr_db,
+                    });
+                }
+                ReceiverEvent::SignalDetected { .. } => outcome = CodecOutcome::Garbled,
+                _ => {}
+            }
+        }
+        Ok(outcome)
+    }
+
     async fn generate_tone(&self, frequency: f32, duration: f32) -> UshResult<()> {
         println!("Generating {}Hz tone for {:.1}s", frequency, duration);
 
@@ -762,137 +2011,366 @@ impl UshApp {
         let rms = (samples.iter().map(|&s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
         let peak = samples.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
 
+        let mut link = self.link_monitor();
+        link.push_samples(&samples);
+        let link = link.quality();
+        let tone = |snr: Option<f32>| match snr {
+            Some(snr) => format!("present, {:.1} dB SNR", snr),
+            None => "quiet".to_string(),
+        };
+
         println!("Noise measurement results:");
         println!("  RMS level: {:.6} ({:.1} dB)", rms, 20.0 * rms.log10());
         println!("  Peak level: {:.6} ({:.1} dB)", peak, 20.0 * peak.log10());
+        println!("  Noise floor: {:.1} dB per bin", link.noise_floor_db);
+        println!("  {} Hz: {}", self.settings.freq_0, tone(link.snr_0_db));
+        println!("  {} Hz: {}", self.settings.freq_1, tone(link.snr_1_db));
         println!("  Samples recorded: {}", samples.len());
+        if let TxLevel::Adaptive(adaptive) = self.settings.tx_level
+            && let Some(estimate) = adaptive.estimate(&samples, self.demodulator.config())
+        {
+            println!(
+                "  Adaptive transmit level: {:.3} ({:.1} dBFS)",
+                estimate.level,
+                estimate.level_db()
+            );
+        }
 
         Ok(())
     }
 
-    pub async fn debug_mode(
-        &self,
-        spectrum: bool,
-        waveform: bool,
-        rate: Option<u32>,
-    ) -> UshResult<()> {
-        let update_rate = rate.unwrap_or(10);
+    /// Listen to the whole channel plan and report which channels are busy
+    pub async fn scan_channels(&self, duration: f32, from_wav: Option<&Path>) -> UshResult<()> {
+        let samples = match from_wav {
+            Some(path) => self.load_audio_file(path)?,
+            None => {
+                println!("Scanning channels for {:.1}s...", duration);
+                let capacity = (self.settings.sample_rate as f32 * duration) as usize;
+                let (input_stream, mut ring) = self.audio_manager.create_input_ring(capacity)?;
+                input_stream.play()?;
+                sleep(Duration::from_secs_f32(duration)).await;
+                drop(input_stream);
+                drain_ring(&mut ring)
+            }
+        };
+
+        let threshold_db = CarrierSenseConfig::default().threshold_db;
+        let activity = scan_channels(&samples, self.settings.sample_rate, threshold_db);
+        if activity.is_empty() {
+            return Err(UshError::Config {
+                message: format!(
+                    "No channel fits below {} Hz, half the {} Hz sample rate",
+                    self.settings.sample_rate / 2,
+                    self.settings.sample_rate
+                ),
+            });
+        }
+
         println!(
-            "Debug mode - spectrum: {}, waveform: {}, rate: {}Hz",
-            spectrum, waveform, update_rate
+            "{:<8} {:<14} {:>10} {:>8}  STATUS",
+            "CHANNEL", "TONES (Hz)", "PEAK SNR", "ACTIVE"
         );
-        println!("This feature requires additional implementation for real-time visualization.");
+        for channel in &activity {
+            let in_use =
+                self.settings.freq_0 == channel.freq_0 && self.settings.freq_1 == channel.freq_1;
+            println!(
+                "{:<8} {:<14} {:>7.1} dB {:>7.0}%  {}{}",
+                channel.channel,
+                format!("{:.0}/{:.0}", channel.freq_0, channel.freq_1),
+                channel.peak_snr_db,
+                channel.busy_fraction * 100.0,
+                if channel.is_busy() { "busy" } else { "idle" },
+                if in_use { " (selected)" } else { "" }
+            );
+        }
 
-        sleep(Duration::from_secs(1)).await;
+        if let Some(free) = activity.iter().find(|channel| !channel.is_busy()) {
+            println!(
+                "\nChannel {} is free: use --channel {}",
+                free.channel, free.channel
+            );
+        } else {
+            println!("\nEvery channel is busy");
+        }
         Ok(())
     }
 
-    async fn play_samples(&self, samples: &[f32]) -> UshResult<()> {
-        let samples_arc = Arc::new(Mutex::new(samples.to_vec()));
-        let (tx, mut rx) = mpsc::unbounded_channel();
-
-        let output_stream = self.audio_manager.create_output_stream(samples_arc, tx)?;
-        output_stream.play()?;
+    pub async fn pair(&self, initiate: bool, timeout_secs: Option<u32>) -> UshResult<()> {
+        let Some(path) = session_key_path() else {
+            return Err(UshError::Config {
+                message: "Cannot locate the config directory (HOME is not set)".to_string(),
+            });
+        };
 
-        // Wait for playback to complete
-        if (rx.recv().await).is_some() {
-            info!("Playback completed");
+        let mut config = PairingConfig::default();
+        if let Some(timeout) = timeout_secs {
+            config.response_timeout = Duration::from_secs(timeout as u64);
+            config.linger = config.response_timeout;
         }
+        let role = if initiate {
+            PairingRole::Initiator
+        } else {
+            println!("Waiting for the other device; run `ush pair --initiate` there");
+            PairingRole::Responder
+        };
 
-        drop(output_stream);
-        sleep(Duration::from_millis(100)).await; // Small delay for cleanup
+        let ring_capacity = self.settings.sample_rate as usize * INPUT_RING_SECONDS;
+        let (input_stream, ring) = self.audio_manager.create_input_ring(ring_capacity)?;
+        input_stream.play()?;
+        let mut link = DeviceLink {
+            app: self,
+            _input_stream: input_stream,
+            ring,
+        };
 
-        Ok(())
-    }
+        let outcome =
+            ush::pairing::pair(&mut link, role, self.demodulator.config().clone(), &config).await?;
+        drop(link);
 
-    fn load_wav_file(&self, path: &Path) -> UshResult<Vec<f32>> {
-        let mut reader = hound::WavReader::open(path)?;
-        let spec = reader.spec();
+        println!("Verification code: {}", outcome.sas);
+        print!("Does the other device show the same code? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
 
-        info!(
-            "Loading WAV: {}Hz, {} channels, {} bits",
-            spec.sample_rate, spec.channels, spec.bits_per_sample
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Codes not confirmed; discarding the session key");
+            return Ok(());
+        }
+
+        save_session_key(&outcome.session_key, &path)?;
+        println!(
+            "Paired. Session key {:08x} saved to {:?} and used by send, listen and chat",
+            outcome.session_key.id(),
+            path
         );
+        Ok(())
+    }
 
-        let samples: UshResult<Vec<f32>> = match spec.sample_format {
-            hound::SampleFormat::Float => reader
-                .samples::<f32>()
-                .map(|s| s.map_err(UshError::from))
-                .collect(),
-            hound::SampleFormat::Int => match spec.bits_per_sample {
-                16 => {
-                    let samples: Result<Vec<i16>, _> = reader.samples().collect();
-                    Ok(samples?
-                        .into_iter()
-                        .map(|s| s as f32 / i16::MAX as f32)
-                        .collect())
-                }
-                32 => {
-                    let samples: Result<Vec<i32>, _> = reader.samples().collect();
-                    Ok(samples?
-                        .into_iter()
-                        .map(|s| s as f32 / i32::MAX as f32)
-                        .collect())
-                }
-                _ => Err(UshError::Config {
-                    message: format!("Unsupported bit depth: {}", spec.bits_per_sample),
-                }),
-            },
+    fn inspector(&self, threshold: f32) -> Inspector {
+        let inspector = Inspector::new(self.demodulator.config().clone(), threshold);
+        let inspector = match &self.fec {
+            Some(code) => inspector.with_fec(code.clone()),
+            None => inspector,
         };
-
-        samples
+        match &self.key {
+            Some(key) => inspector.with_key(key.clone()),
+            None => inspector,
+        }
     }
 
-    fn save_wav_file(&self, samples: &[f32], path: &Path) -> UshResult<()> {
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: self.settings.sample_rate,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+    /// Print every symbol, byte and decoder state change of the microphone or
+    /// a WAV file, with the sample offset each one happened at
+    pub async fn inspect(
+        &self,
+        from_wav: Option<&Path>,
+        timeout_secs: Option<u32>,
+        threshold: f32,
+        show_symbols: bool,
+    ) -> UshResult<()> {
+        let mut inspector = self.inspector(threshold);
+        let mut summary = InspectSummary::default();
+        let mut print = |events: Vec<InspectEvent>| {
+            for event in events {
+                summary.count(&event);
+                if let Some(line) =
+                    describe_inspect_event(&event, self.settings.sample_rate, show_symbols)
+                {
+                    println!("{}", line);
+                }
+            }
         };
 
-        let mut writer = hound::WavWriter::create(path, spec)?;
+        if let Some(path) = from_wav {
+            let samples = self.load_audio_file(path)?;
+            print(inspector.push_samples(&samples));
+        } else {
+            let ring_capacity = self.settings.sample_rate as usize * INPUT_RING_SECONDS;
+            let (input_stream, mut ring) = self.audio_manager.create_input_ring(ring_capacity)?;
+            input_stream.play()?;
+            println!("Inspecting the microphone, press Ctrl+C to stop");
+
+            let start_time = Instant::now();
+            loop {
+                if let Some(timeout) = timeout_secs
+                    && start_time.elapsed().as_secs() > timeout as u64
+                {
+                    break;
+                }
+
+                print(inspector.push_samples(&drain_ring(&mut ring)));
 
-        for &sample in samples {
-            writer.write_sample(sample)?;
+                if event::poll(Duration::from_millis(50)).unwrap_or(false)
+                    && let Ok(Event::Key(key_event)) = event::read()
+                    && key_event.kind == KeyEventKind::Press
+                    && key_event.code == KeyCode::Char('c')
+                    && key_event.modifiers.contains(event::KeyModifiers::CONTROL)
+                {
+                    break;
+                }
+            }
+            drop(input_stream);
         }
+        print(inspector.flush());
 
-        writer.finalize()?;
+        println!(
+            "\n{} transmissions, {} frames decoded, {} failures",
+            summary.transmissions, summary.frames, summary.failures
+        );
         Ok(())
     }
-}
 
-// Add base64 encoding for file transfer
-mod base64 {
-    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    /// Live spectrum, waterfall and waveform of the microphone, until q or Ctrl+C
+    pub async fn debug_mode(
+        &self,
+        spectrum: bool,
+        waveform: bool,
+        rate: Option<u32>,
+    ) -> UshResult<()> {
+        let update_rate = rate.unwrap_or(10).clamp(1, 60);
+        let ring_capacity = self.settings.sample_rate as usize * INPUT_RING_SECONDS;
+        let (input_stream, ring) = self.audio_manager.create_input_ring(ring_capacity)?;
+        input_stream.play()?;
+
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+
+        let result = self
+            .run_visualizer(ring, Panels::from_flags(spectrum, waveform), update_rate)
+            .await;
 
-    pub fn encode(input: &[u8]) -> String {
-        let mut result = String::new();
-        let mut i = 0;
+        execute!(io::stdout(), cursor::Show, LeaveAlternateScreen)?;
+        disable_raw_mode()?;
+        drop(input_stream);
 
-        while i < input.len() {
-            let b1 = input[i];
-            let b2 = if i + 1 < input.len() { input[i + 1] } else { 0 };
-            let b3 = if i + 2 < input.len() { input[i + 2] } else { 0 };
+        result
+    }
 
-            let bitmap = ((b1 as u32) << 16) | ((b2 as u32) << 8) | (b3 as u32);
+    async fn run_visualizer(
+        &self,
+        mut ring: InputRing,
+        panels: Panels,
+        update_rate: u32,
+    ) -> UshResult<()> {
+        let sample_rate = self.settings.sample_rate;
+        let mut visualizer = Visualizer::new(
+            sample_rate,
+            self.settings.freq_0,
+            self.settings.freq_1,
+            panels,
+        );
+        let mut receiver = self.message_receiver(DEFAULT_THRESHOLD);
+        let frame_interval = Duration::from_secs_f32(1.0 / update_rate as f32);
+        let seconds = |sample_offset: u64| sample_offset as f32 / sample_rate as f32;
+        let mut stdout = io::stdout();
 
-            result.push(CHARS[((bitmap >> 18) & 63) as usize] as char);
-            result.push(CHARS[((bitmap >> 12) & 63) as usize] as char);
-            if i + 1 < input.len() {
-                result.push(CHARS[((bitmap >> 6) & 63) as usize] as char);
-            } else {
-                result.push('=');
+        loop {
+            let frame_start = Instant::now();
+
+            let samples = drain_ring(&mut ring);
+            visualizer.push_samples(&samples);
+            for event in receiver.push_samples(&samples) {
+                let line = match event {
+                    ReceiverEvent::SignalDetected { sample_offset } => {
+                        format!("{:8.2}s  signal detected", seconds(sample_offset))
+                    }
+                    ReceiverEvent::Message {
+                        message,
+                        sender,
+                        quality,
+                    } => format!(
+                        "{:8.2}s  {:?} #{} from {}: {} ({:.1} dB SNR, {:+.0} ppm)",
+                        seconds(receiver.samples_seen()),
+                        message.header.message_type,
+                        message.header.sequence_number,
+                        sender,
+                        message
+                            .get_text()
+                            .unwrap_or_else(|_| format!("{} bytes", message.payload.len())),
+                        quality.snr_db,
+                        quality.frequency_offset_ppm
+                    ),
+                    ReceiverEvent::Rejected { reason, .. } => {
+                        format!(
+                            "{:8.2}s  rejected: {}",
+                            seconds(receiver.samples_seen()),
+                            reason
+                        )
+                    }
+                    ReceiverEvent::DecodeFailed {
+                        sample_offset,
+                        bytes,
+                        quality,
+                    } => format!(
+                        "{:8.2}s  decode failed after {} bytes ({:.1} dB SNR)",
+                        seconds(sample_offset),
+                        bytes,
+                        quality.snr_db
+                    ),
+                };
+                visualizer.log_event(line);
             }
-            if i + 2 < input.len() {
-                result.push(CHARS[(bitmap & 63) as usize] as char);
-            } else {
-                result.push('=');
+
+            visualizer.update();
+            let (width, height) = terminal::size()?;
+            for (row, line) in visualizer
+                .render(width as usize, height as usize)
+                .iter()
+                .enumerate()
+            {
+                queue!(stdout, cursor::MoveTo(0, row as u16), Print(line))?;
+            }
+            stdout.flush()?;
+
+            // Wait out the rest of the frame, watching for the quit keys
+            let remaining = frame_interval.saturating_sub(frame_start.elapsed());
+            if event::poll(remaining)?
+                && let Event::Key(key_event) = event::read()?
+                && key_event.kind == KeyEventKind::Press
+            {
+                match key_event.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('c')
+                        if key_event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                    {
+                        break;
+                    }
+                    _ => {}
+                }
             }
+            // Yield so the poll above doesn't starve the runtime
+            sleep(Duration::ZERO).await;
+        }
+
+        Ok(())
+    }
+
+    async fn play_samples(&self, samples: &[f32]) -> UshResult<()> {
+        let samples_arc = Arc::new(Mutex::new(samples.to_vec()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let output_stream = self.audio_manager.create_output_stream(samples_arc, tx)?;
+        output_stream.play()?;
 
-            i += 3;
+        // Wait for playback to complete
+        if (rx.recv().await).is_some() {
+            info!("Playback completed");
         }
 
-        result
+        drop(output_stream);
+        sleep(Duration::from_millis(100)).await; // Small delay for cleanup
+
+        Ok(())
+    }
+
+    /// Samples of a recording in any supported format, mixed down and
+    /// resampled to the configured sample rate
+    fn load_audio_file(&self, path: &Path) -> UshResult<Vec<f32>> {
+        load_audio_file(path, self.settings.sample_rate)
+    }
+
+    /// Write samples as WAV, or as MP3 if the path ends in `.mp3`
+    fn save_audio_file(&self, samples: &[f32], path: &Path) -> UshResult<()> {
+        write_audio_file(path, samples, self.settings.sample_rate, self.mp3_bitrate)
     }
 }
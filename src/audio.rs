@@ -5,6 +5,8 @@ use cpal::{
     SampleRate, Stream, SupportedStreamConfig,
 };
 use log::{debug, error, info, warn};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 
@@ -28,6 +30,170 @@ impl Default for AudioConfig {
     }
 }
 
+/// Counters shared between the realtime input callback and the consumer
+#[derive(Debug, Default)]
+pub struct RingStats {
+    samples_written: AtomicU64,
+    samples_dropped: AtomicU64,
+    overruns: AtomicU64,
+}
+
+/// Point-in-time copy of [`RingStats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingStatsSnapshot {
+    pub samples_written: u64,
+    pub samples_dropped: u64,
+    pub overruns: u64,
+}
+
+impl RingStats {
+    pub fn snapshot(&self) -> RingStatsSnapshot {
+        RingStatsSnapshot {
+            samples_written: self.samples_written.load(Ordering::Relaxed),
+            samples_dropped: self.samples_dropped.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Producer half of the input ring, owned by the audio callback.
+///
+/// Pushing never blocks or allocates; samples that do not fit are dropped
+/// and counted as an overrun.
+pub struct InputRingWriter {
+    producer: rtrb::Producer<f32>,
+    stats: Arc<RingStats>,
+}
+
+impl InputRingWriter {
+    pub fn push(&mut self, samples: &[f32]) {
+        let (_, rest) = self.producer.push_partial_slice(samples);
+        let written = samples.len() - rest.len();
+
+        self.stats
+            .samples_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+        if !rest.is_empty() {
+            self.stats
+                .samples_dropped
+                .fetch_add(rest.len() as u64, Ordering::Relaxed);
+            self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Pass a captured chunk to carrier sense through `tap`, then silence it for
+/// the decoder while our own transmission plays. Carrier sense has to hear
+/// the room even then, or it would always find the channel idle.
+pub fn tap_and_mute(chunk: &mut [f32], tap: &mut InputRingWriter, muted: bool) {
+    tap.push(chunk);
+    if muted {
+        chunk.fill(0.0);
+    }
+}
+
+/// Consumer half of the input ring, read by the decode task
+pub struct InputRing {
+    consumer: rtrb::Consumer<f32>,
+    stats: Arc<RingStats>,
+}
+
+impl InputRing {
+    /// Move every sample currently available into `out`, returning how many were read
+    pub fn pop_into(&mut self, out: &mut Vec<f32>) -> usize {
+        let available = self.consumer.slots();
+        if available == 0 {
+            return 0;
+        }
+
+        match self.consumer.read_chunk(available) {
+            Ok(chunk) => {
+                let (first, second) = chunk.as_slices();
+                out.extend_from_slice(first);
+                out.extend_from_slice(second);
+                chunk.commit_all();
+                available
+            }
+            Err(_) => 0,
+        }
+    }
+
+    /// Number of samples waiting to be consumed
+    pub fn len(&self) -> usize {
+        self.consumer.slots()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumer.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.consumer.buffer().capacity()
+    }
+
+    pub fn stats(&self) -> RingStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+/// Create a lock-free single-producer/single-consumer sample ring
+pub fn input_ring(capacity: usize) -> (InputRingWriter, InputRing) {
+    let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+    let stats = Arc::new(RingStats::default());
+
+    (
+        InputRingWriter {
+            producer,
+            stats: stats.clone(),
+        },
+        InputRing { consumer, stats },
+    )
+}
+
+/// A two-way acoustic channel at the sample level: speaker out, microphone in
+pub trait AudioLink {
+    /// Play samples to the other end, returning once they have been sent
+    fn transmit(&mut self, samples: &[f32]) -> impl Future<Output = UshResult<()>>;
+
+    /// Samples heard from the other end since the last call, possibly none
+    fn capture(&mut self) -> impl Future<Output = UshResult<Vec<f32>>>;
+}
+
+/// One end of an in-memory [`AudioLink`], for exercising audio protocols
+/// without devices. What one end transmits, the other captures.
+pub struct MemoryLink {
+    outgoing: Arc<Mutex<Vec<f32>>>,
+    incoming: Arc<Mutex<Vec<f32>>>,
+}
+
+/// Create both ends of an in-memory link
+pub fn memory_link() -> (MemoryLink, MemoryLink) {
+    let a_to_b = Arc::new(Mutex::new(Vec::new()));
+    let b_to_a = Arc::new(Mutex::new(Vec::new()));
+
+    (
+        MemoryLink {
+            outgoing: a_to_b.clone(),
+            incoming: b_to_a.clone(),
+        },
+        MemoryLink {
+            outgoing: b_to_a,
+            incoming: a_to_b,
+        },
+    )
+}
+
+impl AudioLink for MemoryLink {
+    async fn transmit(&mut self, samples: &[f32]) -> UshResult<()> {
+        self.outgoing.lock().unwrap().extend_from_slice(samples);
+        Ok(())
+    }
+
+    async fn capture(&mut self) -> UshResult<Vec<f32>> {
+        Ok(std::mem::take(&mut *self.incoming.lock().unwrap()))
+    }
+}
+
 pub struct AudioManager {
     host: Host,
     config: AudioConfig,
@@ -293,6 +459,13 @@ impl AudioManager {
         Ok(stream)
     }
 
+    /// Open the input device and stream its samples into a lock-free ring buffer
+    pub fn create_input_ring(&self, capacity: usize) -> UshResult<(Stream, InputRing)> {
+        let (mut writer, ring) = input_ring(capacity);
+        let stream = self.create_input_stream(move |data| writer.push(data))?;
+        Ok((stream, ring))
+    }
+
     pub fn create_output_stream(
         &self,
         samples: Arc<Mutex<Vec<f32>>>,
@@ -431,3 +604,71 @@ impl AudioManager {
         &self.host
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_ring_preserves_order() {
+        let (mut writer, mut ring) = input_ring(16);
+        writer.push(&[1.0, 2.0, 3.0]);
+        writer.push(&[4.0, 5.0]);
+
+        let mut out = Vec::new();
+        assert_eq!(ring.pop_into(&mut out), 5);
+        assert_eq!(out, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_input_ring_counts_overruns() {
+        let (mut writer, mut ring) = input_ring(4);
+        writer.push(&[1.0, 2.0, 3.0]);
+        writer.push(&[4.0, 5.0, 6.0]);
+
+        let stats = ring.stats();
+        assert_eq!(stats.samples_written, 4);
+        assert_eq!(stats.samples_dropped, 2);
+        assert_eq!(stats.overruns, 1);
+
+        let mut out = Vec::new();
+        ring.pop_into(&mut out);
+        assert_eq!(out, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[tokio::test]
+    async fn test_carrier_sense_hears_the_tap_while_sending() {
+        use crate::carrier::{CarrierSense, CarrierSenseConfig};
+        use crate::modulation::{FskModulator, ModulationConfig};
+        use std::time::Duration;
+
+        let config = ModulationConfig::default();
+        let other_sender = FskModulator::new(config.clone()).encode_bytes(b"busy");
+        let mut sense = CarrierSense::new(
+            &config,
+            CarrierSenseConfig {
+                listen_window: Duration::from_millis(1),
+                slot: Duration::from_millis(1),
+                max_attempts: 2,
+                ..CarrierSenseConfig::default()
+            },
+        );
+        let (mut tap, mut tap_ring) = input_ring(config.sample_rate as usize);
+
+        // The decode thread keeps capturing a peer while our send is muted
+        let result = sense
+            .wait_for_idle(|| {
+                let mut chunk = other_sender.clone();
+                tap_and_mute(&mut chunk, &mut tap, true);
+                assert!(chunk.iter().all(|&s| s == 0.0));
+
+                let mut heard = Vec::new();
+                tap_ring.pop_into(&mut heard);
+                heard
+            })
+            .await;
+
+        assert!(matches!(result, Err(UshError::ChannelBusy { .. })));
+    }
+}
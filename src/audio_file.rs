@@ -0,0 +1,414 @@
+//! Reading and writing audio files
+//!
+//! Any container and codec symphonia is built with (WAV at any bit depth,
+//! FLAC, MP3, OGG Vorbis) is decoded, mixed down to mono and resampled to
+//! the rate the modem runs at, so recordings made by phones and other tools
+//! can be fed straight to the receiver. Encoded audio can be written as WAV
+//! or, through LAME, as MP3.
+
+use crate::error::{UshError, UshResult};
+use log::{debug, info, warn};
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, Mode, MonoPcm, Quality};
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Passband edge of the resampling filter as a fraction of the lower
+/// Nyquist frequency: 21.4 kHz when converting 48 kHz to 44.1 kHz
+const RESAMPLE_CUTOFF: f64 = 0.97;
+/// Zero crossings of the windowed sinc on either side of its centre
+const RESAMPLE_ZEROS: usize = 64;
+/// Kernel table entries per input sample
+const KERNEL_STEPS: usize = 256;
+
+/// Constant bitrates LAME can encode at, in kbps
+pub const MP3_BITRATES: [u32; 16] = [
+    8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+/// LAME low-passes lower bitrates below the upper carriers
+pub const DEFAULT_MP3_BITRATE: u32 = 320;
+
+/// Mono samples decoded from a file, at the rate it was recorded at
+#[derive(Debug, Clone)]
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    /// Channels in the file before mixing down
+    pub channels: usize,
+}
+
+/// Decode an audio file to mono at its own sample rate
+pub fn read_audio_file(path: &Path) -> UshResult<DecodedAudio> {
+    let extension = path.extension().and_then(|e| e.to_str());
+    let audio = decode(Box::new(File::open(path)?), extension)?;
+    info!(
+        "Loaded {}: {}Hz, {} channels, {:.2}s",
+        path.display(),
+        audio.sample_rate,
+        audio.channels,
+        audio.samples.len() as f32 / audio.sample_rate as f32
+    );
+    Ok(audio)
+}
+
+/// Decode an encoded file held in memory, such as the output of [`encode_mp3`]
+pub fn decode_audio(data: Vec<u8>, extension: Option<&str>) -> UshResult<DecodedAudio> {
+    decode(Box::new(Cursor::new(data)), extension)
+}
+
+fn decode(source: Box<dyn MediaSource>, extension: Option<&str>) -> UshResult<DecodedAudio> {
+    let source = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| UshError::Decoding {
+            message: "no audio track".to_string(),
+        })?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    let mut channels = 0;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet costs a few milliseconds, not the whole file
+            Err(SymphoniaError::DecodeError(e)) => {
+                warn!("Skipping undecodable packet: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        sample_rate = Some(spec.rate);
+        channels = spec.channels.count();
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend(downmix(buffer.samples(), channels));
+    }
+
+    let sample_rate = sample_rate.ok_or_else(|| UshError::Decoding {
+        message: "no sample rate declared".to_string(),
+    })?;
+
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+        channels,
+    })
+}
+
+/// Decode an audio file to mono at `sample_rate`
+pub fn load_audio_file(path: &Path, sample_rate: u32) -> UshResult<Vec<f32>> {
+    let audio = read_audio_file(path)?;
+    if audio.sample_rate != sample_rate {
+        debug!("Resampling {}Hz to {}Hz", audio.sample_rate, sample_rate);
+    }
+    Ok(resample(&audio.samples, audio.sample_rate, sample_rate))
+}
+
+/// Write mono samples to `path`, as MP3 if its extension is `.mp3` and as
+/// 32-bit float WAV otherwise
+pub fn write_audio_file(
+    path: &Path,
+    samples: &[f32],
+    sample_rate: u32,
+    mp3_bitrate: u32,
+) -> UshResult<()> {
+    let is_mp3 = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("mp3"));
+    if is_mp3 {
+        std::fs::write(path, encode_mp3(samples, sample_rate, mp3_bitrate)?)?;
+        return Ok(());
+    }
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in samples {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Encode mono samples as a constant bitrate MP3 at the highest quality
+pub fn encode_mp3(samples: &[f32], sample_rate: u32, bitrate_kbps: u32) -> UshResult<Vec<u8>> {
+    let lame_error = |e: mp3lame_encoder::BuildError| UshError::Encoding {
+        message: format!("MP3 encoder: {}", e),
+    };
+    let mut builder = Builder::new().ok_or_else(|| UshError::Encoding {
+        message: "MP3 encoder: out of memory".to_string(),
+    })?;
+    builder.set_num_channels(1).map_err(lame_error)?;
+    builder.set_mode(Mode::Mono).map_err(lame_error)?;
+    builder.set_sample_rate(sample_rate).map_err(lame_error)?;
+    builder
+        .set_brate(mp3_bitrate(bitrate_kbps)?)
+        .map_err(lame_error)?;
+    builder.set_quality(Quality::Best).map_err(lame_error)?;
+    let mut encoder = builder.build().map_err(lame_error)?;
+
+    let encode_error = |e: mp3lame_encoder::EncodeError| UshError::Encoding {
+        message: format!("MP3 encoder: {}", e),
+    };
+    let mut output = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(samples.len()));
+    let written = encoder
+        .encode(MonoPcm(samples), output.spare_capacity_mut())
+        .map_err(encode_error)?;
+    // SAFETY: the encoder initialised the `written` bytes after the length
+    unsafe { output.set_len(output.len() + written) };
+    output.reserve(7200);
+    let written = encoder
+        .flush::<FlushNoGap>(output.spare_capacity_mut())
+        .map_err(encode_error)?;
+    // SAFETY: as above
+    unsafe { output.set_len(output.len() + written) };
+
+    debug!(
+        "Encoded {} samples to {} bytes of MP3 at {} kbps",
+        samples.len(),
+        output.len(),
+        bitrate_kbps
+    );
+    Ok(output)
+}
+
+fn mp3_bitrate(kbps: u32) -> UshResult<Bitrate> {
+    Ok(match kbps {
+        8 => Bitrate::Kbps8,
+        16 => Bitrate::Kbps16,
+        24 => Bitrate::Kbps24,
+        32 => Bitrate::Kbps32,
+        40 => Bitrate::Kbps40,
+        48 => Bitrate::Kbps48,
+        64 => Bitrate::Kbps64,
+        80 => Bitrate::Kbps80,
+        96 => Bitrate::Kbps96,
+        112 => Bitrate::Kbps112,
+        128 => Bitrate::Kbps128,
+        160 => Bitrate::Kbps160,
+        192 => Bitrate::Kbps192,
+        224 => Bitrate::Kbps224,
+        256 => Bitrate::Kbps256,
+        320 => Bitrate::Kbps320,
+        _ => {
+            return Err(UshError::Config {
+                message: format!("MP3 bitrate {} kbps is not one of {:?}", kbps, MP3_BITRATES),
+            });
+        }
+    })
+}
+
+/// Average interleaved channels into one
+fn downmix(interleaved: &[f32], channels: usize) -> impl Iterator<Item = f32> + '_ {
+    interleaved
+        .chunks_exact(channels.max(1))
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+}
+
+/// Convert between sample rates with a Blackman-windowed sinc filter.
+///
+/// The filter is steep enough to keep the ultrasonic carriers intact when
+/// converting between 44.1 and 48 kHz; tones within a few hundred Hz of the
+/// lower Nyquist frequency are attenuated.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() || from == 0 || to == 0 {
+        return samples.to_vec();
+    }
+
+    let ratio = to as f64 / from as f64;
+    // Cutoff relative to the input Nyquist frequency
+    let cutoff = RESAMPLE_CUTOFF * ratio.min(1.0);
+    let half_width = RESAMPLE_ZEROS as f64 / cutoff;
+    let kernel = sinc_kernel(cutoff, half_width);
+    let reach = half_width.ceil() as isize;
+
+    let output_len = (samples.len() as f64 * ratio).round() as usize;
+    (0..output_len)
+        .map(|n| {
+            let position = n as f64 / ratio;
+            let centre = position.floor() as isize;
+            let first = (centre - reach + 1).max(0);
+            let last = (centre + reach).min(samples.len() as isize - 1);
+            (first..=last)
+                .map(|k| {
+                    let distance = (position - k as f64).abs() * KERNEL_STEPS as f64;
+                    let index = distance as usize;
+                    let fraction = (distance - index as f64) as f32;
+                    let tap = match (kernel.get(index), kernel.get(index + 1)) {
+                        (Some(&a), Some(&b)) => a + (b - a) * fraction,
+                        _ => 0.0,
+                    };
+                    samples[k as usize] * tap
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// One side of the windowed sinc, sampled `KERNEL_STEPS` times per input
+/// sample and zero at `half_width`
+fn sinc_kernel(cutoff: f64, half_width: f64) -> Vec<f32> {
+    use std::f64::consts::PI;
+
+    let len = (half_width * KERNEL_STEPS as f64).ceil() as usize + 1;
+    (0..len)
+        .map(|i| {
+            let x = i as f64 / KERNEL_STEPS as f64;
+            if x >= half_width {
+                return 0.0;
+            }
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * cutoff * x).sin() / (PI * cutoff * x)
+            };
+            // Blackman window centred on zero
+            let phase = PI * x / half_width;
+            let window = 0.42 + 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            (cutoff * sinc * window) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f32, sample_rate: u32, seconds: f32) -> Vec<f32> {
+        (0..(sample_rate as f32 * seconds) as usize)
+            .map(|i| {
+                0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32).sin()
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_resample_keeps_ultrasonic_tone() {
+        let input = tone(19500.0, 48000, 0.5);
+        let output = resample(&input, 48000, 44100);
+        assert_eq!(output.len(), 22050);
+
+        // Compare against the ideal tone, away from the edges
+        let expected = tone(19500.0, 44100, 0.5);
+        let middle = 2000..20000;
+        let error: Vec<f32> = output[middle.clone()]
+            .iter()
+            .zip(&expected[middle.clone()])
+            .map(|(a, b)| a - b)
+            .collect();
+        assert!(
+            rms(&error) < 0.01 * rms(&expected[middle]),
+            "error {}",
+            rms(&error)
+        );
+    }
+
+    #[test]
+    fn test_resample_rejects_content_above_nyquist() {
+        // 23 kHz cannot be represented at 44.1 kHz and must not alias to 21.1 kHz
+        let input = tone(23000.0, 48000, 0.5);
+        let output = resample(&input, 48000, 44100);
+        assert!(
+            rms(&output[2000..20000]) < 0.01,
+            "{}",
+            rms(&output[2000..20000])
+        );
+    }
+
+    #[test]
+    fn test_read_24_bit_stereo_wav() {
+        let path = std::env::temp_dir().join(format!("ush_audio_file_{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        let full_scale = (1 << 23) - 1;
+        for _ in 0..4800 {
+            writer.write_sample(full_scale / 2).unwrap();
+            writer.write_sample(0).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let audio = read_audio_file(&path).unwrap();
+        assert_eq!((audio.sample_rate, audio.channels), (48000, 2));
+        assert_eq!(audio.samples.len(), 4800);
+        assert!(audio.samples.iter().all(|s| (s - 0.25).abs() < 1e-3));
+
+        let resampled = load_audio_file(&path, 44100).unwrap();
+        assert_eq!(resampled.len(), 4410);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_mp3_round_trip_keeps_length_and_tone() {
+        let input = tone(1000.0, 44100, 1.0);
+        let mp3 = encode_mp3(&input, 44100, 128).unwrap();
+        // 128 kbps for one second, give or take a frame
+        assert!(
+            (mp3.len() as i64 - 16000).abs() < 1000,
+            "{} bytes",
+            mp3.len()
+        );
+
+        let audio = decode_audio(mp3, Some("mp3")).unwrap();
+        assert_eq!((audio.sample_rate, audio.channels), (44100, 1));
+        // The decoder adds the encoder's delay and padding at most
+        assert!(audio.samples.len() >= input.len());
+        assert!((rms(&audio.samples) - rms(&input)).abs() < 0.05);
+
+        assert!(encode_mp3(&input, 44100, 100).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! Minimal base64 encoding for binary payloads in text output
+
+const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(input: &[u8]) -> String {
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < input.len() {
+        let b1 = input[i];
+        let b2 = if i + 1 < input.len() { input[i + 1] } else { 0 };
+        let b3 = if i + 2 < input.len() { input[i + 2] } else { 0 };
+
+        let bitmap = ((b1 as u32) << 16) | ((b2 as u32) << 8) | (b3 as u32);
+
+        result.push(CHARS[((bitmap >> 18) & 63) as usize] as char);
+        result.push(CHARS[((bitmap >> 12) & 63) as usize] as char);
+        if i + 1 < input.len() {
+            result.push(CHARS[((bitmap >> 6) & 63) as usize] as char);
+        } else {
+            result.push('=');
+        }
+        if i + 2 < input.len() {
+            result.push(CHARS[(bitmap & 63) as usize] as char);
+        } else {
+            result.push('=');
+        }
+
+        i += 3;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_padding() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(&[0xff, 0x00, 0x7e]), "/wB+");
+    }
+}
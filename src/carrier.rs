@@ -0,0 +1,266 @@
+//! Listen-before-talk: hold back a transmission while someone else is sending
+//!
+//! Before each transmission the sender listens for a short window and compares
+//! the power at `freq_0` and `freq_1` with the rest of the spectrum, the same
+//! measurement the debug analyzer uses for FSK presence. While the channel is
+//! busy it backs off for a random, exponentially growing delay and listens
+//! again, so two people pressing Enter at once don't destroy both frames.
+
+use crate::modulation::ModulationConfig;
+use crate::{UshError, UshResult};
+use log::{debug, info};
+use rand::Rng;
+use rustfft::{FftPlanner, num_complex::Complex};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const FFT_SIZE: usize = 2048;
+/// Bins either side of each FSK frequency that count as in-band
+const BAND_HALF_WIDTH: usize = 3;
+/// Cap on the backoff exponent, so a retry waits at most 64 slots
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    Idle,
+    Busy,
+}
+
+#[derive(Debug, Clone)]
+pub struct CarrierSenseConfig {
+    /// In-band power above the noise floor that counts as another transmission
+    pub threshold_db: f32,
+    /// How long to listen before each decision
+    pub listen_window: Duration,
+    /// Backoff unit: the n-th retry waits a random 1..=2^n slots
+    pub slot: Duration,
+    /// Busy checks before giving up on a transmission
+    pub max_attempts: u32,
+}
+
+impl Default for CarrierSenseConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: 15.0,
+            listen_window: Duration::from_millis(200),
+            slot: Duration::from_millis(250),
+            max_attempts: 8,
+        }
+    }
+}
+
+/// Counters since the [`CarrierSense`] was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CarrierStats {
+    /// Checks that found the channel idle and let a transmission go ahead
+    pub clear: u64,
+    /// Checks that found the channel busy; each is a collision avoided
+    pub busy: u64,
+    /// Transmissions dropped because the channel never cleared
+    pub abandoned: u64,
+    pub backoff_time: Duration,
+}
+
+pub struct CarrierSense {
+    config: CarrierSenseConfig,
+    freq_0: f32,
+    freq_1: f32,
+    sample_rate: u32,
+    planner: FftPlanner<f32>,
+    state: ChannelState,
+    stats: CarrierStats,
+}
+
+impl CarrierSense {
+    pub fn new(modulation: &ModulationConfig, config: CarrierSenseConfig) -> Self {
+        Self {
+            config,
+            freq_0: modulation.freq_0,
+            freq_1: modulation.freq_1,
+            sample_rate: modulation.sample_rate,
+            planner: FftPlanner::new(),
+            state: ChannelState::Idle,
+            stats: CarrierStats::default(),
+        }
+    }
+
+    /// State found by the most recent check
+    pub fn state(&self) -> ChannelState {
+        self.state
+    }
+
+    pub fn stats(&self) -> CarrierStats {
+        self.stats
+    }
+
+    /// In-band power over the noise floor, in dB, of the loudest block
+    pub fn measure(&mut self, samples: &[f32]) -> f32 {
+        let fft = self.planner.plan_fft_forward(FFT_SIZE);
+        let resolution = self.sample_rate as f32 / FFT_SIZE as f32;
+        let freq_0_bin = (self.freq_0 / resolution) as usize;
+        let freq_1_bin = (self.freq_1 / resolution) as usize;
+
+        let mut loudest = -60.0f32;
+        for block in samples.chunks(FFT_SIZE) {
+            let mut spectrum: Vec<Complex<f32>> =
+                block.iter().map(|&s| Complex::new(s, 0.0)).collect();
+            spectrum.resize(FFT_SIZE, Complex::new(0.0, 0.0));
+            fft.process(&mut spectrum);
+
+            let signal = band_power(&spectrum, freq_0_bin, BAND_HALF_WIDTH)
+                + band_power(&spectrum, freq_1_bin, BAND_HALF_WIDTH);
+            let noise = noise_floor(&spectrum, &[freq_0_bin, freq_1_bin]);
+            if signal > 0.0 && noise > 0.0 {
+                loudest = loudest.max(10.0 * (signal / noise).log10());
+            }
+        }
+        loudest
+    }
+
+    /// Listen until the channel is idle, backing off while it is busy, and
+    /// return the window of audio that was judged idle.
+    ///
+    /// `capture` returns the audio heard since it was last called.
+    pub async fn wait_for_idle(
+        &mut self,
+        mut capture: impl FnMut() -> Vec<f32>,
+    ) -> UshResult<Vec<f32>> {
+        for attempt in 1..=self.config.max_attempts {
+            // Only judge what we hear from now on
+            capture();
+            sleep(self.config.listen_window).await;
+
+            let window = capture();
+            let level_db = self.measure(&window);
+            if level_db < self.config.threshold_db {
+                self.state = ChannelState::Idle;
+                self.stats.clear += 1;
+                debug!("Channel idle ({:.1} dB in band)", level_db);
+                return Ok(window);
+            }
+
+            self.state = ChannelState::Busy;
+            self.stats.busy += 1;
+            if attempt == self.config.max_attempts {
+                break;
+            }
+
+            let exponent = attempt.min(MAX_BACKOFF_EXPONENT);
+            let slots = rand::thread_rng().gen_range(1..=1u32 << exponent);
+            let delay = self.config.slot * slots;
+            info!(
+                "Channel busy ({:.1} dB in band), backing off {:.2}s",
+                level_db,
+                delay.as_secs_f32()
+            );
+            self.stats.backoff_time += delay;
+            sleep(delay).await;
+        }
+
+        self.stats.abandoned += 1;
+        Err(UshError::ChannelBusy {
+            attempts: self.config.max_attempts,
+        })
+    }
+}
+
+/// Peak power within `range` bins of `center_bin`
+pub(crate) fn band_power(spectrum: &[Complex<f32>], center_bin: usize, range: usize) -> f32 {
+    (center_bin.saturating_sub(range)..=(center_bin + range).min(spectrum.len() - 1))
+        .map(|i| spectrum[i].norm_sqr())
+        .fold(0.0f32, f32::max)
+}
+
+/// Mean power over the positive frequencies, skipping DC and `exclude_bins`
+pub(crate) fn noise_floor(spectrum: &[Complex<f32>], exclude_bins: &[usize]) -> f32 {
+    let mut total_power = 0.0;
+    let mut count = 0;
+
+    for (i, sample) in spectrum.iter().enumerate() {
+        if i > 10 && i < spectrum.len() / 2 && !exclude_bins.contains(&i) {
+            total_power += sample.norm_sqr();
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        total_power / count as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::FskModulator;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn noise(len: usize, amplitude: f32) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..len)
+            .map(|_| rng.gen_range(-amplitude..amplitude))
+            .collect()
+    }
+
+    fn fast_config() -> CarrierSenseConfig {
+        CarrierSenseConfig {
+            listen_window: Duration::from_millis(1),
+            slot: Duration::from_millis(1),
+            max_attempts: 3,
+            ..CarrierSenseConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_fsk_signal_is_busy_and_noise_is_idle() {
+        let config = ModulationConfig::default();
+        let mut sense = CarrierSense::new(&config, CarrierSenseConfig::default());
+        let threshold = CarrierSenseConfig::default().threshold_db;
+
+        let mut signal = FskModulator::new(config).encode_bytes(b"someone else");
+        let background = noise(signal.len(), 0.05);
+        for (sample, n) in signal.iter_mut().zip(background) {
+            *sample = *sample * 0.2 + n;
+        }
+
+        assert!(sense.measure(&vec![0.0; 8820]) < threshold);
+        assert!(sense.measure(&noise(8820, 0.3)) < threshold);
+        assert!(sense.measure(&signal[..8820]) > threshold);
+    }
+
+    #[tokio::test]
+    async fn test_backs_off_until_idle() {
+        let config = ModulationConfig::default();
+        let busy = FskModulator::new(config.clone()).encode_bytes(b"busy");
+        let mut sense = CarrierSense::new(&config, fast_config());
+
+        // Each check drains stale audio, then judges the next window
+        let quiet = noise(8820, 0.01);
+        let mut windows =
+            vec![vec![], busy.clone(), vec![], busy, vec![], quiet.clone()].into_iter();
+        let heard = sense
+            .wait_for_idle(|| windows.next().unwrap_or_default())
+            .await
+            .unwrap();
+        assert_eq!(heard, quiet);
+
+        assert_eq!(sense.state(), ChannelState::Idle);
+        assert_eq!(sense.stats().busy, 2);
+        assert_eq!(sense.stats().clear, 1);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_a_jammed_channel() {
+        let config = ModulationConfig::default();
+        let busy = FskModulator::new(config.clone()).encode_bytes(b"jam");
+        let mut sense = CarrierSense::new(&config, fast_config());
+
+        let result = sense.wait_for_idle(|| busy.clone()).await;
+
+        assert!(matches!(result, Err(UshError::ChannelBusy { attempts: 3 })));
+        assert_eq!(sense.state(), ChannelState::Busy);
+        assert_eq!(sense.stats().abandoned, 1);
+    }
+}
@@ -1,4 +1,14 @@
-use clap::{Parser, Subcommand};
+use crate::UshResult;
+use crate::audio_file::MP3_BITRATES;
+use crate::crypto::PresharedKey;
+use crate::fec::{
+    CodeRate, ConvolutionalCode, DEFAULT_CONSTRAINT_LENGTH, MAX_CONSTRAINT_LENGTH,
+    MIN_CONSTRAINT_LENGTH,
+};
+use crate::modulation::{CHANNEL_COUNT, Channel, MAX_PRE_EMPHASIS_DB};
+use crate::protocol::{Address, BROADCAST};
+use crate::tx_level::{AdaptiveLevel, TxLevel};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -34,26 +44,143 @@ pub struct Cli {
         help = "Frequency for bit '1' in Hz (default: 20000)"
     )]
     pub freq_1: Option<f32>,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["freq_0", "freq_1"],
+        value_parser = clap::value_parser!(u8).range(1..=CHANNEL_COUNT as i64),
+        help = "Use a numbered channel of the 17-22 kHz plan (1-5) instead of --freq-0/--freq-1"
+    )]
+    pub channel: Option<u8>,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "key_file",
+        help = "Encrypt and authenticate messages with a shared passphrase"
+    )]
+    pub passphrase: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Encrypt and authenticate messages with a shared key file"
+    )]
+    pub key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Sign outgoing messages with your identity (created on first use)"
+    )]
+    pub sign: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u16).range(..BROADCAST as i64),
+        help = "This device's node id (0-65534), stamped on outgoing frames"
+    )]
+    pub node_id: Option<u16>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only exchange messages with devices in this group (0-65535)"
+    )]
+    pub group: Option<u16>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Transmit without first listening for other senders"
+    )]
+    pub no_carrier_sense: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "RATE",
+        help = "Protect frames with a convolutional code of rate 1/2, 2/3 or 3/4 (both sides must match)"
+    )]
+    pub fec: Option<CodeRate>,
+
+    #[arg(
+        long,
+        global = true,
+        requires = "fec",
+        default_value_t = DEFAULT_CONSTRAINT_LENGTH,
+        value_parser = clap::value_parser!(u8).range(MIN_CONSTRAINT_LENGTH as i64..=MAX_CONSTRAINT_LENGTH as i64),
+        help = "Constraint length of the --fec code (3-9)"
+    )]
+    pub constraint_length: u8,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["tx_margin", "tx_max"],
+        help = "Send at this fixed amplitude (0.0-1.0) instead of adapting to the room noise"
+    )]
+    pub tx_level: Option<f32>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "How far the tones should clear the room noise in dB (default: 40)"
+    )]
+    pub tx_margin: Option<f32>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Loudest amplitude the adaptive level may pick (0.0-1.0, default: 0.3)"
+    )]
+    pub tx_max: Option<f32>,
+
+    #[arg(
+        long,
+        global = true,
+        allow_hyphen_values = true,
+        value_name = "DB",
+        help = "Send freq_1 this much louder than freq_0 (negative: quieter) to make up for speaker roll-off"
+    )]
+    pub pre_emphasis: Option<f32>,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     #[command(about = "Send a text message via ultrasonic audio")]
     Send {
-        #[arg(help = "The message to send")]
+        #[arg(help = "The message to send, or - to send bytes read from stdin")]
         message: String,
 
         #[arg(short, long, help = "Repeat the message N times")]
         repeat: Option<u32>,
 
-        #[arg(long, help = "Save the encoded audio to a WAV file")]
-        save_wav: Option<PathBuf>,
+        #[arg(
+            long,
+            visible_alias = "save-wav",
+            help = "Save the encoded audio to a WAV file, or MP3 if it ends in .mp3"
+        )]
+        save_audio: Option<PathBuf>,
+
+        #[arg(long, help = "MP3 bitrate in kbps for --save-audio (default: 320)")]
+        bitrate: Option<u32>,
 
         #[arg(
             long,
-            help = "Play encoded audio from a WAV file instead of generating"
+            visible_alias = "from-file",
+            help = "Play encoded audio from an audio file instead of generating"
         )]
         from_wav: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u16).range(..BROADCAST as i64),
+            help = "Node id to send to (default: broadcast to everyone)"
+        )]
+        to: Option<u16>,
     },
 
     #[command(about = "Listen for incoming ultrasonic messages")]
@@ -64,8 +191,12 @@ pub enum Commands {
         #[arg(long, help = "Save received audio to a WAV file")]
         save_wav: Option<PathBuf>,
 
-        #[arg(long, help = "Process audio from a WAV file instead of microphone")]
-        from_wav: Option<PathBuf>,
+        #[arg(
+            long,
+            visible_alias = "from-wav",
+            help = "Process a recording (WAV, FLAC, MP3 or OGG) instead of microphone"
+        )]
+        from_file: Option<PathBuf>,
 
         #[arg(long, help = "Apply noise filtering")]
         filter: bool,
@@ -78,6 +209,32 @@ pub enum Commands {
 
         #[arg(long, help = "Output directory for debug analysis files")]
         debug_output: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "Output format for received events")]
+        format: OutputFormat,
+
+        #[arg(
+            long,
+            conflicts_with = "format",
+            help = "Write received payload bytes to stdout"
+        )]
+        raw: bool,
+
+        #[arg(
+            long,
+            help = "Reject frames stamped more than this many seconds from the local clock (default: 120, unchecked with --from-wav)"
+        )]
+        max_skew: Option<u64>,
+
+        #[arg(
+            long,
+            conflicts_with = "max_skew",
+            help = "Accept replayed and stale frames, e.g. when analysing old recordings"
+        )]
+        allow_replay: bool,
+
+        #[arg(long, help = "Show messages addressed to other nodes and groups too")]
+        monitor_all: bool,
     },
 
     #[command(about = "Start interactive chat mode")]
@@ -90,6 +247,38 @@ pub enum Commands {
 
         #[arg(long, help = "Chat session timeout in minutes")]
         timeout: Option<u32>,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "Output format for received events")]
+        format: OutputFormat,
+
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u16).range(..BROADCAST as i64),
+            help = "Node id to send to (default: broadcast to everyone)"
+        )]
+        to: Option<u16>,
+
+        #[arg(long, help = "Show messages addressed to other nodes and groups too")]
+        monitor_all: bool,
+    },
+
+    #[command(about = "Pair with another device by exchanging keys over audio")]
+    Pair {
+        #[arg(
+            long,
+            help = "Start the exchange (run plain `ush pair` on the other device first)"
+        )]
+        initiate: bool,
+
+        #[arg(
+            short,
+            long,
+            help = "Seconds to wait for the other device per attempt (default: 60)"
+        )]
+        timeout: Option<u32>,
+
+        #[arg(long, help = "Delete the stored session key instead of pairing")]
+        forget: bool,
     },
 
     #[command(about = "Send a file via ultrasonic audio")]
@@ -113,6 +302,25 @@ pub enum Commands {
         timeout: Option<u32>,
     },
 
+    #[command(about = "Manage your signing identity and trusted peers")]
+    Identity {
+        #[command(subcommand)]
+        action: IdentityCommands,
+    },
+
+    #[command(about = "Report which channels of the channel plan are in use")]
+    Scan {
+        #[arg(short, long, help = "Seconds to listen (default: 3)")]
+        duration: Option<f32>,
+
+        #[arg(
+            long,
+            visible_alias = "from-file",
+            help = "Scan an audio file instead of the microphone"
+        )]
+        from_wav: Option<PathBuf>,
+    },
+
     #[command(about = "Test audio devices and signal quality")]
     Test {
         #[command(subcommand)]
@@ -127,9 +335,145 @@ pub enum Commands {
         #[arg(long, help = "Show waveform")]
         waveform: bool,
 
-        #[arg(long, help = "Update rate in Hz (default: 10)")]
+        #[arg(long, help = "Refresh rate in Hz (default: 10)")]
         rate: Option<u32>,
     },
+
+    #[command(about = "Write debug reports for recordings without listening")]
+    Analyze {
+        #[arg(
+            required = true,
+            help = "Audio files to analyze (WAV, FLAC, MP3 or OGG)"
+        )]
+        files: Vec<PathBuf>,
+
+        #[arg(
+            short,
+            long,
+            help = "Directory for the reports (default: debug_analysis)"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "Spectrogram window in samples (default: 1024)")]
+        window_size: Option<usize>,
+
+        #[arg(long, help = "Spectrogram hop in samples (default: 256)")]
+        hop_size: Option<usize>,
+
+        #[arg(
+            long,
+            help = "Spectrogram FFT length in samples (default: window size)"
+        )]
+        fft_size: Option<usize>,
+
+        #[arg(long, help = "Also write one HTML report comparing all recordings")]
+        compare: bool,
+    },
+
+    #[command(about = "Hide a message in a music or speech recording under its masking threshold")]
+    Embed {
+        #[arg(
+            long,
+            help = "Recording to hide the message in (WAV, FLAC, MP3 or OGG)"
+        )]
+        carrier: PathBuf,
+
+        #[arg(short, long, help = "The message to hide")]
+        message: String,
+
+        #[arg(
+            short,
+            long,
+            help = "Where to save the result: WAV, or MP3 if it ends in .mp3. Always mono at --sample-rate"
+        )]
+        out: PathBuf,
+
+        #[arg(
+            long,
+            help = "Hop between channels in an order derived from this secret"
+        )]
+        secret: Option<String>,
+
+        #[arg(
+            long,
+            help = "Start time in seconds (default: where the music masks it best)"
+        )]
+        at: Option<f32>,
+
+        #[arg(long, help = "Distance below the masking threshold in dB (default: 6)")]
+        margin: Option<f32>,
+
+        #[arg(
+            long,
+            help = "MP3 bitrate in kbps when --out ends in .mp3 (default: 320)"
+        )]
+        bitrate: Option<u32>,
+    },
+
+    #[command(about = "Recover messages hidden with ush embed")]
+    Extract {
+        #[arg(help = "Recording to search (WAV, FLAC, MP3 or OGG)")]
+        file: PathBuf,
+
+        #[arg(long, help = "The secret the messages were embedded with")]
+        secret: Option<String>,
+    },
+
+    #[command(about = "Trace symbols, bytes and decoder state to find where a decode fails")]
+    Inspect {
+        #[arg(
+            long,
+            visible_alias = "from-file",
+            help = "Inspect an audio file instead of the microphone"
+        )]
+        from_wav: Option<PathBuf>,
+
+        #[arg(short, long, help = "Maximum time to listen in seconds")]
+        timeout: Option<u32>,
+
+        #[arg(long, help = "Signal detection threshold (0.0-1.0, default: 0.1)")]
+        threshold: Option<f32>,
+
+        #[arg(long, help = "Only show bytes, decoder state and failures")]
+        no_symbols: bool,
+    },
+}
+
+/// How received events are written to stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line (JSON Lines)
+    Json,
+    /// Payload bytes only, selected with `listen --raw`
+    #[value(skip)]
+    Raw,
+}
+
+#[derive(Subcommand)]
+pub enum IdentityCommands {
+    #[command(about = "Print your public key, creating an identity if needed")]
+    Show,
+
+    #[command(about = "Trust a peer's public key under a name")]
+    Trust {
+        #[arg(help = "One-word name to show for this peer")]
+        name: String,
+
+        #[arg(help = "The peer's public key, as printed by `ush identity show`")]
+        key: String,
+    },
+
+    #[command(about = "Stop trusting a peer")]
+    Untrust {
+        #[arg(help = "Name of the peer to remove")]
+        name: String,
+    },
+
+    #[command(about = "List trusted peers")]
+    Peers,
 }
 
 #[derive(Subcommand)]
@@ -157,6 +501,26 @@ pub enum TestCommands {
         #[arg(short, long, help = "Measurement duration in seconds")]
         duration: Option<f32>,
     },
+
+    #[command(about = "Measure how the speaker and microphone treat the two tones")]
+    Calibrate {
+        #[arg(short, long, help = "Measurement duration in seconds (default: 2)")]
+        duration: Option<f32>,
+    },
+
+    #[command(about = "Check which MP3 bitrates and channels a frame survives")]
+    Codec {
+        #[arg(help = "Test message")]
+        message: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            help = "Bitrates in kbps to try (default: 96,128,160,192,256,320)"
+        )]
+        bitrates: Vec<u32>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -164,16 +528,26 @@ pub struct AudioSettings {
     pub sample_rate: u32,
     pub freq_0: f32,
     pub freq_1: f32,
+    pub tx_level: TxLevel,
+    /// How much louder `freq_1` is sent than `freq_0`, in dB
+    pub pre_emphasis_db: f32,
     pub verbose: bool,
     pub quiet: bool,
 }
 
 impl AudioSettings {
     pub fn from_cli(cli: &Cli) -> Self {
+        let (freq_0, freq_1) = match cli.channel.and_then(Channel::new) {
+            Some(channel) => channel.frequencies(),
+            None => (cli.freq_0.unwrap_or(18000.0), cli.freq_1.unwrap_or(20000.0)),
+        };
+
         Self {
             sample_rate: cli.sample_rate.unwrap_or(44100),
-            freq_0: cli.freq_0.unwrap_or(18000.0),
-            freq_1: cli.freq_1.unwrap_or(20000.0),
+            freq_0,
+            freq_1,
+            tx_level: cli.tx_level(),
+            pre_emphasis_db: cli.pre_emphasis.unwrap_or(0.0),
             verbose: cli.verbose,
             quiet: cli.quiet,
         }
@@ -184,6 +558,45 @@ impl Cli {
     pub fn get_audio_settings(&self) -> AudioSettings {
         AudioSettings::from_cli(self)
     }
+
+    /// Node id and group selected by `--node-id` and `--group`
+    pub fn address(&self) -> Address {
+        Address {
+            node_id: self.node_id,
+            group: self.group,
+        }
+    }
+
+    /// Transmit level selected by `--tx-level`, `--tx-margin` and `--tx-max`
+    pub fn tx_level(&self) -> TxLevel {
+        if let Some(level) = self.tx_level {
+            return TxLevel::Fixed(level);
+        }
+        let defaults = AdaptiveLevel::default();
+        TxLevel::Adaptive(AdaptiveLevel {
+            margin_db: self.tx_margin.unwrap_or(defaults.margin_db),
+            max_level: self.tx_max.unwrap_or(defaults.max_level),
+            ..defaults
+        })
+    }
+
+    /// Convolutional code selected by `--fec` and `--constraint-length`
+    pub fn fec(&self) -> UshResult<Option<ConvolutionalCode>> {
+        self.fec
+            .map(|rate| Ok(ConvolutionalCode::new(self.constraint_length)?.with_rate(rate)))
+            .transpose()
+    }
+
+    /// The pre-shared key selected by `--passphrase` or `--key-file`, if any
+    pub fn preshared_key(&self) -> UshResult<Option<PresharedKey>> {
+        if let Some(passphrase) = &self.passphrase {
+            return PresharedKey::from_passphrase(passphrase).map(Some);
+        }
+        self.key_file
+            .as_deref()
+            .map(PresharedKey::from_file)
+            .transpose()
+    }
 }
 
 pub fn validate_frequency(freq: f32) -> Result<f32, String> {
@@ -208,6 +621,40 @@ pub fn validate_sample_rate(rate: u32) -> Result<u32, String> {
     }
 }
 
+pub fn validate_tx_level(level: f32) -> Result<f32, String> {
+    if !(level > 0.0 && level <= 1.0) {
+        Err(format!(
+            "Transmit level {} is outside valid range (0.0-1.0)",
+            level
+        ))
+    } else {
+        Ok(level)
+    }
+}
+
+pub fn validate_pre_emphasis(db: f32) -> Result<f32, String> {
+    if !(-MAX_PRE_EMPHASIS_DB..=MAX_PRE_EMPHASIS_DB).contains(&db) {
+        Err(format!(
+            "Pre-emphasis {} dB is outside valid range (-{max} to {max} dB)",
+            db,
+            max = MAX_PRE_EMPHASIS_DB
+        ))
+    } else {
+        Ok(db)
+    }
+}
+
+pub fn validate_bitrate(bitrate: u32) -> Result<u32, String> {
+    if !MP3_BITRATES.contains(&bitrate) {
+        Err(format!(
+            "MP3 bitrate {} kbps is not one of {:?}",
+            bitrate, MP3_BITRATES
+        ))
+    } else {
+        Ok(bitrate)
+    }
+}
+
 pub fn validate_threshold(threshold: f32) -> Result<f32, String> {
     if !(0.0..=1.0).contains(&threshold) {
         Err(format!(
@@ -245,4 +692,48 @@ mod tests {
         assert!(validate_threshold(-0.1).is_err());
         assert!(validate_threshold(1.1).is_err());
     }
+
+    #[test]
+    fn test_bitrate_validation() {
+        assert!(validate_bitrate(320).is_ok());
+        assert!(validate_bitrate(128).is_ok());
+        assert!(validate_bitrate(100).is_err());
+    }
+
+    #[test]
+    fn test_tx_level_validation() {
+        assert!(validate_tx_level(0.3).is_ok());
+        assert!(validate_tx_level(1.0).is_ok());
+        assert!(validate_tx_level(0.0).is_err());
+        assert!(validate_tx_level(1.5).is_err());
+        assert!(validate_tx_level(f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_pre_emphasis_validation() {
+        assert!(validate_pre_emphasis(6.0).is_ok());
+        assert!(validate_pre_emphasis(-6.0).is_ok());
+        assert!(validate_pre_emphasis(25.0).is_err());
+
+        let cli = Cli::parse_from(["ush", "--pre-emphasis", "-4.5", "listen"]);
+        assert_eq!(cli.get_audio_settings().pre_emphasis_db, -4.5);
+    }
+
+    #[test]
+    fn test_tx_level_flags() {
+        let cli = Cli::parse_from(["ush", "send", "hi", "--tx-level", "0.1"]);
+        assert_eq!(cli.tx_level(), TxLevel::Fixed(0.1));
+
+        let cli = Cli::parse_from(["ush", "--tx-margin", "30", "send", "hi"]);
+        let TxLevel::Adaptive(adaptive) = cli.tx_level() else {
+            panic!("expected an adaptive level");
+        };
+        assert_eq!(adaptive.margin_db, 30.0);
+        assert_eq!(adaptive.max_level, AdaptiveLevel::default().max_level);
+
+        assert!(
+            Cli::try_parse_from(["ush", "send", "hi", "--tx-level", "0.1", "--tx-max", "0.2"])
+                .is_err()
+        );
+    }
 }
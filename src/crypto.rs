@@ -0,0 +1,180 @@
+//! Key material for encrypted messages
+//!
+//! Both ends derive the same 256-bit key from a shared passphrase or key file.
+//! The key id lets a receiver tell "encrypted with a different key" apart
+//! from a damaged or forged frame.
+
+use crate::{UshError, UshResult};
+use argon2::Argon2;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Fixed salt so every device derives the same key from the same passphrase
+const PASSPHRASE_SALT: &[u8] = b"ush pre-shared key v1";
+const KEY_ID_CONTEXT: &[u8] = b"ush key id v1";
+const MIN_KEY_FILE_LENGTH: usize = 16;
+
+pub const KEY_LENGTH: usize = 32;
+
+#[derive(Clone)]
+pub struct PresharedKey {
+    key: [u8; KEY_LENGTH],
+    id: u32,
+}
+
+impl PresharedKey {
+    pub fn from_bytes(key: [u8; KEY_LENGTH]) -> Self {
+        let digest = Sha256::new()
+            .chain_update(KEY_ID_CONTEXT)
+            .chain_update(key)
+            .finalize();
+        let id = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+
+        Self { key, id }
+    }
+
+    /// Stretch a passphrase into a key with Argon2id
+    pub fn from_passphrase(passphrase: &str) -> UshResult<Self> {
+        if passphrase.is_empty() {
+            return Err(UshError::Config {
+                message: "Passphrase must not be empty".to_string(),
+            });
+        }
+
+        let mut key = [0u8; KEY_LENGTH];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), PASSPHRASE_SALT, &mut key)
+            .map_err(|e| UshError::Config {
+                message: format!("Failed to derive key from passphrase: {}", e),
+            })?;
+
+        Ok(Self::from_bytes(key))
+    }
+
+    /// Hash the contents of a key file, e.g. one made with
+    /// `head -c 32 /dev/urandom > ush.key`
+    pub fn from_file(path: &Path) -> UshResult<Self> {
+        let contents = std::fs::read(path)?;
+        if contents.len() < MIN_KEY_FILE_LENGTH {
+            return Err(UshError::Config {
+                message: format!(
+                    "Key file {:?} is too short: {} bytes (min: {})",
+                    path,
+                    contents.len(),
+                    MIN_KEY_FILE_LENGTH
+                ),
+            });
+        }
+
+        Ok(Self::from_bytes(Sha256::digest(&contents).into()))
+    }
+
+    /// Short public identifier carried in the header of encrypted frames
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        &self.key
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PresharedKey")
+            .field("id", &format_args!("{:08x}", self.id))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where ush keeps keys: `$XDG_CONFIG_HOME/ush`, falling back to `~/.config/ush`
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_home.join("ush"))
+}
+
+/// Where `ush pair` keeps the session key
+pub fn session_key_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("session.key"))
+}
+
+/// Store a session key, readable only by the current user
+pub fn save_session_key(key: &PresharedKey, path: &Path) -> UshResult<()> {
+    write_private(path, key.as_bytes())
+}
+
+/// Write secret material to a file only the current user can read
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> UshResult<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(path)?, contents)?;
+    Ok(())
+}
+
+/// Load a key stored by [`save_session_key`], if there is one
+pub fn load_session_key(path: &Path) -> UshResult<Option<PresharedKey>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let key: [u8; KEY_LENGTH] = contents.try_into().map_err(|_| UshError::Config {
+        message: format!("Session key file {:?} is corrupt", path),
+    })?;
+    Ok(Some(PresharedKey::from_bytes(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passphrase_derivation_is_deterministic() {
+        let a = PresharedKey::from_passphrase("correct horse").unwrap();
+        let b = PresharedKey::from_passphrase("correct horse").unwrap();
+        let c = PresharedKey::from_passphrase("battery staple").unwrap();
+
+        assert_eq!(a.as_bytes(), b.as_bytes());
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a.id(), c.id());
+        assert!(PresharedKey::from_passphrase("").is_err());
+    }
+
+    #[test]
+    fn test_session_key_roundtrip() {
+        let path = std::env::temp_dir()
+            .join(format!("ush-test-{}", std::process::id()))
+            .join("session.key");
+        assert!(load_session_key(&path).unwrap().is_none());
+
+        let key = PresharedKey::from_bytes([7; KEY_LENGTH]);
+        save_session_key(&key, &path).unwrap();
+        let loaded = load_session_key(&path).unwrap().unwrap();
+
+        assert_eq!(loaded.as_bytes(), key.as_bytes());
+        assert_eq!(loaded.id(), key.id());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_debug_does_not_leak_key() {
+        let key = PresharedKey::from_bytes([0x5a; KEY_LENGTH]);
+        let shown = format!("{:?}", key);
+
+        assert!(shown.contains(&format!("{:08x}", key.id())));
+        assert!(!shown.contains("90, 90"));
+    }
+}
@@ -3,6 +3,13 @@
 //! This module provides comprehensive audio analysis capabilities for the ush system,
 //! generating spectrograms, FFT plots, and detailed signal analysis reports.
 
+use crate::carrier::{band_power, noise_floor};
+use crate::crypto::PresharedKey;
+use crate::fec::ConvolutionalCode;
+use crate::inspector::{InspectEvent, Inspector};
+use crate::modulation::{Channel, MAX_LLR, ModulationConfig};
+use crate::protocol::DecoderState;
+use crate::stream::SymbolTrace;
 use crate::{UshError, UshResult};
 use colorgrad::viridis;
 use image::{ImageBuffer, Rgb, RgbImage};
@@ -23,11 +30,39 @@ pub struct DebugConfig {
     pub freq_0: f32, // FSK frequency for '0'
     pub freq_1: f32, // FSK frequency for '1'
     pub output_dir: PathBuf,
+    /// Spectrogram window length in samples
     pub window_size: usize,
+    /// Samples between the starts of consecutive spectrogram windows
     pub hop_size: usize,
+    /// Spectrogram FFT length; shorter windows are zero-padded up to it
     pub fft_size: usize,
 }
 
+impl DebugConfig {
+    /// Reject window, hop and FFT sizes the spectrogram can't use
+    pub fn validate(&self) -> UshResult<()> {
+        let problem = if self.window_size < 2 {
+            Some(format!(
+                "window size {} is below 2 samples",
+                self.window_size
+            ))
+        } else if self.hop_size == 0 {
+            Some("hop size must be at least 1 sample".to_string())
+        } else if self.fft_size < self.window_size {
+            Some(format!(
+                "FFT size {} is smaller than the {} sample window",
+                self.fft_size, self.window_size
+            ))
+        } else {
+            None
+        };
+        match problem {
+            Some(message) => Err(UshError::Config { message }),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Default for DebugConfig {
     fn default() -> Self {
         Self {
@@ -37,7 +72,7 @@ impl Default for DebugConfig {
             output_dir: PathBuf::from("debug_analysis"),
             window_size: 1024,
             hop_size: 256,
-            fft_size: 512,
+            fft_size: 1024,
         }
     }
 }
@@ -86,9 +121,216 @@ pub struct DebugAnalysis {
     pub config: DebugConfigJson,
     pub signal_metrics: SignalMetrics,
     pub fft_analysis: FftAnalysis,
+    /// Frames the demodulator found in the capture
+    #[serde(default)]
+    pub frames: Vec<FrameReport>,
     pub files_generated: Vec<String>,
 }
 
+/// One frame found in the capture, from preamble to end delimiter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameReport {
+    pub start_seconds: f32,
+    pub end_seconds: f32,
+    /// Whether the frame passed its checksum and decoded
+    pub passed: bool,
+    /// The message text, or why the frame was rejected
+    pub detail: String,
+    pub snr_db: f32,
+    pub symbols: usize,
+    /// Symbol counts by |LLR|, in [`CONFIDENCE_BINS`] equal steps up to the cap
+    pub confidence_histogram: Vec<usize>,
+}
+
+/// Number of bins in [`FrameReport::confidence_histogram`]
+pub const CONFIDENCE_BINS: usize = 8;
+
+/// Detection threshold used to find frames in the capture
+const FRAME_THRESHOLD: f32 = 0.1;
+/// Height in pixels of the annotation strip above the spectrogram
+const STRIP_HEIGHT: u32 = 24;
+
+/// Part of a frame, labelled by the decoder state that read it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameField {
+    Preamble,
+    StartDelimiter,
+    Length,
+    Message,
+    EndDelimiter,
+}
+
+impl FrameField {
+    fn read_in(state: DecoderState) -> Self {
+        match state {
+            DecoderState::WaitingForPreamble => FrameField::Preamble,
+            DecoderState::WaitingForStart => FrameField::StartDelimiter,
+            DecoderState::ReadingLength => FrameField::Length,
+            DecoderState::ReadingMessage => FrameField::Message,
+            DecoderState::WaitingForEnd => FrameField::EndDelimiter,
+        }
+    }
+
+    fn color(self) -> Rgb<u8> {
+        match self {
+            FrameField::Preamble => Rgb([241, 196, 15]),
+            FrameField::StartDelimiter | FrameField::EndDelimiter => Rgb([230, 126, 34]),
+            FrameField::Length => Rgb([52, 152, 219]),
+            FrameField::Message => Rgb([236, 240, 241]),
+        }
+    }
+}
+
+/// A span of samples, `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    start: u64,
+    end: u64,
+}
+
+/// Start of the frame being read, and whether it passed once known
+type OpenFrame = Option<(u64, Option<(bool, String)>)>;
+
+/// What the demodulator and decoder made of a capture
+#[derive(Debug, Default)]
+struct ProtocolAnnotations {
+    symbols: Vec<SymbolTrace>,
+    fields: Vec<(Span, FrameField)>,
+    /// Each frame and whether it passed its checksum
+    frames: Vec<(Span, bool, String)>,
+}
+
+impl ProtocolAnnotations {
+    /// Decode `samples` and label every symbol, frame field and frame
+    fn decode(inspector: &mut Inspector, samples: &[f32]) -> Self {
+        let mut events = inspector.push_samples(samples);
+        events.extend(inspector.flush());
+
+        let mut annotations = Self::default();
+        let mut last_mark = 0;
+        // Start of the frame being read and its outcome once known
+        let mut frame: OpenFrame = None;
+
+        for event in events {
+            match event {
+                InspectEvent::SignalStart { sample_offset } => last_mark = sample_offset,
+                InspectEvent::Symbol(symbol) => annotations.symbols.push(symbol),
+                InspectEvent::Byte(_) => {}
+                InspectEvent::Transition {
+                    sample_offset,
+                    transition,
+                } => {
+                    let span = Span {
+                        start: last_mark,
+                        end: sample_offset,
+                    };
+                    if transition.to == DecoderState::WaitingForStart {
+                        annotations.close_frame(&mut frame, last_mark, "no end delimiter");
+                        frame = Some((last_mark, None));
+                    }
+                    if frame.is_some() && span.end > span.start {
+                        annotations
+                            .fields
+                            .push((span, FrameField::read_in(transition.from)));
+                    }
+                    if transition.failure {
+                        if let Some((_, outcome)) = &mut frame {
+                            outcome.get_or_insert((false, transition.note.clone()));
+                        }
+                        annotations.close_frame(&mut frame, sample_offset, "");
+                    } else if transition.to == DecoderState::WaitingForPreamble {
+                        annotations.close_frame(&mut frame, sample_offset, "");
+                    }
+                    last_mark = sample_offset;
+                }
+                InspectEvent::Frame { message, .. } => {
+                    if let Some((_, outcome)) = &mut frame {
+                        let detail = message
+                            .get_text()
+                            .unwrap_or_else(|_| format!("{} bytes", message.payload.len()));
+                        *outcome = Some((true, detail));
+                    }
+                }
+                InspectEvent::Failure {
+                    sample_offset,
+                    reason,
+                } => {
+                    if let Some((_, outcome)) = &mut frame {
+                        outcome.get_or_insert((false, reason));
+                    }
+                    annotations.close_frame(&mut frame, sample_offset, "");
+                }
+                InspectEvent::SignalEnd { sample_offset, .. } => {
+                    annotations.close_frame(&mut frame, sample_offset, "transmission ended");
+                }
+            }
+        }
+        annotations
+    }
+
+    /// Record the open frame, if any, as ending at `end`; `reason` is the
+    /// failure if its outcome is still unknown
+    fn close_frame(&mut self, frame: &mut OpenFrame, end: u64, reason: &str) {
+        if let Some((start, outcome)) = frame.take() {
+            let (passed, detail) = outcome.unwrap_or((false, reason.to_string()));
+            self.frames.push((Span { start, end }, passed, detail));
+        }
+    }
+
+    /// SNR and confidence histogram of the symbols inside `span`
+    fn frame_report(
+        &self,
+        span: Span,
+        passed: bool,
+        detail: &str,
+        sample_rate: u32,
+    ) -> FrameReport {
+        let symbols: Vec<&SymbolTrace> = self
+            .symbols
+            .iter()
+            .filter(|symbol| (span.start..span.end).contains(&symbol.sample_offset))
+            .collect();
+
+        let (signal, noise) = symbols.iter().filter(|symbol| symbol.is_signal).fold(
+            (0.0f64, 0.0f64),
+            |(signal, noise), symbol| {
+                (
+                    signal + symbol.power_0.max(symbol.power_1) as f64,
+                    noise + symbol.noise_power as f64,
+                )
+            },
+        );
+        let snr_db = if signal > 0.0 && noise > 0.0 {
+            10.0 * (signal / noise).log10() as f32
+        } else {
+            0.0
+        };
+
+        let mut confidence_histogram = vec![0; CONFIDENCE_BINS];
+        for symbol in &symbols {
+            let bin = (symbol.llr.abs() / MAX_LLR * CONFIDENCE_BINS as f32) as usize;
+            confidence_histogram[bin.min(CONFIDENCE_BINS - 1)] += 1;
+        }
+
+        FrameReport {
+            start_seconds: span.start as f32 / sample_rate as f32,
+            end_seconds: span.end as f32 / sample_rate as f32,
+            passed,
+            detail: detail.to_string(),
+            snr_db,
+            symbols: symbols.len(),
+            confidence_histogram,
+        }
+    }
+
+    fn reports(&self, sample_rate: u32) -> Vec<FrameReport> {
+        self.frames
+            .iter()
+            .map(|(span, passed, detail)| self.frame_report(*span, *passed, detail, sample_rate))
+            .collect()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DebugConfigJson {
     pub sample_rate: u32,
@@ -147,6 +389,8 @@ pub struct DebugAnalyzer {
     config: DebugConfig,
     session_id: String,
     fft_planner: FftPlanner<f32>,
+    fec: Option<ConvolutionalCode>,
+    key: Option<PresharedKey>,
 }
 
 impl DebugAnalyzer {
@@ -156,11 +400,19 @@ impl DebugAnalyzer {
             .unwrap()
             .as_secs();
 
-        let session_id = format!("session_{}", timestamp);
+        config.validate()?;
+
+        // Batch runs start several sessions within the same second
+        let mut session_id = format!("session_{}", timestamp);
+        let mut suffix = 1;
+        while config.output_dir.join(&session_id).exists() {
+            suffix += 1;
+            session_id = format!("session_{}_{}", timestamp, suffix);
+        }
 
         // Create output directory
         let session_dir = config.output_dir.join(&session_id);
-        fs::create_dir_all(&session_dir).map_err(|e| UshError::Io(e))?;
+        fs::create_dir_all(&session_dir).map_err(UshError::Io)?;
 
         info!("Debug session started: {}", session_id);
         info!("Output directory: {:?}", session_dir);
@@ -169,9 +421,52 @@ impl DebugAnalyzer {
             config,
             session_id,
             fft_planner: FftPlanner::new(),
+            fec: None,
+            key: None,
         })
     }
 
+    /// Decode frames in the capture with this convolutional code
+    pub fn with_fec(mut self, code: Option<ConvolutionalCode>) -> Self {
+        self.fec = code;
+        self
+    }
+
+    /// Decrypt frames in the capture with this pre-shared key
+    pub fn with_key(mut self, key: Option<PresharedKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    fn modulation_config(&self) -> ModulationConfig {
+        ModulationConfig {
+            sample_rate: self.config.sample_rate,
+            freq_0: self.config.freq_0,
+            freq_1: self.config.freq_1,
+            ..ModulationConfig::default()
+        }
+    }
+
+    /// Run the demodulator and protocol decoder over the capture
+    fn annotate_protocol(&self, samples: &[f32]) -> ProtocolAnnotations {
+        let inspector = Inspector::new(self.modulation_config(), FRAME_THRESHOLD);
+        let inspector = match &self.fec {
+            Some(code) => inspector.with_fec(code.clone()),
+            None => inspector,
+        };
+        let mut inspector = match &self.key {
+            Some(key) => inspector.with_key(key.clone()),
+            None => inspector,
+        };
+        let annotations = ProtocolAnnotations::decode(&mut inspector, samples);
+        info!(
+            "Found {} symbols and {} frames",
+            annotations.symbols.len(),
+            annotations.frames.len()
+        );
+        annotations
+    }
+
     /// Generate complete debug analysis from audio samples
     pub fn analyze_audio(&mut self, samples: &[f32]) -> UshResult<DebugAnalysis> {
         info!("Starting debug analysis of {} samples", samples.len());
@@ -185,10 +480,11 @@ impl DebugAnalyzer {
         // 2. Generate signal metrics
         let signal_metrics = self.calculate_signal_metrics(samples);
 
-        // 3. Generate spectrogram
+        // 3. Decode frames and generate the annotated spectrogram
+        let annotations = self.annotate_protocol(samples);
         let spectrogram = self.generate_spectrogram(samples)?;
         let spectrogram_path = session_dir.join("spectrogram.png");
-        self.save_spectrogram(&spectrogram, &spectrogram_path)?;
+        self.save_spectrogram(&spectrogram, &annotations, &spectrogram_path)?;
 
         // 4. Generate FFT analysis
         let fft_analysis = self.analyze_full_spectrum(samples)?;
@@ -224,6 +520,7 @@ impl DebugAnalyzer {
             },
             signal_metrics,
             fft_analysis,
+            frames: annotations.reports(self.config.sample_rate),
             files_generated: vec![
                 "raw_audio.wav".to_string(),
                 "spectrogram.png".to_string(),
@@ -241,7 +538,7 @@ impl DebugAnalyzer {
             serde_json::to_string_pretty(&analysis).map_err(|e| UshError::Config {
                 message: format!("JSON serialization error: {}", e),
             })?;
-        fs::write(&analysis_path, analysis_json).map_err(|e| UshError::Io(e))?;
+        fs::write(&analysis_path, analysis_json).map_err(UshError::Io)?;
 
         // 9. Generate HTML report
         self.generate_html_report(&analysis, &session_dir)?;
@@ -290,31 +587,39 @@ impl DebugAnalyzer {
         }
     }
 
-    /// Analyze FSK frequency presence
+    /// Analyze FSK frequency presence, averaged over the whole capture
     fn analyze_fsk_presence(&mut self, samples: &[f32]) -> (f32, f32, f32) {
         let fft_size = 4096; // Larger FFT for better frequency resolution
         let fft = self.fft_planner.plan_fft_forward(fft_size);
 
-        let mut fft_input: Vec<Complex<f32>> = samples[..fft_size.min(samples.len())]
-            .iter()
-            .map(|&s| Complex::new(s, 0.0))
-            .collect();
-        fft_input.resize(fft_size, Complex::new(0.0, 0.0));
-
-        fft.process(&mut fft_input);
-
         let freq_resolution = self.config.sample_rate as f32 / fft_size as f32;
 
         // Find bins for FSK frequencies
         let freq_0_bin = (self.config.freq_0 / freq_resolution) as usize;
         let freq_1_bin = (self.config.freq_1 / freq_resolution) as usize;
 
-        // Measure power in frequency bands (±3 bins for robustness)
-        let freq_0_power = self.measure_bin_power(&fft_input, freq_0_bin, 3);
-        let freq_1_power = self.measure_bin_power(&fft_input, freq_1_bin, 3);
+        let mut freq_0_power = 0.0;
+        let mut freq_1_power = 0.0;
+        let mut noise_power = 0.0;
+        let mut blocks = 0;
+        for block in samples.chunks(fft_size) {
+            let mut fft_input: Vec<Complex<f32>> =
+                block.iter().map(|&s| Complex::new(s, 0.0)).collect();
+            fft_input.resize(fft_size, Complex::new(0.0, 0.0));
+            fft.process(&mut fft_input);
 
-        // Estimate noise floor (average power excluding signal bands)
-        let noise_power = self.estimate_noise_floor(&fft_input, &[freq_0_bin, freq_1_bin]);
+            // Measure power in frequency bands (±3 bins for robustness)
+            freq_0_power += band_power(&fft_input, freq_0_bin, 3);
+            freq_1_power += band_power(&fft_input, freq_1_bin, 3);
+            // Estimate noise floor (average power excluding signal bands)
+            noise_power += noise_floor(&fft_input, &[freq_0_bin, freq_1_bin]);
+            blocks += 1;
+        }
+        if blocks > 0 {
+            freq_0_power /= blocks as f32;
+            freq_1_power /= blocks as f32;
+            noise_power /= blocks as f32;
+        }
 
         // Calculate presence indicators (0.0 to 1.0)
         let max_power = freq_0_power.max(freq_1_power);
@@ -345,45 +650,13 @@ impl DebugAnalyzer {
         (freq_0_presence, freq_1_presence, snr_db)
     }
 
-    /// Measure power in a frequency bin with surrounding bins
-    fn measure_bin_power(
-        &self,
-        fft_result: &[Complex<f32>],
-        center_bin: usize,
-        range: usize,
-    ) -> f32 {
-        (center_bin.saturating_sub(range)..=(center_bin + range).min(fft_result.len() - 1))
-            .map(|i| fft_result[i].norm_sqr())
-            .fold(0.0f32, f32::max)
-    }
-
-    /// Estimate noise floor excluding signal bins
-    fn estimate_noise_floor(&self, fft_result: &[Complex<f32>], exclude_bins: &[usize]) -> f32 {
-        let mut total_power = 0.0;
-        let mut count = 0;
-
-        for (i, sample) in fft_result.iter().enumerate() {
-            // Skip DC, high frequencies, and signal bins
-            if i > 10 && i < fft_result.len() / 2 && !exclude_bins.contains(&i) {
-                total_power += sample.norm_sqr();
-                count += 1;
-            }
-        }
-
-        if count > 0 {
-            total_power / count as f32
-        } else {
-            0.0
-        }
-    }
-
     /// Generate spectrogram using STFT
     fn generate_spectrogram(&mut self, samples: &[f32]) -> UshResult<SpectrogramData> {
         info!("Generating spectrogram...");
 
         let window_size = self.config.window_size;
         let hop_size = self.config.hop_size;
-        let fft_size = window_size; // Use same size as window
+        let fft_size = self.config.fft_size;
 
         let fft = self.fft_planner.plan_fft_forward(fft_size);
         let mut magnitude_data = Vec::new();
@@ -411,6 +684,7 @@ impl DebugAnalyzer {
                 .zip(window.iter())
                 .map(|(&s, &w)| Complex::new(s * w, 0.0))
                 .collect();
+            fft_input.resize(fft_size, Complex::new(0.0, 0.0));
 
             fft.process(&mut fft_input);
 
@@ -458,7 +732,12 @@ impl DebugAnalyzer {
     }
 
     /// Save spectrogram as PNG image
-    fn save_spectrogram(&self, spectrogram: &SpectrogramData, path: &Path) -> UshResult<()> {
+    fn save_spectrogram(
+        &self,
+        spectrogram: &SpectrogramData,
+        annotations: &ProtocolAnnotations,
+        path: &Path,
+    ) -> UshResult<()> {
         info!("Saving spectrogram to: {:?}", path);
 
         if spectrogram.magnitude_data.is_empty() {
@@ -468,8 +747,13 @@ impl DebugAnalyzer {
 
         let width = spectrogram.magnitude_data.len();
         let height = spectrogram.magnitude_data[0].len();
+        let strip = if annotations.symbols.is_empty() {
+            0
+        } else {
+            STRIP_HEIGHT as usize
+        };
 
-        let mut img: RgbImage = ImageBuffer::new(width as u32, height as u32);
+        let mut img: RgbImage = ImageBuffer::new(width as u32, (height + strip) as u32);
         let gradient = viridis();
 
         let magnitude_range = spectrogram.max_magnitude - spectrogram.min_magnitude;
@@ -487,7 +771,7 @@ impl DebugAnalyzer {
                 let rgb_color = color.to_rgba8();
 
                 // Flip Y axis (frequency increases upward)
-                let pixel_y = height - 1 - y;
+                let pixel_y = strip + height - 1 - y;
                 img.put_pixel(
                     x as u32,
                     pixel_y as u32,
@@ -496,12 +780,89 @@ impl DebugAnalyzer {
             }
         }
 
+        if strip > 0 {
+            self.draw_annotations(&mut img, spectrogram, annotations);
+        }
+
         img.save(path)
-            .map_err(|e| UshError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+            .map_err(|e| UshError::Io(std::io::Error::other(e)))?;
         info!("Spectrogram saved successfully");
         Ok(())
     }
 
+    /// Mark frames, fields, symbols and bits on a spectrogram image.
+    ///
+    /// The strip above the spectrogram holds, top to bottom: checksum pass
+    /// (green) or fail (red) per frame, the frame fields, the decoded bit of
+    /// each symbol (white 1, black 0) and a tick at each symbol boundary. The
+    /// decoded tone of each symbol is also marked in red on the spectrogram.
+    fn draw_annotations(
+        &self,
+        img: &mut RgbImage,
+        spectrogram: &SpectrogramData,
+        annotations: &ProtocolAnnotations,
+    ) {
+        let width = img.width();
+        let window_center = self.config.window_size as u64 / 2;
+        let hop = self.config.hop_size as u64;
+        let column = |sample_offset: u64| {
+            ((sample_offset.saturating_sub(window_center) / hop) as u32).min(width - 1)
+        };
+        let fill = |img: &mut RgbImage, span: Span, rows: std::ops::Range<u32>, color| {
+            for x in column(span.start)..=column(span.end) {
+                for y in rows.clone() {
+                    img.put_pixel(x, y, color);
+                }
+            }
+        };
+
+        for x in 0..width {
+            for y in 0..STRIP_HEIGHT {
+                img.put_pixel(x, y, Rgb([30, 30, 30]));
+            }
+        }
+        for (span, passed, _) in &annotations.frames {
+            let color = if *passed {
+                Rgb([39, 174, 96])
+            } else {
+                Rgb([231, 76, 60])
+            };
+            fill(img, *span, 0..6, color);
+        }
+        for (span, field) in &annotations.fields {
+            fill(img, *span, 7..13, field.color());
+        }
+
+        let modulation = self.modulation_config();
+        let symbol_samples = (modulation.sample_rate as f32 * modulation.symbol_duration) as u64;
+        let height = img.height() - STRIP_HEIGHT;
+        let tone_row = |freq: f32| {
+            let bin = (freq / spectrogram.freq_resolution).round() as u32;
+            STRIP_HEIGHT + height.saturating_sub(bin + 1)
+        };
+        let (row_0, row_1) = (tone_row(self.config.freq_0), tone_row(self.config.freq_1));
+        for symbol in &annotations.symbols {
+            let span = Span {
+                start: symbol.sample_offset,
+                end: symbol.sample_offset + symbol_samples,
+            };
+            let bit_color = if symbol.bit {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            };
+            if symbol.is_signal {
+                fill(img, span, 14..19, bit_color);
+                let center = column(symbol.sample_offset + symbol_samples / 2);
+                let row = if symbol.bit { row_1 } else { row_0 };
+                if row < img.height() {
+                    img.put_pixel(center, row, Rgb([255, 0, 0]));
+                }
+            }
+            img.put_pixel(column(symbol.sample_offset), 20, Rgb([160, 160, 160]));
+        }
+    }
+
     /// Analyze full frequency spectrum
     fn analyze_full_spectrum(&mut self, samples: &[f32]) -> UshResult<FftAnalysis> {
         let fft_size = 4096; // High resolution for full spectrum
@@ -541,9 +902,9 @@ impl DebugAnalyzer {
         let mut peak_magnitude = 0.0f32;
         let mut peak_frequency = 0.0f32;
 
-        for i in min_bin..max_bin {
+        for (i, bin) in fft_input.iter().enumerate().take(max_bin).skip(min_bin) {
             let freq = i as f32 * freq_resolution;
-            let magnitude = fft_input[i].norm();
+            let magnitude = bin.norm();
 
             frequencies.push(freq);
             magnitudes.push(magnitude);
@@ -559,19 +920,19 @@ impl DebugAnalyzer {
         let freq_1_bin = (self.config.freq_1 / freq_resolution) as usize;
 
         let freq_0_power = if freq_0_bin < fft_size / 2 {
-            self.measure_bin_power(&fft_input, freq_0_bin, 2)
+            band_power(&fft_input, freq_0_bin, 2)
         } else {
             0.0
         };
 
         let freq_1_power = if freq_1_bin < fft_size / 2 {
-            self.measure_bin_power(&fft_input, freq_1_bin, 2)
+            band_power(&fft_input, freq_1_bin, 2)
         } else {
             0.0
         };
 
         // Estimate SNR
-        let noise_power = self.estimate_noise_floor(&fft_input, &[freq_0_bin, freq_1_bin]);
+        let noise_power = noise_floor(&fft_input, &[freq_0_bin, freq_1_bin]);
         let signal_power = freq_0_power + freq_1_power;
         let snr_estimate = if noise_power > 0.0 {
             10.0 * (signal_power / noise_power).log10()
@@ -639,7 +1000,7 @@ impl DebugAnalyzer {
                 message: format!("Series error: {}", e),
             })?
             .label("FFT Magnitude")
-            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 10, y)], &BLUE));
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 10, y)], BLUE));
 
         // Mark FSK frequencies
         chart
@@ -651,7 +1012,7 @@ impl DebugAnalyzer {
             .map_err(|e| UshError::Config {
                 message: format!("Freq0 marker error: {}", e),
             })?
-            .label(&format!("freq_0 ({} Hz)", self.config.freq_0))
+            .label(format!("freq_0 ({} Hz)", self.config.freq_0))
             .legend(|(x, y)| Circle::new((x + 5, y), 3, RED.filled()));
 
         chart
@@ -663,7 +1024,7 @@ impl DebugAnalyzer {
             .map_err(|e| UshError::Config {
                 message: format!("Freq1 marker error: {}", e),
             })?
-            .label(&format!("freq_1 ({} Hz)", self.config.freq_1))
+            .label(format!("freq_1 ({} Hz)", self.config.freq_1))
             .legend(|(x, y)| Circle::new((x + 5, y), 3, GREEN.filled()));
 
         chart
@@ -699,7 +1060,7 @@ impl DebugAnalyzer {
 
         let segments: Vec<_> = samples.chunks(segment_samples).enumerate().collect();
         let rows = ((segments.len() as f32).sqrt().ceil() as usize).max(1);
-        let cols = (segments.len() + rows - 1) / rows;
+        let cols = segments.len().div_ceil(rows);
 
         let areas = root.split_evenly((rows, cols));
 
@@ -809,6 +1170,9 @@ impl DebugAnalyzer {
         table {{ width: 100%; border-collapse: collapse; margin: 10px 0; }}
         th, td {{ border: 1px solid #ddd; padding: 8px; text-align: left; }}
         th {{ background: #f8f9fa; }}
+        .swatch {{ display: inline-block; width: 12px; height: 12px; border: 1px solid #999; vertical-align: middle; }}
+        .histogram {{ display: flex; align-items: flex-end; gap: 2px; height: 40px; }}
+        .histogram div {{ width: 10px; background: #3498db; }}
     </style>
 </head>
 <body>
@@ -877,6 +1241,12 @@ impl DebugAnalyzer {
                 <h3>Spectrogram (Time-Frequency Analysis)</h3>
                 <img src="spectrogram.png" alt="Spectrogram showing frequency content over time">
                 <p>Shows frequency content over time. FSK signals should appear as horizontal lines at {} Hz and {} Hz.</p>
+                <p>The strip above shows, top to bottom: each frame's checksum (<span class="good">pass</span> / <span class="bad">fail</span>), the frame fields
+                    (<span class="swatch" style="background:#f1c40f"></span> preamble,
+                    <span class="swatch" style="background:#e67e22"></span> delimiters,
+                    <span class="swatch" style="background:#3498db"></span> length,
+                    <span class="swatch" style="background:#ecf0f1"></span> message),
+                    the decoded bits (white 1, black 0) and symbol boundaries. Red dots mark the decoded tone of each symbol.</p>
             </div>
 
             <div class="visualization">
@@ -898,6 +1268,8 @@ impl DebugAnalyzer {
             </div>
         </div>
 
+        {}
+
         <div class="section">
             <h2>📁 Generated Files</h2>
             <ul class="file-list">
@@ -978,6 +1350,7 @@ impl DebugAnalyzer {
             analysis.fft_analysis.peak_magnitude,
             analysis.config.freq_0,
             analysis.config.freq_1,
+            frames_section(&analysis.frames),
             analysis
                 .files_generated
                 .iter()
@@ -993,7 +1366,7 @@ impl DebugAnalyzer {
         );
 
         let html_path = output_dir.join("debug_report.html");
-        fs::write(&html_path, html_content).map_err(|e| UshError::Io(e))?;
+        fs::write(&html_path, html_content).map_err(UshError::Io)?;
         info!("HTML report saved to: {:?}", html_path);
         Ok(())
     }
@@ -1009,20 +1382,413 @@ impl DebugAnalyzer {
             sample_format: hound::SampleFormat::Float,
         };
 
-        let mut writer = WavWriter::create(path, spec)
-            .map_err(|e| UshError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        let mut writer =
+            WavWriter::create(path, spec).map_err(|e| UshError::Io(std::io::Error::other(e)))?;
 
         for &sample in samples {
             writer
                 .write_sample(sample)
-                .map_err(|e| UshError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+                .map_err(|e| UshError::Io(std::io::Error::other(e)))?;
         }
 
         writer
             .finalize()
-            .map_err(|e| UshError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+            .map_err(|e| UshError::Io(std::io::Error::other(e)))?;
 
         debug!("WAV file saved: {:?}", path);
         Ok(())
     }
 }
+
+/// Formats one metric of a capture for the comparison table
+type MetricCell = fn(&DebugAnalysis) -> String;
+
+/// Write one HTML page putting several analyzed captures side by side.
+///
+/// Each capture is a label (usually its file name) and the analysis of a
+/// session written to `output_dir`. Returns the path of the page.
+pub fn write_comparison_report(
+    output_dir: &Path,
+    captures: &[(String, DebugAnalysis)],
+) -> UshResult<PathBuf> {
+    let metric_rows: [(&str, MetricCell); 8] = [
+        ("Duration", |a| {
+            format!("{:.2} s", a.signal_metrics.duration_seconds)
+        }),
+        ("RMS level", |a| {
+            format!("{:.4}", a.signal_metrics.rms_level)
+        }),
+        ("Peak level", |a| {
+            format!("{:.4}", a.signal_metrics.peak_level)
+        }),
+        ("Estimated SNR", |a| {
+            format!("{:.1} dB", a.signal_metrics.estimated_snr)
+        }),
+        ("freq_0 presence", |a| {
+            format!("{:.1}%", a.signal_metrics.freq_0_presence * 100.0)
+        }),
+        ("freq_1 presence", |a| {
+            format!("{:.1}%", a.signal_metrics.freq_1_presence * 100.0)
+        }),
+        ("Frames passed", |a| {
+            let passed = a.frames.iter().filter(|frame| frame.passed).count();
+            format!("{} of {}", passed, a.frames.len())
+        }),
+        ("Mean frame SNR", |a| {
+            if a.frames.is_empty() {
+                "-".to_string()
+            } else {
+                let total: f32 = a.frames.iter().map(|frame| frame.snr_db).sum();
+                format!("{:.1} dB", total / a.frames.len() as f32)
+            }
+        }),
+    ];
+
+    let header: String = captures
+        .iter()
+        .map(|(label, analysis)| {
+            format!(
+                r#"<th><a href="{}/debug_report.html">{}</a></th>"#,
+                analysis.session_id,
+                html_escape(label)
+            )
+        })
+        .collect();
+    let rows: Vec<String> = metric_rows
+        .iter()
+        .map(|(name, metric)| {
+            let cells: String = captures
+                .iter()
+                .map(|(_, analysis)| format!("<td>{}</td>", metric(analysis)))
+                .collect();
+            format!("<tr><th>{}</th>{}</tr>", name, cells)
+        })
+        .collect();
+    let spectrograms: String = captures
+        .iter()
+        .map(|(label, analysis)| {
+            format!(
+                r#"<figure><img src="{}/spectrogram.png" alt="Spectrogram of {}"><figcaption>{}</figcaption></figure>"#,
+                analysis.session_id,
+                html_escape(label),
+                html_escape(label)
+            )
+        })
+        .collect();
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>USH Capture Comparison</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 20px; background: #f5f5f5; }}
+        .container {{ background: white; padding: 20px; border-radius: 8px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }}
+        table {{ border-collapse: collapse; margin: 10px 0; }}
+        th, td {{ border: 1px solid #ddd; padding: 8px; text-align: left; }}
+        th {{ background: #f8f9fa; }}
+        .side-by-side {{ display: grid; grid-template-columns: repeat({}, 1fr); gap: 10px; }}
+        .side-by-side img {{ width: 100%; height: 300px; object-fit: fill; border: 1px solid #ddd; }}
+        figcaption {{ text-align: center; }}
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>🔊 USH Capture Comparison</h1>
+        <table>
+            <tr><th>Capture</th>{}</tr>
+            {}
+        </table>
+        <h2>Spectrograms</h2>
+        <div class="side-by-side">
+            {}
+        </div>
+    </div>
+</body>
+</html>
+"#,
+        captures.len().max(1),
+        header,
+        rows.join("\n            "),
+        spectrograms
+    );
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let path = output_dir.join(format!("comparison_{}.html", timestamp));
+    fs::write(&path, html).map_err(UshError::Io)?;
+    info!("Comparison report saved to: {:?}", path);
+    Ok(path)
+}
+
+/// The report section listing every frame with its SNR and bit confidence
+fn frames_section(frames: &[FrameReport]) -> String {
+    let rows: Vec<String> = frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let most = frame.confidence_histogram.iter().copied().max().unwrap_or(0).max(1);
+            let bars: String = frame
+                .confidence_histogram
+                .iter()
+                .map(|&count| {
+                    format!(
+                        r#"<div style="height:{}%" title="{} symbols"></div>"#,
+                        count * 100 / most,
+                        count
+                    )
+                })
+                .collect();
+            format!(
+                r#"<tr><td>{}</td><td>{:.3}-{:.3} s</td><td class="{}">{}</td><td>{}</td><td>{:.1} dB</td><td>{}</td><td><div class="histogram">{}</div></td></tr>"#,
+                i + 1,
+                frame.start_seconds,
+                frame.end_seconds,
+                if frame.passed { "good" } else { "bad" },
+                if frame.passed { "pass" } else { "fail" },
+                html_escape(&frame.detail),
+                frame.snr_db,
+                frame.symbols,
+                bars
+            )
+        })
+        .collect();
+
+    let body = if rows.is_empty() {
+        "<p>No frames were found in the capture.</p>".to_string()
+    } else {
+        format!(
+            r#"<table>
+                <tr><th>#</th><th>Time</th><th>Checksum</th><th>Content</th><th>SNR</th><th>Symbols</th><th>Bit confidence (|LLR| 0-{:.0})</th></tr>
+                {}
+            </table>"#,
+            MAX_LLR,
+            rows.join("\n                ")
+        )
+    };
+    format!(
+        r#"<div class="section">
+            <h2>📡 Frames</h2>
+            {}
+        </div>"#,
+        body
+    )
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// FFT size for channel scans: about 46 ms at 44.1 kHz
+const SCAN_FFT_SIZE: usize = 2048;
+/// Bins either side of each tone counted as in-band when scanning
+const SCAN_BAND_HALF_WIDTH: usize = 3;
+
+/// How much one channel of the channel plan was used during a scan
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelActivity {
+    pub channel: u8,
+    pub freq_0: f32,
+    pub freq_1: f32,
+    /// Strongest tone power over the noise floor in any block, in dB
+    pub peak_snr_db: f32,
+    /// Fraction of blocks in which the channel was above the threshold
+    pub busy_fraction: f32,
+}
+
+impl ChannelActivity {
+    pub fn is_busy(&self) -> bool {
+        self.busy_fraction > 0.0
+    }
+}
+
+/// Measure every channel's tone power against the noise floor, block by block
+///
+/// A channel counts as busy in a block when its tones stand more than
+/// `threshold_db` above the mean power of the rest of the spectrum.
+pub fn scan_channels(samples: &[f32], sample_rate: u32, threshold_db: f32) -> Vec<ChannelActivity> {
+    let nyquist = sample_rate as f32 / 2.0;
+    let resolution = sample_rate as f32 / SCAN_FFT_SIZE as f32;
+    let channels: Vec<(Channel, usize, usize)> = Channel::all()
+        .filter(|channel| channel.frequencies().1 < nyquist)
+        .map(|channel| {
+            let (freq_0, freq_1) = channel.frequencies();
+            (
+                channel,
+                (freq_0 / resolution) as usize,
+                (freq_1 / resolution) as usize,
+            )
+        })
+        .collect();
+    let tone_bins: Vec<usize> = channels
+        .iter()
+        .flat_map(|&(_, bin_0, bin_1)| [bin_0, bin_1])
+        .collect();
+
+    let fft = FftPlanner::new().plan_fft_forward(SCAN_FFT_SIZE);
+    let mut peak_snr = vec![-60.0f32; channels.len()];
+    let mut busy_blocks = vec![0usize; channels.len()];
+    let mut blocks = 0;
+
+    for block in samples.chunks(SCAN_FFT_SIZE) {
+        let mut spectrum: Vec<Complex<f32>> = block.iter().map(|&s| Complex::new(s, 0.0)).collect();
+        spectrum.resize(SCAN_FFT_SIZE, Complex::new(0.0, 0.0));
+        fft.process(&mut spectrum);
+        blocks += 1;
+
+        let noise = noise_floor(&spectrum, &tone_bins);
+        for (i, &(_, bin_0, bin_1)) in channels.iter().enumerate() {
+            let signal = band_power(&spectrum, bin_0, SCAN_BAND_HALF_WIDTH)
+                + band_power(&spectrum, bin_1, SCAN_BAND_HALF_WIDTH);
+            if signal <= 0.0 || noise <= 0.0 {
+                continue;
+            }
+
+            let snr_db = 10.0 * (signal / noise).log10();
+            peak_snr[i] = peak_snr[i].max(snr_db);
+            if snr_db > threshold_db {
+                busy_blocks[i] += 1;
+            }
+        }
+    }
+
+    channels
+        .iter()
+        .enumerate()
+        .map(|(i, &(channel, _, _))| {
+            let (freq_0, freq_1) = channel.frequencies();
+            ChannelActivity {
+                channel: channel.number(),
+                freq_0,
+                freq_1,
+                peak_snr_db: peak_snr[i],
+                busy_fraction: if blocks > 0 {
+                    busy_blocks[i] as f32 / blocks as f32
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::{FskModulator, ModulationConfig};
+    use crate::protocol::ProtocolEncoder;
+
+    fn annotate(frame: &[u8]) -> ProtocolAnnotations {
+        let config = ModulationConfig::default();
+        let mut samples = vec![0.0; 2000];
+        samples.extend(FskModulator::new(config.clone()).encode_bytes(frame));
+        samples.extend(vec![0.0; 4410]);
+        ProtocolAnnotations::decode(&mut Inspector::new(config, FRAME_THRESHOLD), &samples)
+    }
+
+    #[test]
+    fn test_sessions_get_separate_directories() {
+        let output_dir = std::env::temp_dir().join(format!("ush-debug-{}", std::process::id()));
+        let config = DebugConfig {
+            output_dir: output_dir.clone(),
+            ..DebugConfig::default()
+        };
+
+        let first = DebugAnalyzer::new(config.clone()).unwrap();
+        let second = DebugAnalyzer::new(config).unwrap();
+        assert_ne!(first.session_id, second.session_id);
+
+        fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_rejects_unusable_sizes() {
+        assert!(DebugConfig::default().validate().is_ok());
+        for (window_size, hop_size, fft_size) in [(1, 256, 1024), (1024, 0, 1024), (1024, 256, 512)]
+        {
+            let config = DebugConfig {
+                window_size,
+                hop_size,
+                fft_size,
+                ..DebugConfig::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_annotations_label_a_clean_frame() {
+        let frame = ProtocolEncoder::new().encode_text("annotate me").unwrap();
+        let annotations = annotate(&frame);
+
+        let fields: Vec<FrameField> = annotations.fields.iter().map(|(_, field)| *field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                FrameField::Preamble,
+                FrameField::StartDelimiter,
+                FrameField::Length,
+                FrameField::Message,
+                FrameField::EndDelimiter,
+            ]
+        );
+
+        let reports = annotations.reports(44100);
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert!(report.passed);
+        assert_eq!(report.detail, "annotate me");
+        assert!(report.snr_db > 20.0, "SNR {}", report.snr_db);
+        assert_eq!(report.symbols, frame.len() * 8);
+        assert_eq!(
+            report.confidence_histogram.iter().sum::<usize>(),
+            report.symbols
+        );
+        // A clean signal is decided with full confidence
+        assert_eq!(
+            report.confidence_histogram[CONFIDENCE_BINS - 1],
+            report.symbols
+        );
+    }
+
+    #[test]
+    fn test_annotations_mark_a_corrupted_frame() {
+        let mut frame = ProtocolEncoder::new().encode_text("annotate me").unwrap();
+        // Corrupt a byte of the serialized message
+        let middle = frame.len() / 2;
+        frame[middle] ^= 0x01;
+        let annotations = annotate(&frame);
+
+        let reports = annotations.reports(44100);
+        assert_eq!(reports.len(), 1);
+        assert!(!reports[0].passed);
+        assert!(!reports[0].detail.is_empty());
+    }
+
+    #[test]
+    fn test_scan_finds_the_busy_channel() {
+        let (freq_0, freq_1) = Channel::new(4).unwrap().frequencies();
+        let config = ModulationConfig {
+            freq_0,
+            freq_1,
+            ..ModulationConfig::default()
+        };
+        let samples = FskModulator::new(config).encode_bytes(b"who is on channel 4?");
+
+        let activity = scan_channels(&samples, 44100, 15.0);
+
+        assert_eq!(activity.len(), 5);
+        let busy: Vec<u8> = activity
+            .iter()
+            .filter(|channel| channel.is_busy())
+            .map(|channel| channel.channel)
+            .collect();
+        assert_eq!(busy, vec![4]);
+    }
+}
@@ -20,6 +20,9 @@ pub enum UshError {
     #[error("WAV error: {0}")]
     Wav(#[from] hound::Error),
 
+    #[error("Audio file error: {0}")]
+    AudioFile(#[from] symphonia::core::errors::Error),
+
     #[error("Protocol error: {message}")]
     Protocol { message: String },
 
@@ -29,9 +32,18 @@ pub enum UshError {
     #[error("Encoding error: {message}")]
     Encoding { message: String },
 
+    #[error("Authentication failed: {message}")]
+    Authentication { message: String },
+
+    #[error("Replay rejected: {message}")]
+    Replay { message: String },
+
     #[error("CRC mismatch: expected {expected}, got {actual}")]
     CrcMismatch { expected: u32, actual: u32 },
 
+    #[error("Channel busy: gave up after {attempts} attempts")]
+    ChannelBusy { attempts: u32 },
+
     #[error("Timeout waiting for signal")]
     Timeout,
 
@@ -0,0 +1,379 @@
+//! Convolutional forward error correction with soft-decision Viterbi decoding
+//!
+//! The code sits between protocol framing and the FSK modulator: the frame
+//! bytes of a transmission are encoded as one block, terminated with K-1 zero
+//! tail bits, and the receiver decodes the soft bits of the whole transmission
+//! once the signal ends. Rates 2/3 and 3/4 are obtained by puncturing the
+//! rate-1/2 mother code, dropping coded bits the decoder treats as erasures.
+
+use crate::modulation::SoftBit;
+use crate::{UshError, UshResult};
+use log::debug;
+use std::fmt;
+use std::str::FromStr;
+
+pub const MIN_CONSTRAINT_LENGTH: u8 = 3;
+pub const MAX_CONSTRAINT_LENGTH: u8 = 9;
+pub const DEFAULT_CONSTRAINT_LENGTH: u8 = 7;
+
+/// Best known rate-1/2 generator polynomials (octal) for K = 3..=9
+const GENERATORS: [(u32, u32); 7] = [
+    (0o7, 0o5),
+    (0o17, 0o15),
+    (0o23, 0o35),
+    (0o53, 0o75),
+    (0o171, 0o133),
+    (0o371, 0o247),
+    (0o561, 0o753),
+];
+
+/// Code rate, selected by puncturing the rate-1/2 mother code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeRate {
+    #[default]
+    Half,
+    TwoThirds,
+    ThreeQuarters,
+}
+
+impl CodeRate {
+    /// Which outputs of the two generators are sent at each step of the period
+    fn puncture_pattern(&self) -> &'static [[bool; 2]] {
+        match self {
+            CodeRate::Half => &[[true, true]],
+            CodeRate::TwoThirds => &[[true, true], [false, true]],
+            CodeRate::ThreeQuarters => &[[true, true], [false, true], [true, false]],
+        }
+    }
+
+    /// Information bits per transmitted bit
+    pub fn ratio(&self) -> f32 {
+        match self {
+            CodeRate::Half => 1.0 / 2.0,
+            CodeRate::TwoThirds => 2.0 / 3.0,
+            CodeRate::ThreeQuarters => 3.0 / 4.0,
+        }
+    }
+}
+
+impl fmt::Display for CodeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodeRate::Half => write!(f, "1/2"),
+            CodeRate::TwoThirds => write!(f, "2/3"),
+            CodeRate::ThreeQuarters => write!(f, "3/4"),
+        }
+    }
+}
+
+impl FromStr for CodeRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1/2" => Ok(CodeRate::Half),
+            "2/3" => Ok(CodeRate::TwoThirds),
+            "3/4" => Ok(CodeRate::ThreeQuarters),
+            _ => Err(format!(
+                "unsupported code rate {s:?}, expected 1/2, 2/3 or 3/4"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvolutionalCode {
+    constraint_length: u8,
+    generators: (u32, u32),
+    rate: CodeRate,
+}
+
+impl ConvolutionalCode {
+    /// Rate-1/2 code with the standard generators for `constraint_length`
+    pub fn new(constraint_length: u8) -> UshResult<Self> {
+        if !(MIN_CONSTRAINT_LENGTH..=MAX_CONSTRAINT_LENGTH).contains(&constraint_length) {
+            return Err(UshError::Config {
+                message: format!(
+                    "Constraint length {} is outside {}..={}",
+                    constraint_length, MIN_CONSTRAINT_LENGTH, MAX_CONSTRAINT_LENGTH
+                ),
+            });
+        }
+
+        Ok(Self {
+            constraint_length,
+            generators: GENERATORS[(constraint_length - MIN_CONSTRAINT_LENGTH) as usize],
+            rate: CodeRate::Half,
+        })
+    }
+
+    pub fn with_rate(mut self, rate: CodeRate) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn constraint_length(&self) -> u8 {
+        self.constraint_length
+    }
+
+    pub fn rate(&self) -> CodeRate {
+        self.rate
+    }
+
+    fn memory(&self) -> usize {
+        self.constraint_length as usize - 1
+    }
+
+    /// Outputs of both generators for `input` entering a register holding `state`
+    fn branch(&self, state: usize, input: bool) -> (usize, [bool; 2]) {
+        let register = ((input as u32) << self.memory()) | state as u32;
+        let parity = |generator: u32| (register & generator).count_ones() % 2 == 1;
+        (
+            (register >> 1) as usize,
+            [parity(self.generators.0), parity(self.generators.1)],
+        )
+    }
+
+    /// Encode `bits` followed by the zero tail that returns the encoder to state 0
+    pub fn encode(&self, bits: &[bool]) -> Vec<bool> {
+        let pattern = self.rate.puncture_pattern();
+        let tail = std::iter::repeat_n(false, self.memory());
+        let mut state = 0;
+        let mut coded = Vec::with_capacity((bits.len() + self.memory()) * 2);
+
+        for (step, input) in bits.iter().copied().chain(tail).enumerate() {
+            let (next, outputs) = self.branch(state, input);
+            let keep = pattern[step % pattern.len()];
+            coded.extend((0..2).filter(|&i| keep[i]).map(|i| outputs[i]));
+            state = next;
+        }
+
+        coded
+    }
+
+    /// Encode bytes MSB first, as [`FskModulator::encode_bytes`](crate::modulation::FskModulator::encode_bytes) would send them
+    pub fn encode_bytes(&self, data: &[u8]) -> Vec<bool> {
+        let bits: Vec<bool> = data
+            .iter()
+            .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .collect();
+        self.encode(&bits)
+    }
+
+    /// Viterbi-decode received LLRs (positive favours 1) back to information bits.
+    ///
+    /// A trailing partial step or a stray extra symbol doesn't derail the
+    /// decoder: it ends in the best surviving state rather than forcing state 0.
+    pub fn decode(&self, llrs: &[f32]) -> Vec<bool> {
+        // Re-insert punctured positions as zero-confidence erasures
+        let pattern = self.rate.puncture_pattern();
+        let mut received = llrs.iter().copied();
+        let mut steps: Vec<[f32; 2]> = Vec::new();
+        loop {
+            let keep = pattern[steps.len() % pattern.len()];
+            let mut pair = [0.0; 2];
+            let mut taken = 0;
+            for i in (0..2).filter(|&i| keep[i]) {
+                if let Some(llr) = received.next() {
+                    pair[i] = llr;
+                    taken += 1;
+                }
+            }
+            if taken == 0 {
+                break;
+            }
+            steps.push(pair);
+        }
+
+        let states = 1usize << self.memory();
+        let mut metrics = vec![f32::NEG_INFINITY; states];
+        metrics[0] = 0.0;
+        // Surviving predecessor of every state at every step
+        let mut history: Vec<Vec<u16>> = Vec::with_capacity(steps.len());
+
+        for pair in &steps {
+            let mut next_metrics = vec![f32::NEG_INFINITY; states];
+            let mut survivors = vec![0u16; states];
+
+            for (state, &metric) in metrics.iter().enumerate() {
+                if metric == f32::NEG_INFINITY {
+                    continue;
+                }
+                for input in [false, true] {
+                    let (next, outputs) = self.branch(state, input);
+                    let correlation: f32 = outputs
+                        .iter()
+                        .zip(pair)
+                        .map(|(&bit, &llr)| if bit { llr } else { -llr })
+                        .sum();
+                    if metric + correlation > next_metrics[next] {
+                        next_metrics[next] = metric + correlation;
+                        survivors[next] = state as u16;
+                    }
+                }
+            }
+
+            metrics = next_metrics;
+            history.push(survivors);
+        }
+
+        // Trace back from the best end state; the input bit that led into a
+        // state is its most significant register bit
+        let mut state = (0..states)
+            .max_by(|&a, &b| metrics[a].total_cmp(&metrics[b]))
+            .unwrap_or(0);
+        let mut bits = Vec::with_capacity(history.len());
+        for survivors in history.iter().rev() {
+            bits.push(state >> (self.memory() - 1) & 1 == 1);
+            state = survivors[state] as usize;
+        }
+        bits.reverse();
+        bits.truncate(bits.len().saturating_sub(self.memory()));
+
+        debug!(
+            "Viterbi decoded {} coded bits into {} bits",
+            llrs.len(),
+            bits.len()
+        );
+        bits
+    }
+
+    /// Decode soft bits from the demodulator into whole bytes, MSB first
+    pub fn decode_soft_bytes(&self, soft_bits: &[SoftBit]) -> Vec<u8> {
+        let llrs: Vec<f32> = soft_bits.iter().map(SoftBit::llr).collect();
+        self.decode(&llrs)
+            .chunks_exact(8)
+            .map(|chunk| chunk.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::{FskDemodulator, FskModulator, ModulationConfig};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_bits(rng: &mut StdRng, len: usize) -> Vec<bool> {
+        (0..len).map(|_| rng.r#gen()).collect()
+    }
+
+    /// Standard normal sample by the Box-Muller transform
+    fn gaussian(rng: &mut StdRng) -> f32 {
+        let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+        let u2: f32 = rng.r#gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+
+    /// Send bits through the FSK modem over white noise and return soft bits
+    fn channel(bits: &[bool], noise: f32, rng: &mut StdRng) -> Vec<SoftBit> {
+        let config = ModulationConfig::default();
+        let samples: Vec<f32> = FskModulator::new(config.clone())
+            .encode_bits(bits)
+            .into_iter()
+            .map(|sample| sample + noise * gaussian(rng))
+            .collect();
+        FskDemodulator::new(config)
+            .soft_decode_samples(&samples)
+            .unwrap()
+    }
+
+    fn errors(sent: &[bool], received: &[bool]) -> usize {
+        sent.iter().zip(received).filter(|(a, b)| a != b).count()
+            + sent.len().abs_diff(received.len())
+    }
+
+    #[test]
+    fn test_rate_and_length() {
+        let bits = vec![true; 12];
+        for (rate, expected) in [
+            (CodeRate::Half, 36),
+            (CodeRate::TwoThirds, 27),
+            (CodeRate::ThreeQuarters, 24),
+        ] {
+            let code = ConvolutionalCode::new(7).unwrap().with_rate(rate);
+            assert_eq!(code.encode(&bits).len(), expected, "rate {}", rate);
+        }
+        assert!(ConvolutionalCode::new(2).is_err());
+        assert!(ConvolutionalCode::new(10).is_err());
+    }
+
+    #[test]
+    fn test_noiseless_roundtrip_for_every_rate_and_length() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let bits = random_bits(&mut rng, 203);
+
+        for constraint_length in MIN_CONSTRAINT_LENGTH..=MAX_CONSTRAINT_LENGTH {
+            for rate in [CodeRate::Half, CodeRate::TwoThirds, CodeRate::ThreeQuarters] {
+                let code = ConvolutionalCode::new(constraint_length)
+                    .unwrap()
+                    .with_rate(rate);
+                let llrs: Vec<f32> = code
+                    .encode(&bits)
+                    .iter()
+                    .map(|&bit| if bit { 1.0 } else { -1.0 })
+                    .collect();
+                assert_eq!(
+                    code.decode(&llrs),
+                    bits,
+                    "K={} rate {}",
+                    constraint_length,
+                    rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrects_flipped_and_erased_bits() {
+        let code = ConvolutionalCode::new(7).unwrap();
+        let data = b"ultrasonic";
+        let mut soft: Vec<SoftBit> = code
+            .encode_bytes(data)
+            .iter()
+            .map(|&bit| SoftBit::Llr(if bit { 8.0 } else { -8.0 }))
+            .collect();
+
+        // Sparse hard errors and a short dropout
+        for i in (5..soft.len()).step_by(23) {
+            soft[i] = SoftBit::Llr(-soft[i].llr());
+        }
+        soft[60..63].fill(SoftBit::Erasure);
+
+        assert_eq!(code.decode_soft_bytes(&soft), data);
+    }
+
+    #[test]
+    fn test_coding_gain_over_uncoded_fsk() {
+        // Same modem, same noise per symbol: the coded link spends two
+        // symbols per bit but should still make far fewer bit errors
+        let noise = 1.4;
+        let mut rng = StdRng::seed_from_u64(42);
+        let bits = random_bits(&mut rng, 2000);
+
+        let uncoded: Vec<bool> = channel(&bits, noise, &mut rng)
+            .iter()
+            .map(|bit| bit.hard().unwrap_or(false))
+            .collect();
+        let uncoded_errors = errors(&bits, &uncoded);
+
+        let code = ConvolutionalCode::new(7).unwrap();
+        let received = channel(&code.encode(&bits), noise, &mut rng);
+        let llrs: Vec<f32> = received.iter().map(SoftBit::llr).collect();
+        let coded_errors = errors(&bits, &code.decode(&llrs));
+
+        let ber = |errors: usize| errors as f32 / bits.len() as f32;
+        assert!(
+            ber(uncoded_errors) > 0.01,
+            "uncoded BER {} too low to compare",
+            ber(uncoded_errors)
+        );
+        assert!(
+            coded_errors * 10 < uncoded_errors,
+            "coded BER {} vs uncoded {}",
+            ber(coded_errors),
+            ber(uncoded_errors)
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Sender identity: Ed25519 signing keys and a known-peers trust store
+//!
+//! A signed message carries the sender's public key in its header and a
+//! signature over the header and plaintext payload. The receiver verifies the
+//! signature and looks the key up in its trust store to put a name to it.
+
+use crate::crypto::{config_dir, write_private};
+use crate::protocol::{Message, MessageHeader};
+use crate::{UshError, UshResult};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const SIGNATURE_CONTEXT: &[u8] = b"ush signature v1";
+
+/// Where the local signing key is kept
+pub fn identity_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("identity.key"))
+}
+
+/// Where the trust store is kept
+pub fn known_peers_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("known_peers"))
+}
+
+/// The local Ed25519 keypair
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Load the identity at `path`, creating and saving a new one if missing
+    pub fn load_or_create(path: &Path) -> UshResult<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => {
+                let secret: [u8; 32] = contents.try_into().map_err(|_| UshError::Config {
+                    message: format!("Identity file {:?} is corrupt", path),
+                })?;
+                Ok(Self {
+                    signing_key: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                write_private(path, identity.signing_key.as_bytes())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Sign a message, recording our public key in its header
+    pub fn sign(&self, message: &Message) -> UshResult<Message> {
+        let mut signed = message.clone();
+        signed.header.signer = Some(self.public_key());
+        signed.checksum = Message::calculate_checksum(&signed.header, &signed.payload)?;
+
+        let bytes = signed_bytes(&signed.header, &signed.payload)?;
+        signed.signature = Some(self.signing_key.sign(&bytes).to_bytes().to_vec());
+        Ok(signed)
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &to_hex(&self.public_key()))
+            .finish_non_exhaustive()
+    }
+}
+
+fn signed_bytes(header: &MessageHeader, payload: &[u8]) -> UshResult<Vec<u8>> {
+    let header_bytes = serde_json::to_vec(header).map_err(|e| UshError::Protocol {
+        message: format!("Failed to serialize header: {}", e),
+    })?;
+
+    Ok([SIGNATURE_CONTEXT, &header_bytes, payload].concat())
+}
+
+/// Who sent a message, as far as the receiver can tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sender {
+    /// No signature; the content could come from anyone
+    Unsigned,
+    /// Signature checks out and the key belongs to a known peer
+    Verified(String),
+    /// Signature checks out, but the key is not in the trust store
+    Untrusted([u8; 32]),
+    /// Signature is missing its key, malformed, or doesn't match
+    Invalid,
+}
+
+impl Sender {
+    pub fn is_verified(&self) -> bool {
+        matches!(self, Sender::Verified(_))
+    }
+}
+
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sender::Unsigned => write!(f, "unsigned"),
+            Sender::Verified(name) => write!(f, "{}", name),
+            Sender::Untrusted(key) => write!(f, "unknown key {}", &to_hex(key)[..16]),
+            Sender::Invalid => write!(f, "INVALID SIGNATURE"),
+        }
+    }
+}
+
+/// Names for the public keys we trust, one `name hex-key` pair per line
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    peers: Vec<(String, [u8; 32])>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a trust store, treating a missing file as empty
+    pub fn load(path: &Path) -> UshResult<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut store = Self::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(name, key)| Some((name, from_hex(key.trim())?)));
+            match parsed {
+                Some((name, key)) => store.add(name, key)?,
+                None => {
+                    return Err(UshError::Config {
+                        message: format!("Invalid entry in {:?} line {}", path, number + 1),
+                    });
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self, path: &Path) -> UshResult<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let contents: String = self
+            .peers
+            .iter()
+            .map(|(name, key)| format!("{} {}\n", name, to_hex(key)))
+            .collect();
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Trust `key` under `name`, replacing any previous key for that name
+    pub fn add(&mut self, name: &str, key: [u8; 32]) -> UshResult<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(UshError::Config {
+                message: format!("Invalid peer name {:?}: must be one word", name),
+            });
+        }
+        VerifyingKey::from_bytes(&key).map_err(|_| UshError::Config {
+            message: format!("Key for {} is not a valid Ed25519 public key", name),
+        })?;
+
+        self.peers.retain(|(existing, _)| existing != name);
+        self.peers.push((name.to_string(), key));
+        Ok(())
+    }
+
+    /// Returns `true` if a peer was removed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.peers.len();
+        self.peers.retain(|(existing, _)| existing != name);
+        self.peers.len() != before
+    }
+
+    pub fn name_of(&self, key: &[u8; 32]) -> Option<&str> {
+        self.peers
+            .iter()
+            .find(|(_, known)| known == key)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&str, &[u8; 32])> {
+        self.peers.iter().map(|(name, key)| (name.as_str(), key))
+    }
+
+    /// Check a message's signature and name its sender
+    pub fn verify(&self, message: &Message) -> Sender {
+        let (signer, signature) = match (&message.header.signer, &message.signature) {
+            (None, None) => return Sender::Unsigned,
+            (Some(signer), Some(signature)) => (signer, signature),
+            _ => return Sender::Invalid,
+        };
+
+        let Ok(key) = VerifyingKey::from_bytes(signer) else {
+            return Sender::Invalid;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return Sender::Invalid;
+        };
+        let Ok(bytes) = signed_bytes(&message.header, &message.payload) else {
+            return Sender::Invalid;
+        };
+        if key.verify(&bytes, &signature).is_err() {
+            return Sender::Invalid;
+        }
+
+        match self.name_of(signer) {
+            Some(name) => Sender::Verified(name.to_string()),
+            None => Sender::Untrusted(*signer),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parse a 64-digit hex public key
+pub fn from_hex(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verified_untrusted_and_unsigned() {
+        let alice = Identity::generate();
+        let mut trust = TrustStore::new();
+
+        let message = Message::new_text("hi", 1).unwrap();
+        assert_eq!(trust.verify(&message), Sender::Unsigned);
+
+        let signed = alice.sign(&message).unwrap();
+        assert!(signed.verify_checksum().unwrap());
+        assert_eq!(trust.verify(&signed), Sender::Untrusted(alice.public_key()));
+
+        trust.add("alice", alice.public_key()).unwrap();
+        assert_eq!(trust.verify(&signed), Sender::Verified("alice".to_string()));
+    }
+
+    #[test]
+    fn test_forged_content_is_invalid() {
+        let alice = Identity::generate();
+        let mut trust = TrustStore::new();
+        trust.add("alice", alice.public_key()).unwrap();
+
+        let mut forged = alice
+            .sign(&Message::new_text("pay bob 1", 1).unwrap())
+            .unwrap();
+        forged.payload = b"pay bob 9".to_vec();
+        assert_eq!(trust.verify(&forged), Sender::Invalid);
+
+        // Claiming alice's key without her signature
+        let mut impostor = Message::new_text("hi", 2).unwrap();
+        impostor.header.signer = Some(alice.public_key());
+        assert_eq!(trust.verify(&impostor), Sender::Invalid);
+    }
+
+    #[test]
+    fn test_trust_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("ush-peers-{}", std::process::id()));
+        let alice = Identity::generate();
+        let mut trust = TrustStore::new();
+        trust.add("alice", alice.public_key()).unwrap();
+        trust.save(&path).unwrap();
+
+        let loaded = TrustStore::load(&path).unwrap();
+        assert_eq!(loaded.name_of(&alice.public_key()), Some("alice"));
+        assert!(trust.add("two words", alice.public_key()).is_err());
+        assert_eq!(
+            from_hex(&to_hex(&alice.public_key())),
+            Some(alice.public_key())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+//! Step-by-step trace of the receive pipeline for debugging failed decodes
+//!
+//! [`Inspector`] runs the same [`StreamDemodulator`] and [`ProtocolDecoder`]
+//! as [`MessageReceiver`](crate::receiver::MessageReceiver), with tracing
+//! enabled on both. Bytes are fed to the decoder one at a time, so every
+//! state transition, frame and failure is tied to the sample offset of the
+//! byte that caused it.
+
+use crate::crypto::PresharedKey;
+use crate::fec::ConvolutionalCode;
+use crate::modulation::ModulationConfig;
+use crate::protocol::{DecoderState, DecoderTransition, Message, ProtocolDecoder};
+use crate::stream::{ByteTrace, SignalQuality, StreamDemodulator, StreamEvent, SymbolTrace};
+
+/// One step of the receive pipeline, in the order it happened
+#[derive(Debug, Clone)]
+pub enum InspectEvent {
+    /// A transmission was detected and aligned
+    SignalStart { sample_offset: u64 },
+    /// A demodulated symbol
+    Symbol(SymbolTrace),
+    /// A byte assembled from the symbols starting at its sample offset
+    Byte(ByteTrace),
+    /// The decoder changed state after reading the byte ending here
+    Transition {
+        sample_offset: u64,
+        transition: DecoderTransition,
+    },
+    /// A frame passed its checksum and authentication
+    Frame {
+        sample_offset: u64,
+        message: Message,
+    },
+    /// A frame was abandoned or rejected
+    Failure { sample_offset: u64, reason: String },
+    /// The transmission ended
+    SignalEnd {
+        sample_offset: u64,
+        bytes: usize,
+        quality: SignalQuality,
+    },
+}
+
+impl InspectEvent {
+    /// Absolute sample offset the event is placed at on the timeline
+    pub fn sample_offset(&self) -> u64 {
+        match self {
+            InspectEvent::Symbol(symbol) => symbol.sample_offset,
+            InspectEvent::Byte(byte) => byte.sample_offset,
+            InspectEvent::SignalStart { sample_offset }
+            | InspectEvent::Transition { sample_offset, .. }
+            | InspectEvent::Frame { sample_offset, .. }
+            | InspectEvent::Failure { sample_offset, .. }
+            | InspectEvent::SignalEnd { sample_offset, .. } => *sample_offset,
+        }
+    }
+
+    /// Order among events at the same offset: whatever the previous byte
+    /// caused, then the next byte, then its first symbol
+    fn rank(&self) -> u8 {
+        match self {
+            InspectEvent::Byte(_) => 1,
+            InspectEvent::Symbol(_) => 2,
+            _ => 0,
+        }
+    }
+}
+
+pub struct Inspector {
+    stream: StreamDemodulator,
+    decoder: ProtocolDecoder,
+    /// Channel symbols per decoded byte
+    symbols_per_byte: f32,
+    frames_in_transmission: usize,
+}
+
+impl Inspector {
+    pub fn new(config: ModulationConfig, threshold: f32) -> Self {
+        Self {
+            stream: StreamDemodulator::new(config, threshold).with_trace(),
+            decoder: ProtocolDecoder::new().with_trace(),
+            symbols_per_byte: 8.0,
+            frames_in_transmission: 0,
+        }
+    }
+
+    /// Only accept messages encrypted with this pre-shared key
+    pub fn with_key(mut self, key: PresharedKey) -> Self {
+        self.decoder = ProtocolDecoder::new().with_key(key).with_trace();
+        self
+    }
+
+    /// Expect transmissions encoded with this convolutional code
+    pub fn with_fec(mut self, code: ConvolutionalCode) -> Self {
+        self.symbols_per_byte = 8.0 / code.rate().ratio();
+        self.stream = self.stream.with_fec(code);
+        self
+    }
+
+    /// Feed newly captured samples and return what happened, in sample order
+    pub fn push_samples(&mut self, samples: &[f32]) -> Vec<InspectEvent> {
+        let events = self.stream.push_samples(samples);
+        self.process(events)
+    }
+
+    /// Close out any transmission still in progress
+    pub fn flush(&mut self) -> Vec<InspectEvent> {
+        let events = self.stream.flush();
+        self.process(events)
+    }
+
+    /// Total number of samples pushed so far
+    pub fn samples_seen(&self) -> u64 {
+        self.stream.samples_seen()
+    }
+
+    fn process(&mut self, events: Vec<StreamEvent>) -> Vec<InspectEvent> {
+        let trace = self.stream.take_trace();
+        let mut output: Vec<InspectEvent> = trace
+            .symbols
+            .into_iter()
+            .map(InspectEvent::Symbol)
+            .collect();
+        let mut traced_bytes = trace.bytes.into_iter();
+
+        for event in events {
+            match event {
+                StreamEvent::SignalDetected { sample_offset } => {
+                    self.frames_in_transmission = 0;
+                    output.push(InspectEvent::SignalStart { sample_offset });
+                }
+                StreamEvent::Data { bytes, .. } => {
+                    for (value, byte) in bytes.into_iter().zip(traced_bytes.by_ref()) {
+                        output.push(InspectEvent::Byte(byte));
+                        self.decode_byte(value, self.byte_end(&byte), &mut output);
+                    }
+                }
+                StreamEvent::SignalLost {
+                    sample_offset,
+                    bytes,
+                    quality,
+                } => {
+                    if let Some(reason) = self.unfinished_frame(bytes) {
+                        output.push(InspectEvent::Failure {
+                            sample_offset,
+                            reason,
+                        });
+                    }
+                    self.decoder.reset();
+                    output.push(InspectEvent::SignalEnd {
+                        sample_offset,
+                        bytes,
+                        quality,
+                    });
+                }
+            }
+        }
+
+        // Stable, so events at the same offset keep their causal order
+        output.sort_by_key(|event| (event.sample_offset(), event.rank()));
+        output
+    }
+
+    /// Sample offset just past the last symbol of a byte
+    fn byte_end(&self, byte: &ByteTrace) -> u64 {
+        let symbols = self.symbols_per_byte.round() as u64;
+        byte.sample_offset + symbols * self.stream.samples_per_symbol() as u64
+    }
+
+    fn decode_byte(&mut self, value: u8, sample_offset: u64, output: &mut Vec<InspectEvent>) {
+        let results = self.decoder.decode(&[value]);
+        output.extend(self.decoder.take_trace().into_iter().map(|transition| {
+            InspectEvent::Transition {
+                sample_offset,
+                transition,
+            }
+        }));
+        for result in results {
+            self.frames_in_transmission += 1;
+            output.push(match result {
+                Ok(message) => InspectEvent::Frame {
+                    sample_offset,
+                    message,
+                },
+                Err(e) => InspectEvent::Failure {
+                    sample_offset,
+                    reason: e.to_string(),
+                },
+            });
+        }
+    }
+
+    /// Why a transmission that just ended did not yield a frame, if it didn't
+    fn unfinished_frame(&self, bytes: usize) -> Option<String> {
+        match self.decoder.state() {
+            DecoderState::WaitingForPreamble if self.frames_in_transmission == 0 => Some(format!(
+                "transmission ended after {} bytes without a complete frame",
+                bytes
+            )),
+            // A delivered frame cut short before its end delimiter
+            DecoderState::WaitingForPreamble | DecoderState::WaitingForEnd => None,
+            DecoderState::ReadingMessage => Some(format!(
+                "transmission ended while {} ({} of {} bytes)",
+                DecoderState::ReadingMessage,
+                self.decoder.buffered(),
+                self.decoder.expected_length()
+            )),
+            state => Some(format!("transmission ended while {}", state)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::{FskDemodulator, FskModulator};
+    use crate::protocol::ProtocolEncoder;
+
+    fn inspect(frame: &[u8]) -> (Vec<InspectEvent>, u64) {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut inspector = Inspector::new(config, 0.1);
+
+        let lead_in = 1000;
+        let mut samples = vec![0.0; lead_in];
+        samples.extend(modulator.encode_bytes(frame));
+        samples.extend(vec![0.0; 4410]);
+
+        let mut events = inspector.push_samples(&samples);
+        events.extend(inspector.flush());
+        (events, lead_in as u64)
+    }
+
+    fn transitions(events: &[InspectEvent]) -> Vec<&DecoderTransition> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                InspectEvent::Transition { transition, .. } => Some(transition),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_inspector_traces_a_clean_frame() {
+        let frame = ProtocolEncoder::new().encode_text("inspect me").unwrap();
+        let (events, _) = inspect(&frame);
+
+        let symbols = events
+            .iter()
+            .filter(|event| matches!(event, InspectEvent::Symbol(_)))
+            .count();
+        assert!(symbols >= frame.len() * 8);
+
+        let bytes: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                InspectEvent::Byte(byte) => Some(byte.value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(&bytes[..frame.len()], &frame[..]);
+
+        let states: Vec<DecoderState> = transitions(&events).iter().map(|t| t.to).collect();
+        assert_eq!(
+            states,
+            vec![
+                DecoderState::WaitingForStart,
+                DecoderState::ReadingLength,
+                DecoderState::ReadingMessage,
+                DecoderState::WaitingForEnd,
+                DecoderState::WaitingForPreamble,
+            ]
+        );
+        assert!(transitions(&events).iter().all(|t| !t.failure));
+
+        let text: Vec<String> = events
+            .iter()
+            .filter_map(|event| match event {
+                InspectEvent::Frame { message, .. } => message.get_text().ok(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, vec!["inspect me".to_string()]);
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, InspectEvent::Failure { .. }))
+        );
+
+        let offsets: Vec<u64> = events.iter().map(InspectEvent::sample_offset).collect();
+        assert!(offsets.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_inspector_pinpoints_a_bad_length() {
+        let mut frame = ProtocolEncoder::new().encode_text("inspect me").unwrap();
+        // Byte 10 is the high byte of the length field
+        frame[10] = 0xFF;
+        let (events, lead_in) = inspect(&frame);
+
+        let sps = FskDemodulator::new(ModulationConfig::default()).samples_per_symbol() as u64;
+        let failure = events
+            .iter()
+            .find_map(|event| match event {
+                InspectEvent::Transition {
+                    sample_offset,
+                    transition,
+                } if transition.failure => Some((*sample_offset, transition.clone())),
+                _ => None,
+            })
+            .expect("the bad length should be reported");
+
+        assert_eq!(failure.1.from, DecoderState::ReadingLength);
+        assert!(failure.1.note.contains("exceeds"));
+        // Reported at the end of the second length byte, give or take alignment
+        let expected = lead_in + 12 * 8 * sps;
+        assert!(
+            failure.0.abs_diff(expected) < sps,
+            "{} vs {}",
+            failure.0,
+            expected
+        );
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, InspectEvent::Frame { .. }))
+        );
+    }
+}
@@ -1,8 +1,23 @@
 pub mod audio;
+pub mod audio_file;
+pub mod base64;
+pub mod carrier;
 pub mod cli;
+pub mod crypto;
 pub mod debug;
 pub mod error;
+pub mod fec;
+pub mod identity;
+pub mod inspector;
 pub mod modulation;
+pub mod output;
+pub mod pairing;
 pub mod protocol;
+pub mod quality;
+pub mod receiver;
+pub mod stego;
+pub mod stream;
+pub mod tx_level;
+pub mod visualizer;
 
 pub use error::{UshError, UshResult};
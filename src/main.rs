@@ -1,7 +1,16 @@
 use clap::Parser;
 use log::info;
+use std::time::Duration;
 
-use ush::cli::{Cli, Commands, validate_frequency, validate_sample_rate, validate_threshold};
+use ush::audio_file::DEFAULT_MP3_BITRATE;
+use ush::cli::{
+    AudioSettings, Cli, Commands, OutputFormat, validate_bitrate, validate_frequency,
+    validate_pre_emphasis, validate_sample_rate, validate_threshold, validate_tx_level,
+};
+use ush::crypto::{load_session_key, session_key_path};
+use ush::identity::{Identity, TrustStore, identity_path, known_peers_path};
+use ush::protocol::{DEFAULT_MAX_SKEW, ReplayPolicy};
+use ush::tx_level::TxLevel;
 use ush::{UshError, UshResult};
 
 mod app;
@@ -37,81 +46,231 @@ async fn main() -> UshResult<()> {
         });
     }
 
+    if settings.freq_1 >= settings.sample_rate as f32 / 2.0 {
+        return Err(UshError::Config {
+            message: format!(
+                "freq_1 ({}) must be below {} Hz, half the {} Hz sample rate",
+                settings.freq_1,
+                settings.sample_rate / 2,
+                settings.sample_rate
+            ),
+        });
+    }
+
+    validate_pre_emphasis(settings.pre_emphasis_db).map_err(|e| UshError::Config { message: e })?;
+    match settings.tx_level {
+        TxLevel::Fixed(level) => {
+            validate_tx_level(level).map_err(|e| UshError::Config { message: e })?;
+        }
+        TxLevel::Adaptive(adaptive) => {
+            validate_tx_level(adaptive.max_level).map_err(|e| UshError::Config { message: e })?;
+        }
+    }
+
     info!("Starting ush v0.1.0");
     info!(
         "Audio settings: {}Hz sample rate, freq_0={}Hz, freq_1={}Hz",
         settings.sample_rate, settings.freq_0, settings.freq_1
     );
 
+    let app = configured_app(&cli, settings)?;
+
     match &cli.command {
         Commands::Send {
             message,
             repeat,
-            save_wav,
+            save_audio,
+            bitrate,
             from_wav,
+            to,
         } => {
-            let app = UshApp::new(settings)?;
-            app.send_message(message, *repeat, save_wav.as_deref(), from_wav.as_deref())
-                .await
+            let bitrate = bitrate
+                .map(validate_bitrate)
+                .transpose()
+                .map_err(|e| UshError::Config { message: e })?
+                .unwrap_or(DEFAULT_MP3_BITRATE);
+            let app = app.with_destination(*to).with_mp3_bitrate(bitrate);
+            if message == "-" && from_wav.is_none() {
+                app.send_stdin(*repeat, save_audio.as_deref()).await
+            } else {
+                app.send_message(message, *repeat, save_audio.as_deref(), from_wav.as_deref())
+                    .await
+            }
         }
         Commands::Listen {
             timeout,
             save_wav,
-            from_wav,
+            from_file,
             filter,
             threshold,
             debug,
             debug_output,
+            format,
+            raw,
+            max_skew,
+            allow_replay,
+            monitor_all,
         } => {
-            let app = UshApp::new(settings)?;
+            // Recordings carry old timestamps, so only check skew if asked to
+            let replay = if *allow_replay {
+                ReplayPolicy::disabled()
+            } else {
+                ReplayPolicy {
+                    max_skew: match (max_skew, from_file) {
+                        (Some(seconds), _) => Some(Duration::from_secs(*seconds)),
+                        (None, Some(_)) => None,
+                        (None, None) => Some(DEFAULT_MAX_SKEW),
+                    },
+                    reject_replays: true,
+                }
+            };
+            let app = app
+                .with_replay_policy(replay)
+                .with_monitor_all(*monitor_all);
             let threshold = threshold
                 .map(validate_threshold)
                 .transpose()
                 .map_err(|e| UshError::Config { message: e })?
                 .unwrap_or(0.1);
-            app.listen_for_messages(
-                *timeout,
-                save_wav.as_deref(),
-                from_wav.as_deref(),
-                *filter,
+            app.listen_for_messages(ListenOptions {
+                timeout_secs: *timeout,
+                save_wav: save_wav.as_deref(),
+                from_file: from_file.as_deref(),
+                filter: *filter,
                 threshold,
-                *debug,
-                debug_output.as_deref(),
-            )
+                debug: *debug,
+                debug_output: debug_output.as_deref(),
+                format: if *raw { OutputFormat::Raw } else { *format },
+            })
             .await
         }
         Commands::Chat {
             username,
             ack,
             timeout,
+            format,
+            to,
+            monitor_all,
         } => {
-            let app = UshApp::new(settings)?;
-            app.start_chat_mode(username.as_deref(), *ack, *timeout)
+            let app = app.with_destination(*to).with_monitor_all(*monitor_all);
+            app.start_chat_mode(username.as_deref(), *ack, *timeout, *format)
                 .await
         }
+        Commands::Identity { action } => run_identity_command(action),
+        Commands::Pair { forget: true, .. } => forget_pairing(),
+        Commands::Pair {
+            initiate, timeout, ..
+        } => app.pair(*initiate, *timeout).await,
         Commands::SendFile {
             file,
             chunk_size,
             delay,
-        } => {
-            let app = UshApp::new(settings)?;
-            app.send_file(file, *chunk_size, *delay).await
-        }
-        Commands::ReceiveFile { output, timeout } => {
-            let app = UshApp::new(settings)?;
-            app.receive_file(output, *timeout).await
-        }
-        Commands::Test { test_type } => {
-            let app = UshApp::new(settings)?;
-            app.run_test(test_type).await
+        } => app.send_file(file, *chunk_size, *delay).await,
+        Commands::ReceiveFile { output, timeout } => app.receive_file(output, *timeout).await,
+        Commands::Scan { duration, from_wav } => {
+            app.scan_channels(duration.unwrap_or(3.0), from_wav.as_deref())
+                .await
         }
+        Commands::Test { test_type } => app.run_test(test_type).await,
         Commands::Debug {
             spectrum,
             waveform,
             rate,
+        } => app.debug_mode(*spectrum, *waveform, *rate).await,
+        Commands::Analyze {
+            files,
+            output,
+            window_size,
+            hop_size,
+            fft_size,
+            compare,
+        } => {
+            app.analyze_recordings(
+                files,
+                output.as_deref(),
+                *window_size,
+                *hop_size,
+                *fft_size,
+                *compare,
+            )
+            .await
+        }
+        Commands::Embed {
+            carrier,
+            message,
+            out,
+            secret,
+            at,
+            margin,
+            bitrate,
+        } => {
+            let bitrate = bitrate
+                .map(validate_bitrate)
+                .transpose()
+                .map_err(|e| UshError::Config { message: e })?
+                .unwrap_or(DEFAULT_MP3_BITRATE);
+            let app = app.with_mp3_bitrate(bitrate);
+            app.embed_message(carrier, message, out, secret.as_deref(), *at, *margin)
+        }
+        Commands::Extract { file, secret } => app.extract_messages(file, secret.as_deref()),
+        Commands::Inspect {
+            from_wav,
+            timeout,
+            threshold,
+            no_symbols,
         } => {
-            let app = UshApp::new(settings)?;
-            app.debug_mode(*spectrum, *waveform, *rate).await
+            let threshold = threshold
+                .map(validate_threshold)
+                .transpose()
+                .map_err(|e| UshError::Config { message: e })?
+                .unwrap_or(0.1);
+            app.inspect(from_wav.as_deref(), *timeout, threshold, !*no_symbols)
+                .await
         }
     }
 }
+
+/// The app with the key, identity, addressing and FEC every command shares
+fn configured_app(cli: &Cli, settings: AudioSettings) -> UshResult<UshApp> {
+    let key = match cli.preshared_key()? {
+        Some(key) => {
+            info!("Encrypting messages with pre-shared key {:08x}", key.id());
+            Some(key)
+        }
+        None if !matches!(cli.command, Commands::Pair { .. }) => {
+            let key = session_key_path()
+                .map(|path| load_session_key(&path))
+                .transpose()?
+                .flatten();
+            if let Some(key) = &key {
+                info!(
+                    "Encrypting messages with paired session key {:08x}",
+                    key.id()
+                );
+            }
+            key
+        }
+        None => None,
+    };
+
+    let identity = if cli.sign {
+        let path = identity_path().ok_or_else(|| UshError::Config {
+            message: "Cannot locate the config directory (HOME is not set)".to_string(),
+        })?;
+        Some(Identity::load_or_create(&path)?)
+    } else {
+        None
+    };
+    let trust = known_peers_path()
+        .map(|path| TrustStore::load(&path))
+        .transpose()?
+        .unwrap_or_default();
+
+    Ok(UshApp::new(settings)?
+        .with_key(key)
+        .with_identity(identity)
+        .with_trust_store(trust)
+        .with_address(cli.address())
+        .with_carrier_sense(!cli.no_carrier_sense)
+        .with_fec(cli.fec()?))
+}
@@ -7,6 +7,12 @@ const CARRIER_FREQ_0: f32 = 18000.0; // Frequency for bit '0'
 const CARRIER_FREQ_1: f32 = 20000.0; // Frequency for bit '1'
 const SYMBOL_DURATION: f32 = 0.01; // 10ms per symbol
 const RAMP_DURATION: f32 = 0.002; // 2ms ramp up/down to reduce clicks
+const MIN_SYMBOL_POWER: f32 = 0.001; // Below this neither tone is considered present
+const SEARCH_RANGE: usize = 3; // Bins searched either side of each tone
+const TRACKING_RANGE: usize = 1; // Bins searched once the carrier offset is known
+pub const MAX_LLR: f32 = 32.0; // Cap on soft-decision confidence
+pub const DEFAULT_AMPLITUDE: f32 = 0.3; // Peak tone amplitude unless a level is chosen
+pub const MAX_PRE_EMPHASIS_DB: f32 = 20.0; // Largest tone tilt the modulator makes up for
 
 #[derive(Debug, Clone)]
 pub struct ModulationConfig {
@@ -29,10 +35,56 @@ impl Default for ModulationConfig {
     }
 }
 
+/// Lower edge of the channel plan in Hz
+pub const CHANNEL_PLAN_START: f32 = 17000.0;
+/// Width of each channel in Hz; the plan spans 17–22 kHz
+pub const CHANNEL_WIDTH: f32 = 1000.0;
+pub const CHANNEL_COUNT: u8 = 5;
+/// Distance from each tone to the nearer edge of its channel, in Hz
+const CHANNEL_GUARD: f32 = 250.0;
+
+/// One numbered sub-band of the channel plan, 1 to [`CHANNEL_COUNT`]
+///
+/// Each channel places `freq_0` and `freq_1` 500 Hz apart with a 250 Hz guard
+/// band to either edge, so neighbouring channels' tones are 500 Hz apart too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel(u8);
+
+impl Channel {
+    pub fn new(number: u8) -> Option<Self> {
+        (1..=CHANNEL_COUNT)
+            .contains(&number)
+            .then_some(Self(number))
+    }
+
+    pub fn all() -> impl Iterator<Item = Channel> {
+        (1..=CHANNEL_COUNT).map(Channel)
+    }
+
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    /// Lower and upper edge of the sub-band in Hz
+    pub fn band(self) -> (f32, f32) {
+        let low = CHANNEL_PLAN_START + (self.0 - 1) as f32 * CHANNEL_WIDTH;
+        (low, low + CHANNEL_WIDTH)
+    }
+
+    /// The `freq_0` and `freq_1` carriers for this channel
+    pub fn frequencies(self) -> (f32, f32) {
+        let (low, high) = self.band();
+        (low + CHANNEL_GUARD, high - CHANNEL_GUARD)
+    }
+}
+
 pub struct FskModulator {
     config: ModulationConfig,
     samples_per_symbol: usize,
     ramp_samples: usize,
+    amplitude: f32,
+    /// Relative gain of the `freq_0` and `freq_1` tones
+    tone_gains: (f32, f32),
 }
 
 impl FskModulator {
@@ -44,20 +96,37 @@ impl FskModulator {
             config,
             samples_per_symbol,
             ramp_samples,
+            amplitude: DEFAULT_AMPLITUDE,
+            tone_gains: (1.0, 1.0),
         }
     }
 
+    /// Peak amplitude of each tone (0.0-1.0)
+    pub fn with_amplitude(mut self, amplitude: f32) -> Self {
+        self.amplitude = amplitude;
+        self
+    }
+
+    /// Boost `freq_1` by `db` relative to `freq_0`, or `freq_0` if negative,
+    /// to make up for a speaker that rolls off between them. The louder tone
+    /// keeps the full amplitude.
+    pub fn with_pre_emphasis(mut self, db: f32) -> Self {
+        let cut = 10f32.powf(-db.abs() / 20.0);
+        self.tone_gains = if db >= 0.0 { (cut, 1.0) } else { (1.0, cut) };
+        self
+    }
+
     pub fn encode_bits(&self, bits: &[bool]) -> Vec<f32> {
         let total_samples = bits.len() * self.samples_per_symbol;
         let mut samples = Vec::with_capacity(total_samples);
 
         for (i, &bit) in bits.iter().enumerate() {
-            let frequency = if bit {
-                self.config.freq_1
+            let (frequency, gain) = if bit {
+                (self.config.freq_1, self.tone_gains.1)
             } else {
-                self.config.freq_0
+                (self.config.freq_0, self.tone_gains.0)
             };
-            let symbol_samples = self.generate_symbol(frequency, i == 0, i == bits.len() - 1);
+            let symbol_samples = self.generate_symbol(frequency, gain, i == 0, i == bits.len() - 1);
             samples.extend(symbol_samples);
         }
 
@@ -65,7 +134,13 @@ impl FskModulator {
         samples
     }
 
-    fn generate_symbol(&self, frequency: f32, is_first: bool, is_last: bool) -> Vec<f32> {
+    fn generate_symbol(
+        &self,
+        frequency: f32,
+        gain: f32,
+        is_first: bool,
+        is_last: bool,
+    ) -> Vec<f32> {
         let mut samples = Vec::with_capacity(self.samples_per_symbol);
 
         for i in 0..self.samples_per_symbol {
@@ -84,7 +159,7 @@ impl FskModulator {
                 amplitude *= ramp_factor;
             }
 
-            samples.push(amplitude * 0.3); // Reduce volume to 30%
+            samples.push(amplitude * self.amplitude * gain);
         }
 
         samples
@@ -104,11 +179,129 @@ impl FskModulator {
     }
 }
 
+/// Soft-decision output for one symbol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoftBit {
+    /// Log-likelihood ratio ln(P(1) / P(0)): the sign is the bit, the
+    /// magnitude how sure the demodulator is of it
+    Llr(f32),
+    /// Neither tone was present, so the bit carries no information
+    Erasure,
+}
+
+impl SoftBit {
+    /// LLR of the bit, zero for an erasure
+    pub fn llr(&self) -> f32 {
+        match self {
+            SoftBit::Llr(llr) => *llr,
+            SoftBit::Erasure => 0.0,
+        }
+    }
+
+    /// Hard decision, or `None` for an erasure
+    pub fn hard(&self) -> Option<bool> {
+        match self {
+            SoftBit::Llr(llr) => Some(*llr > 0.0),
+            SoftBit::Erasure => None,
+        }
+    }
+
+    pub fn is_erasure(&self) -> bool {
+        matches!(self, SoftBit::Erasure)
+    }
+}
+
+/// Tone powers measured over a single symbol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SymbolPowers {
+    pub power_0: f32,
+    pub power_1: f32,
+    pub total_power: f32,
+    /// Average power of a bin outside both tone windows
+    pub noise_power: f32,
+    /// Frequency error of the dominant tone in Hz
+    pub frequency_offset: f32,
+    /// Nominal frequency of the dominant tone in Hz
+    pub nominal_freq: f32,
+    /// Weight on `power_1` when the tones are compared
+    pub balance: f32,
+}
+
+impl SymbolPowers {
+    /// Whether either tone carries enough power to be decoded
+    pub fn has_signal(&self) -> bool {
+        self.power_0 >= MIN_SYMBOL_POWER || self.power_1 >= MIN_SYMBOL_POWER
+    }
+
+    /// Fraction of the symbol's power concentrated in the strongest tone (0.0-1.0)
+    pub fn tone_ratio(&self) -> f32 {
+        if self.total_power > 0.0 {
+            (self.power_0.max(self.power_1) / self.total_power).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// How cleanly one tone dominates the other (0.0-1.0)
+    pub fn contrast(&self) -> f32 {
+        let power_1 = self.power_1 * self.balance;
+        let sum = self.power_0 + power_1;
+        if sum > 0.0 {
+            (power_1 - self.power_0).abs() / sum
+        } else {
+            0.0
+        }
+    }
+
+    pub fn bit(&self) -> bool {
+        self.power_1 * self.balance > self.power_0
+    }
+
+    /// Soft decision from the tone power difference relative to the noise floor.
+    ///
+    /// For non-coherent FSK in white noise the LLR is proportional to
+    /// `(power_1 - power_0) / noise_power`; it is capped at ±32.
+    pub fn soft_bit(&self) -> SoftBit {
+        if !self.has_signal() {
+            return SoftBit::Erasure;
+        }
+
+        let difference = self.power_1 * self.balance - self.power_0;
+        let llr = if self.noise_power > 0.0 {
+            difference / self.noise_power
+        } else {
+            MAX_LLR.copysign(difference)
+        };
+        SoftBit::Llr(llr.clamp(-MAX_LLR, MAX_LLR))
+    }
+
+    /// Power of the dominant tone
+    pub fn signal_power(&self) -> f32 {
+        self.power_0.max(self.power_1)
+    }
+
+    /// Frequency error of the dominant tone in parts per million.
+    ///
+    /// Doppler shift and clock mismatch scale both tones by the same factor,
+    /// so unlike the offset in Hz this is comparable between tones.
+    pub fn offset_ppm(&self) -> f32 {
+        if self.nominal_freq > 0.0 {
+            self.frequency_offset / self.nominal_freq * 1e6
+        } else {
+            0.0
+        }
+    }
+}
+
 pub struct FskDemodulator {
     config: ModulationConfig,
     samples_per_symbol: usize,
     fft_size: usize,
     fft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Carrier offset the tone windows are corrected for, once locked
+    offset_ppm: Option<f32>,
+    /// Weight on `freq_1` power that makes both tones arrive equally strong
+    tone_balance: Option<f32>,
 }
 
 impl FskDemodulator {
@@ -124,11 +317,38 @@ impl FskDemodulator {
             samples_per_symbol,
             fft_size,
             fft,
+            offset_ppm: None,
+            tone_balance: None,
         }
     }
 
+    /// Expect both tones shifted by `ppm` and search a narrow window around them.
+    ///
+    /// `None` returns to the nominal tones and the wide acquisition window.
+    pub fn set_frequency_offset(&mut self, ppm: Option<f32>) {
+        self.offset_ppm = ppm;
+    }
+
+    /// Carrier offset currently corrected for, in ppm
+    pub fn frequency_offset(&self) -> Option<f32> {
+        self.offset_ppm
+    }
+
+    /// Weigh `freq_1` power by `balance` when the tones are compared, so a
+    /// speaker or microphone that favours one tone doesn't bias the bits.
+    ///
+    /// `None` compares the raw powers.
+    pub fn set_tone_balance(&mut self, balance: Option<f32>) {
+        self.tone_balance = balance;
+    }
+
+    /// Weight currently applied to `freq_1` power
+    pub fn tone_balance(&self) -> Option<f32> {
+        self.tone_balance
+    }
+
     pub fn decode_samples(&self, samples: &[f32]) -> UshResult<Vec<bool>> {
-        if samples.len() % self.samples_per_symbol != 0 {
+        if !samples.len().is_multiple_of(self.samples_per_symbol) {
             return Err(UshError::Decoding {
                 message: format!(
                     "Sample length {} is not a multiple of symbol length {}",
@@ -154,10 +374,59 @@ impl FskDemodulator {
         Ok(bits)
     }
 
+    /// Demodulate into soft bits, one per symbol.
+    ///
+    /// Unlike [`decode_samples`](Self::decode_samples), weak symbols don't
+    /// fail the call but come back as [`SoftBit::Erasure`].
+    pub fn soft_decode_samples(&self, samples: &[f32]) -> UshResult<Vec<SoftBit>> {
+        if !samples.len().is_multiple_of(self.samples_per_symbol) {
+            return Err(UshError::Decoding {
+                message: format!(
+                    "Sample length {} is not a multiple of symbol length {}",
+                    samples.len(),
+                    self.samples_per_symbol
+                ),
+            });
+        }
+
+        let soft_bits: Vec<SoftBit> = samples
+            .chunks_exact(self.samples_per_symbol)
+            .map(|symbol| self.measure_symbol(symbol).soft_bit())
+            .collect();
+
+        debug!(
+            "Soft-decoded {} symbols, {} erasures",
+            soft_bits.len(),
+            soft_bits.iter().filter(|bit| bit.is_erasure()).count()
+        );
+        Ok(soft_bits)
+    }
+
     fn decode_symbol(&self, samples: &[f32]) -> UshResult<bool> {
+        let powers = self.measure_symbol(samples);
+
+        debug!(
+            "Symbol detection: freq_0 power = {:.2}, freq_1 power = {:.2}",
+            powers.power_0, powers.power_1
+        );
+
+        if !powers.has_signal() {
+            return Err(UshError::Decoding {
+                message: "No signal detected in symbol".to_string(),
+            });
+        }
+
+        Ok(powers.bit())
+    }
+
+    /// Measure the power at both FSK tones over one symbol's worth of samples
+    pub fn measure_symbol(&self, samples: &[f32]) -> SymbolPowers {
         // Pad samples to FFT size
-        let mut padded_samples: Vec<Complex<f32>> =
-            samples.iter().map(|&s| Complex::new(s, 0.0)).collect();
+        let mut padded_samples: Vec<Complex<f32>> = samples
+            .iter()
+            .take(self.fft_size)
+            .map(|&s| Complex::new(s, 0.0))
+            .collect();
 
         padded_samples.resize(self.fft_size, Complex::new(0.0, 0.0));
 
@@ -165,47 +434,99 @@ impl FskDemodulator {
         self.fft.process(&mut padded_samples);
 
         // Find the dominant frequency by looking at magnitude spectrum
-        let freq_0_bin =
-            (self.config.freq_0 * self.fft_size as f32 / self.config.sample_rate as f32) as usize;
-        let freq_1_bin =
-            (self.config.freq_1 * self.fft_size as f32 / self.config.sample_rate as f32) as usize;
+        let bin_hz = self.config.sample_rate as f32 / self.fft_size as f32;
+        let (scale, range) = match self.offset_ppm {
+            Some(ppm) => (1.0 + ppm * 1e-6, TRACKING_RANGE),
+            None => (1.0, SEARCH_RANGE),
+        };
+        let freq_0_bin = (self.config.freq_0 * scale / bin_hz).round() as usize;
+        let freq_1_bin = (self.config.freq_1 * scale / bin_hz).round() as usize;
 
-        let _power_0 = if freq_0_bin < padded_samples.len() {
-            padded_samples[freq_0_bin].norm_sqr()
+        // Check nearby bins for better detection
+        let (peak_0, power_0) = Self::strongest_bin(&padded_samples, freq_0_bin, range);
+        let (peak_1, power_1) = Self::strongest_bin(&padded_samples, freq_1_bin, range);
+
+        // Total power over the positive half of the spectrum, and the average
+        // power of the bins outside both tone windows as a noise estimate
+        let half = self.fft_size / 2;
+        let in_tone_window = |i: usize| {
+            i.abs_diff(freq_0_bin) <= SEARCH_RANGE || i.abs_diff(freq_1_bin) <= SEARCH_RANGE
+        };
+        let mut total_power = 0.0;
+        let mut noise_total = 0.0;
+        let mut noise_bins = 0;
+        for (i, bin) in padded_samples[..half].iter().enumerate() {
+            let power = bin.norm_sqr();
+            total_power += power;
+            if i > 0 && !in_tone_window(i) {
+                noise_total += power;
+                noise_bins += 1;
+            }
+        }
+        let noise_power = if noise_bins > 0 {
+            noise_total / noise_bins as f32
         } else {
             0.0
         };
 
-        let _power_1 = if freq_1_bin < padded_samples.len() {
-            padded_samples[freq_1_bin].norm_sqr()
+        // Locate the dominant tone between bins to estimate its frequency error
+        let balance = self.tone_balance.unwrap_or(1.0);
+        let (peak_bin, nominal_freq) = if power_1 * balance > power_0 {
+            (peak_1, self.config.freq_1)
         } else {
-            0.0
+            (peak_0, self.config.freq_0)
         };
+        let frequency_offset =
+            (peak_bin as f32 + Self::interpolate_peak(&padded_samples, peak_bin)) * bin_hz
+                - nominal_freq;
+
+        SymbolPowers {
+            power_0,
+            power_1,
+            total_power,
+            noise_power,
+            frequency_offset,
+            nominal_freq,
+            balance,
+        }
+    }
 
-        // Check nearby bins for better detection
-        let search_range = 3;
-        let power_0_max = (freq_0_bin.saturating_sub(search_range)
-            ..=(freq_0_bin + search_range).min(padded_samples.len() - 1))
-            .map(|i| padded_samples[i].norm_sqr())
-            .fold(0.0f32, f32::max);
+    /// Index and power of the strongest bin within `range` of `center`
+    fn strongest_bin(spectrum: &[Complex<f32>], center: usize, range: usize) -> (usize, f32) {
+        (center.saturating_sub(range)..=(center + range).min(spectrum.len() - 1))
+            .map(|i| (i, spectrum[i].norm_sqr()))
+            .fold((center, 0.0f32), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+    }
 
-        let power_1_max = (freq_1_bin.saturating_sub(search_range)
-            ..=(freq_1_bin + search_range).min(padded_samples.len() - 1))
-            .map(|i| padded_samples[i].norm_sqr())
-            .fold(0.0f32, f32::max);
+    /// Fractional bin offset (-0.5..0.5) of a spectral peak, by parabolic interpolation
+    fn interpolate_peak(spectrum: &[Complex<f32>], peak: usize) -> f32 {
+        if peak == 0 || peak + 1 >= spectrum.len() {
+            return 0.0;
+        }
 
-        debug!(
-            "Symbol detection: freq_0 power = {:.2}, freq_1 power = {:.2}",
-            power_0_max, power_1_max
-        );
+        let log_mag = |i: usize| (spectrum[i].norm() + f32::EPSILON).ln();
+        let (a, b, c) = (log_mag(peak - 1), log_mag(peak), log_mag(peak + 1));
+        let denominator = a - 2.0 * b + c;
 
-        if power_0_max < 0.001 && power_1_max < 0.001 {
-            return Err(UshError::Decoding {
-                message: "No signal detected in symbol".to_string(),
-            });
+        if denominator.abs() > f32::EPSILON {
+            (0.5 * (a - c) / denominator).clamp(-0.5, 0.5)
+        } else {
+            0.0
         }
+    }
+
+    pub fn samples_per_symbol(&self) -> usize {
+        self.samples_per_symbol
+    }
 
-        Ok(power_1_max > power_0_max)
+    pub fn config(&self) -> &ModulationConfig {
+        &self.config
     }
 
     pub fn decode_bytes(&self, samples: &[f32]) -> UshResult<Vec<u8>> {
@@ -234,6 +555,25 @@ impl FskDemodulator {
     }
 }
 
+/// Mean `freq_0` power of the symbols decided as 0 over the mean `freq_1`
+/// power of those decided as 1: the weight on `freq_1` that makes both tones
+/// equally strong. `None` unless both tones were heard.
+pub fn tone_balance(symbols: &[SymbolPowers]) -> Option<f32> {
+    let (mut sum_0, mut count_0, mut sum_1, mut count_1) = (0.0, 0, 0.0, 0);
+    for powers in symbols.iter().filter(|powers| powers.has_signal()) {
+        if powers.bit() {
+            sum_1 += powers.power_1;
+            count_1 += 1;
+        } else {
+            sum_0 += powers.power_0;
+            count_0 += 1;
+        }
+    }
+
+    (count_0 > 0 && count_1 > 0 && sum_1 > 0.0)
+        .then(|| (sum_0 / count_0 as f32) / (sum_1 / count_1 as f32))
+}
+
 // Utility functions for signal detection
 pub fn detect_signal_start(samples: &[f32], threshold: f32) -> Option<usize> {
     let window_size = 512;
@@ -262,39 +602,62 @@ pub fn apply_bandpass_filter(
     high_freq: f32,
     sample_rate: u32,
 ) -> Vec<f32> {
-    // Simple high-pass then low-pass filtering
     let mut filtered = samples.to_vec();
+    BandpassFilter::new(low_freq, high_freq, sample_rate).process(&mut filtered);
+    filtered
+}
 
-    // High-pass filter (remove DC and low frequencies)
-    let alpha_hp = 1.0 / (1.0 + 2.0 * PI * low_freq / sample_rate as f32);
-    let mut prev_input = 0.0;
-    let mut prev_output = 0.0;
-
-    for sample in filtered.iter_mut() {
-        let output = alpha_hp * (prev_output + *sample - prev_input);
-        prev_input = *sample;
-        prev_output = output;
-        *sample = output;
-    }
+/// Stateful high-pass/low-pass filter pair for chunked sample streams
+///
+/// Filter state carries over between calls to [`BandpassFilter::process`],
+/// so splitting a signal into chunks gives the same output as filtering it
+/// in one piece.
+#[derive(Debug, Clone)]
+pub struct BandpassFilter {
+    alpha_hp: f32,
+    alpha_lp: f32,
+    hp_prev_input: f32,
+    hp_prev_output: f32,
+    lp_prev_output: f32,
+}
 
-    // Low-pass filter (remove high frequencies)
-    let alpha_lp = 2.0 * PI * high_freq
-        / sample_rate as f32
-        / (1.0 + 2.0 * PI * high_freq / sample_rate as f32);
-    prev_output = 0.0;
+impl BandpassFilter {
+    pub fn new(low_freq: f32, high_freq: f32, sample_rate: u32) -> Self {
+        let alpha_hp = 1.0 / (1.0 + 2.0 * PI * low_freq / sample_rate as f32);
+        let alpha_lp = 2.0 * PI * high_freq
+            / sample_rate as f32
+            / (1.0 + 2.0 * PI * high_freq / sample_rate as f32);
 
-    for sample in filtered.iter_mut() {
-        let output = prev_output + alpha_lp * (*sample - prev_output);
-        prev_output = output;
-        *sample = output;
+        Self {
+            alpha_hp,
+            alpha_lp,
+            hp_prev_input: 0.0,
+            hp_prev_output: 0.0,
+            lp_prev_output: 0.0,
+        }
     }
 
-    filtered
+    /// Filter samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            // High-pass filter (remove DC and low frequencies)
+            let hp = self.alpha_hp * (self.hp_prev_output + *sample - self.hp_prev_input);
+            self.hp_prev_input = *sample;
+            self.hp_prev_output = hp;
+
+            // Low-pass filter (remove high frequencies)
+            let lp = self.lp_prev_output + self.alpha_lp * (hp - self.lp_prev_output);
+            self.lp_prev_output = lp;
+            *sample = lp;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_encode_decode_roundtrip() {
@@ -309,6 +672,43 @@ mod tests {
         assert_eq!(original_data, &decoded[..]);
     }
 
+    #[test]
+    fn test_bandpass_filter_is_chunk_invariant() {
+        let samples: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.37).sin()).collect();
+        let whole = apply_bandpass_filter(&samples, 17000.0, 21000.0, 44100);
+
+        let mut filter = BandpassFilter::new(17000.0, 21000.0, 44100);
+        let mut chunked = samples.clone();
+        for chunk in chunked.chunks_mut(333) {
+            filter.process(chunk);
+        }
+
+        assert_eq!(whole, chunked);
+    }
+
+    #[test]
+    fn test_measure_symbol_reports_frequency_offset() {
+        let config = ModulationConfig::default();
+        let demodulator = FskDemodulator::new(config.clone());
+
+        let offset = 30.0;
+        let samples: Vec<f32> = (0..demodulator.samples_per_symbol())
+            .map(|i| {
+                let t = i as f32 / config.sample_rate as f32;
+                (2.0 * PI * (config.freq_1 + offset) * t).sin() * 0.3
+            })
+            .collect();
+
+        let powers = demodulator.measure_symbol(&samples);
+        assert!(powers.bit());
+        assert!(
+            (powers.frequency_offset - offset).abs() < 15.0,
+            "measured offset {} Hz",
+            powers.frequency_offset
+        );
+        assert!(powers.signal_power() > powers.noise_power * 100.0);
+    }
+
     #[test]
     fn test_bit_encoding() {
         let config = ModulationConfig::default();
@@ -319,4 +719,147 @@ mod tests {
 
         assert_eq!(samples.len(), bits.len() * modulator.samples_per_symbol);
     }
+
+    #[test]
+    fn test_channel_plan_fits_the_band() {
+        assert!(Channel::new(0).is_none());
+        assert!(Channel::new(CHANNEL_COUNT + 1).is_none());
+
+        let channels: Vec<Channel> = Channel::all().collect();
+        assert_eq!(channels.len(), CHANNEL_COUNT as usize);
+        assert_eq!(channels[0].frequencies(), (17250.0, 17750.0));
+        assert_eq!(channels[4].band().1, 22000.0);
+        for pair in channels.windows(2) {
+            assert!(pair[0].frequencies().1 < pair[1].frequencies().0);
+        }
+    }
+
+    #[test]
+    fn test_neighbouring_channels_coexist() {
+        let config_for = |number| {
+            let (freq_0, freq_1) = Channel::new(number).unwrap().frequencies();
+            ModulationConfig {
+                freq_0,
+                freq_1,
+                ..ModulationConfig::default()
+            }
+        };
+
+        let ours = FskModulator::new(config_for(2)).encode_bytes(b"channel two");
+        let theirs = FskModulator::new(config_for(3)).encode_bytes(b"channel 3!!");
+        assert_eq!(ours.len(), theirs.len());
+        let mixed: Vec<f32> = ours.iter().zip(&theirs).map(|(a, b)| a + b).collect();
+
+        let decoded = FskDemodulator::new(config_for(2))
+            .decode_bytes(&mixed)
+            .unwrap();
+        assert_eq!(decoded, b"channel two");
+    }
+
+    #[test]
+    fn test_soft_decode_marks_weak_symbols_as_erasures() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let demodulator = FskDemodulator::new(config);
+        let sps = demodulator.samples_per_symbol();
+
+        // 0b1010_0000 with the third and fourth symbols silenced
+        let mut samples = modulator.encode_bytes(&[0xA0]);
+        samples[2 * sps..4 * sps].fill(0.0);
+
+        assert!(demodulator.decode_samples(&samples).is_err());
+
+        let soft = demodulator.soft_decode_samples(&samples).unwrap();
+        assert_eq!(soft.len(), 8);
+        assert!(soft[0].llr() > 1.0);
+        assert!(soft[1].llr() < -1.0);
+        assert!(soft[2].is_erasure() && soft[3].is_erasure());
+        assert_eq!(soft[3].hard(), None);
+        assert!(soft[4..].iter().all(|bit| bit.hard() == Some(false)));
+    }
+
+    #[test]
+    fn test_soft_bit_confidence_falls_with_noise() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let demodulator = FskDemodulator::new(config);
+
+        let clean = modulator.encode_bytes(&[0xFF]);
+        let mut rng = StdRng::seed_from_u64(39);
+        let noisy: Vec<f32> = clean
+            .iter()
+            .map(|&s| s + rng.gen_range(-3.0..3.0))
+            .collect();
+
+        let mean_llr = |samples: &[f32]| {
+            let soft = demodulator.soft_decode_samples(samples).unwrap();
+            soft.iter().map(SoftBit::llr).sum::<f32>() / soft.len() as f32
+        };
+        let (clean_llr, noisy_llr) = (mean_llr(&clean), mean_llr(&noisy));
+        assert!(noisy_llr > 0.0);
+        assert!(clean_llr > noisy_llr, "{} vs {}", clean_llr, noisy_llr);
+    }
+
+    #[test]
+    fn test_pre_emphasis_and_tone_balance() {
+        let config = ModulationConfig::default();
+        let mut demodulator = FskDemodulator::new(config.clone());
+        let sps = demodulator.samples_per_symbol();
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+
+        // freq_1 12 dB down, as from a speaker rolling off towards 20 kHz
+        let tilted = FskModulator::new(config).with_pre_emphasis(-12.0);
+        let samples = tilted.encode_bytes(&[0xAA, 0xAA]);
+        assert!((peak(&samples[..sps]) - DEFAULT_AMPLITUDE / 4.0).abs() < 0.01);
+        assert!((peak(&samples[sps..2 * sps]) - DEFAULT_AMPLITUDE).abs() < 0.01);
+
+        let symbols: Vec<SymbolPowers> = samples
+            .chunks_exact(sps)
+            .map(|symbol| demodulator.measure_symbol(symbol))
+            .collect();
+        let balance = tone_balance(&symbols).unwrap();
+        assert!((10.0 * balance.log10() - 12.0).abs() < 0.5, "{}", balance);
+
+        demodulator.set_tone_balance(Some(balance));
+        let powers = demodulator.measure_symbol(&samples[2 * sps..3 * sps]);
+        assert!((powers.power_1 * powers.balance / symbols[1].power_0 - 1.0).abs() < 0.1);
+        assert_eq!(demodulator.decode_bytes(&samples).unwrap(), [0xAA, 0xAA]);
+    }
+
+    #[test]
+    fn test_tone_balance_evens_out_errors() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone()).with_pre_emphasis(-15.0);
+        let mut demodulator = FskDemodulator::new(config);
+        let sps = demodulator.samples_per_symbol();
+
+        let mut rng = StdRng::seed_from_u64(50);
+        let bytes: Vec<u8> = (0..128).map(|_| rng.r#gen()).collect();
+        let noisy: Vec<f32> = modulator
+            .encode_bytes(&bytes)
+            .iter()
+            .map(|&s| s + rng.gen_range(-0.4..0.4))
+            .collect();
+        let bits: Vec<bool> = bytes
+            .iter()
+            .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .collect();
+        let mut errors = |balance: Option<f32>| {
+            demodulator.set_tone_balance(balance);
+            noisy
+                .chunks_exact(sps)
+                .zip(&bits)
+                .filter(|(symbol, bit)| demodulator.measure_symbol(symbol).bit() != **bit)
+                .count()
+        };
+
+        // Noise flips the weak 1s; weighting them up by half the 15 dB
+        // clears nearly all of them, and the full 15 dB flips 0s instead
+        let raw = errors(None);
+        let half = errors(Some(10f32.powf(15.0 / 20.0)));
+        let full = errors(Some(10f32.powf(15.0 / 10.0)));
+        assert!(raw > 20, "{} raw errors", raw);
+        assert!(half <= 2, "{} errors at half weight", half);
+        assert!(full > half, "{} errors at full weight", full);
+    }
 }
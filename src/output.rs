@@ -0,0 +1,285 @@
+//! Machine-readable JSON Lines output for receive events
+//!
+//! Every [`ReceiverEvent`] maps to one [`EventRecord`], written as a single
+//! line of JSON so other programs can consume `ush listen` and `ush chat`
+//! output without scraping log text.
+
+use crate::base64;
+use crate::identity::{Sender, to_hex};
+use crate::protocol::{Message, MessageType};
+use crate::receiver::ReceiverEvent;
+use crate::stream::SignalQuality;
+use crate::{UshError, UshResult};
+use serde::Serialize;
+
+/// What happened, as reported in the `event` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Message,
+    Ack,
+    Ping,
+    Rejected,
+    DecodeFailure,
+    SignalDetected,
+}
+
+/// How the `payload` field is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadEncoding {
+    Text,
+    Base64,
+}
+
+/// Outcome of signature verification, as reported in the `signature` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Unsigned,
+    Verified,
+    Untrusted,
+    Invalid,
+}
+
+/// One line of JSON output
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub event: EventKind,
+    /// Local time the event was reported (RFC 3339)
+    pub timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence_number: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_type: Option<MessageType>,
+    /// Unix time the sender stamped into the frame header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<u64>,
+    /// Node ids and group from the frame header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureStatus>,
+    /// Trust store name of a verified sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Hex public key of a signed message's sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_encoding: Option<PayloadEncoding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<usize>,
+    /// Why a frame was rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snr_db: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_offset_hz: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_offset_ppm: Option<f32>,
+}
+
+impl EventRecord {
+    fn new(event: EventKind) -> Self {
+        Self {
+            event,
+            timestamp: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            sequence_number: None,
+            message_type: None,
+            sent_at: None,
+            source: None,
+            destination: None,
+            group: None,
+            signature: None,
+            sender: None,
+            signer_key: None,
+            payload: None,
+            payload_encoding: None,
+            sample_offset: None,
+            bytes: None,
+            error: None,
+            snr_db: None,
+            frequency_offset_hz: None,
+            frequency_offset_ppm: None,
+        }
+    }
+
+    pub fn from_event(event: &ReceiverEvent) -> Self {
+        match event {
+            ReceiverEvent::SignalDetected { sample_offset } => {
+                let mut record = Self::new(EventKind::SignalDetected);
+                record.sample_offset = Some(*sample_offset);
+                record
+            }
+            ReceiverEvent::Message {
+                message,
+                sender,
+                quality,
+            } => Self::from_message(message, sender, quality),
+            ReceiverEvent::Rejected { reason, quality } => {
+                let mut record = Self::new(EventKind::Rejected);
+                record.error = Some(reason.clone());
+                record.with_quality(quality)
+            }
+            ReceiverEvent::DecodeFailed {
+                sample_offset,
+                bytes,
+                quality,
+            } => {
+                let mut record = Self::new(EventKind::DecodeFailure);
+                record.sample_offset = Some(*sample_offset);
+                record.bytes = Some(*bytes);
+                record.with_quality(quality)
+            }
+        }
+    }
+
+    fn from_message(message: &Message, sender: &Sender, quality: &SignalQuality) -> Self {
+        let kind = match message.header.message_type {
+            MessageType::Ack => EventKind::Ack,
+            MessageType::Ping => EventKind::Ping,
+            MessageType::Text
+            | MessageType::File
+            | MessageType::PairRequest
+            | MessageType::PairResponse => EventKind::Message,
+        };
+
+        let mut record = Self::new(kind);
+        record.sequence_number = Some(message.header.sequence_number);
+        record.message_type = Some(message.header.message_type.clone());
+        record.sent_at = Some(message.header.timestamp);
+        record.source = message.header.source;
+        record.destination = message.header.destination;
+        record.group = message.header.group;
+        record.signature = Some(match sender {
+            Sender::Unsigned => SignatureStatus::Unsigned,
+            Sender::Verified(_) => SignatureStatus::Verified,
+            Sender::Untrusted(_) => SignatureStatus::Untrusted,
+            Sender::Invalid => SignatureStatus::Invalid,
+        });
+        if let Sender::Verified(name) = sender {
+            record.sender = Some(name.clone());
+        }
+        if matches!(sender, Sender::Verified(_) | Sender::Untrusted(_)) {
+            record.signer_key = message.header.signer.as_ref().map(|key| to_hex(key));
+        }
+
+        if !message.payload.is_empty() {
+            let text = match message.header.message_type {
+                MessageType::Text | MessageType::Ping => std::str::from_utf8(&message.payload).ok(),
+                _ => None,
+            };
+            let (payload, encoding) = match text {
+                Some(text) => (text.to_string(), PayloadEncoding::Text),
+                None => (base64::encode(&message.payload), PayloadEncoding::Base64),
+            };
+            record.payload = Some(payload);
+            record.payload_encoding = Some(encoding);
+        }
+
+        record.with_quality(quality)
+    }
+
+    fn with_quality(mut self, quality: &SignalQuality) -> Self {
+        if quality.symbols > 0 {
+            self.snr_db = Some(quality.snr_db);
+            self.frequency_offset_hz = Some(quality.frequency_offset_hz);
+            self.frequency_offset_ppm = Some(quality.frequency_offset_ppm);
+        }
+        self
+    }
+
+    /// Serialize as a single line of JSON, without the trailing newline
+    pub fn to_json_line(&self) -> UshResult<String> {
+        serde_json::to_string(self).map_err(|e| UshError::Encoding {
+            message: format!("Failed to serialize event: {}", e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quality() -> SignalQuality {
+        SignalQuality {
+            snr_db: 24.5,
+            frequency_offset_hz: -3.0,
+            frequency_offset_ppm: -150.0,
+            tone_balance_db: 0.0,
+            symbols: 120,
+        }
+    }
+
+    #[test]
+    fn test_text_message_record() {
+        let message = Message::new_text("hello", 7).unwrap();
+        let event = ReceiverEvent::Message {
+            message,
+            sender: Sender::Verified("alice".to_string()),
+            quality: quality(),
+        };
+
+        let line = EventRecord::from_event(&event).to_json_line().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(value["event"], "message");
+        assert_eq!(value["sequence_number"], 7);
+        assert_eq!(value["message_type"], "Text");
+        assert_eq!(value["payload"], "hello");
+        assert_eq!(value["payload_encoding"], "text");
+        assert_eq!(value["signature"], "verified");
+        assert_eq!(value["sender"], "alice");
+        assert_eq!(value["snr_db"], 24.5);
+        assert_eq!(value["frequency_offset_hz"], -3.0);
+        assert_eq!(value["frequency_offset_ppm"], -150.0);
+        assert!(value["timestamp"].is_string());
+    }
+
+    #[test]
+    fn test_binary_payload_is_base64() {
+        let mut message = Message::new_text("", 1).unwrap();
+        message.header.message_type = MessageType::File;
+        message.payload = vec![0xff, 0x00, 0x7e];
+
+        let record = EventRecord::from_message(&message, &Sender::Unsigned, &quality());
+
+        assert_eq!(record.payload.as_deref(), Some("/wB+"));
+        assert_eq!(record.payload_encoding, Some(PayloadEncoding::Base64));
+    }
+
+    #[test]
+    fn test_ack_and_failure_records() {
+        let ack = ReceiverEvent::Message {
+            message: Message::new_ack(4).unwrap(),
+            sender: Sender::Unsigned,
+            quality: quality(),
+        };
+        let record = EventRecord::from_event(&ack);
+        assert_eq!(record.event, EventKind::Ack);
+        assert_eq!(record.sequence_number, Some(4));
+
+        let failure = ReceiverEvent::DecodeFailed {
+            sample_offset: 1000,
+            bytes: 3,
+            quality: SignalQuality::default(),
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&EventRecord::from_event(&failure).to_json_line().unwrap())
+                .unwrap();
+        assert_eq!(value["event"], "decode_failure");
+        assert_eq!(value["sample_offset"], 1000);
+        assert!(value.get("snr_db").is_none());
+    }
+}
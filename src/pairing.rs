@@ -0,0 +1,268 @@
+//! `ush pair`: ephemeral X25519 key exchange over the acoustic channel
+//!
+//! The initiator sends a [`MessageType::PairRequest`] carrying its public key
+//! and repeats it until a [`MessageType::PairResponse`] comes back. Both sides
+//! then derive the same session key and a short authentication string (SAS)
+//! with HKDF-SHA256. If the SAS shown on both screens matches, nobody in the
+//! room swapped the public keys.
+
+use crate::audio::AudioLink;
+use crate::crypto::{KEY_LENGTH, PresharedKey};
+use crate::modulation::{FskModulator, ModulationConfig};
+use crate::protocol::{Message, MessageType, ProtocolEncoder};
+use crate::receiver::{MessageReceiver, ReceiverEvent};
+use crate::{UshError, UshResult};
+use hkdf::Hkdf;
+use log::{debug, info, warn};
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const SESSION_KEY_INFO: &[u8] = b"ush session key v1";
+const SAS_INFO: &[u8] = b"ush sas v1";
+const SAS_DIGITS: u32 = 1_000_000;
+/// Silence after each transmission so the receiver sees the signal end
+const TRAILING_SILENCE_SECONDS: f32 = 0.1;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingRole {
+    Initiator,
+    Responder,
+}
+
+#[derive(Debug, Clone)]
+pub struct PairingConfig {
+    /// Times the initiator sends its request before giving up
+    pub attempts: u32,
+    /// How long to wait for the other side after each transmission
+    pub response_timeout: Duration,
+    /// How long the responder keeps answering repeated requests, in case its
+    /// response was not heard
+    pub linger: Duration,
+    pub threshold: f32,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        // A key exchange frame takes about 25 s of airtime at 100 bit/s
+        Self {
+            attempts: 3,
+            response_timeout: Duration::from_secs(60),
+            linger: Duration::from_secs(60),
+            threshold: 0.1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PairingOutcome {
+    pub session_key: PresharedKey,
+    /// Six digits, e.g. `"042 917"`, for the users to compare
+    pub sas: String,
+}
+
+struct Handshake<'a, L: AudioLink> {
+    link: &'a mut L,
+    modulator: FskModulator,
+    encoder: ProtocolEncoder,
+    receiver: MessageReceiver,
+    public_key: [u8; 32],
+    trailing_silence: usize,
+}
+
+impl<L: AudioLink> Handshake<'_, L> {
+    async fn send(&mut self, message_type: MessageType) -> UshResult<()> {
+        let sequence_number = self.encoder.next_sequence_number();
+        let message = match message_type {
+            MessageType::PairRequest => {
+                Message::new_pair_request(&self.public_key, sequence_number)?
+            }
+            _ => Message::new_pair_response(&self.public_key, sequence_number)?,
+        };
+
+        let mut samples = self
+            .modulator
+            .encode_bytes(&self.encoder.encode_message(&message)?);
+        samples.extend(std::iter::repeat_n(0.0, self.trailing_silence));
+
+        debug!("Sending {:?} (sequence {})", message_type, sequence_number);
+        self.link.transmit(&samples).await
+    }
+
+    /// Wait for a public key carried by a message of the given type
+    async fn receive(
+        &mut self,
+        message_type: MessageType,
+        timeout: Duration,
+    ) -> UshResult<Option<[u8; 32]>> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            let samples = self.link.capture().await?;
+            if samples.is_empty() {
+                sleep(POLL_INTERVAL).await;
+                continue;
+            }
+
+            for event in self.receiver.push_samples(&samples) {
+                let ReceiverEvent::Message { message, .. } = event else {
+                    continue;
+                };
+                if message.header.message_type != message_type {
+                    debug!("Ignoring {:?} while pairing", message.header.message_type);
+                    continue;
+                }
+                match <[u8; 32]>::try_from(message.payload.as_slice()) {
+                    Ok(key) => return Ok(Some(key)),
+                    Err(_) => warn!(
+                        "Ignoring {:?} with a {} byte key",
+                        message_type,
+                        message.payload.len()
+                    ),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Run one side of the handshake over `link`
+pub async fn pair<L: AudioLink>(
+    link: &mut L,
+    role: PairingRole,
+    modulation: ModulationConfig,
+    config: &PairingConfig,
+) -> UshResult<PairingOutcome> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+
+    let mut handshake = Handshake {
+        trailing_silence: (modulation.sample_rate as f32 * TRAILING_SILENCE_SECONDS) as usize,
+        link,
+        modulator: FskModulator::new(modulation.clone()),
+        encoder: ProtocolEncoder::new(),
+        receiver: MessageReceiver::new(modulation, config.threshold),
+        public_key: public_key.to_bytes(),
+    };
+
+    let peer_key = match role {
+        PairingRole::Initiator => {
+            let mut peer_key = None;
+            for attempt in 1..=config.attempts {
+                info!("Sending pairing request ({}/{})", attempt, config.attempts);
+                handshake.send(MessageType::PairRequest).await?;
+
+                peer_key = handshake
+                    .receive(MessageType::PairResponse, config.response_timeout)
+                    .await?;
+                if peer_key.is_some() {
+                    break;
+                }
+            }
+            peer_key.ok_or(UshError::Timeout)?
+        }
+        PairingRole::Responder => {
+            info!("Waiting for a pairing request");
+            let timeout = config.response_timeout * config.attempts;
+            let peer_key = handshake
+                .receive(MessageType::PairRequest, timeout)
+                .await?
+                .ok_or(UshError::Timeout)?;
+            handshake.send(MessageType::PairResponse).await?;
+
+            // The initiator repeats its request if our response got lost
+            while let Some(repeated) = handshake
+                .receive(MessageType::PairRequest, config.linger)
+                .await?
+            {
+                if repeated == peer_key {
+                    info!("Pairing request repeated, answering again");
+                    handshake.send(MessageType::PairResponse).await?;
+                } else {
+                    warn!("Ignoring pairing request from a second device");
+                }
+            }
+            peer_key
+        }
+    };
+
+    let shared = secret.diffie_hellman(&PublicKey::from(peer_key));
+    if !shared.was_contributory() {
+        return Err(UshError::Authentication {
+            message: "peer sent a low-order public key".to_string(),
+        });
+    }
+
+    let (initiator_key, responder_key) = match role {
+        PairingRole::Initiator => (public_key.to_bytes(), peer_key),
+        PairingRole::Responder => (peer_key, public_key.to_bytes()),
+    };
+    derive_session(shared.as_bytes(), &initiator_key, &responder_key)
+}
+
+/// Session key and SAS, bound to both public keys in a fixed order
+fn derive_session(
+    shared_secret: &[u8; 32],
+    initiator_key: &[u8; 32],
+    responder_key: &[u8; 32],
+) -> UshResult<PairingOutcome> {
+    let salt = [initiator_key.as_slice(), responder_key.as_slice()].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+
+    let expand_error = |_| UshError::Protocol {
+        message: "Failed to derive session key".to_string(),
+    };
+    let mut session_key = [0u8; KEY_LENGTH];
+    hkdf.expand(SESSION_KEY_INFO, &mut session_key)
+        .map_err(expand_error)?;
+    let mut sas = [0u8; 4];
+    hkdf.expand(SAS_INFO, &mut sas).map_err(expand_error)?;
+
+    let code = u32::from_be_bytes(sas) % SAS_DIGITS;
+    Ok(PairingOutcome {
+        session_key: PresharedKey::from_bytes(session_key),
+        sas: format!("{:03} {:03}", code / 1000, code % 1000),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::memory_link;
+
+    #[tokio::test]
+    async fn test_pairing_over_memory_link() {
+        let (mut a, mut b) = memory_link();
+        let config = PairingConfig {
+            attempts: 2,
+            response_timeout: Duration::from_secs(30),
+            linger: Duration::ZERO,
+            ..PairingConfig::default()
+        };
+        let modulation = ModulationConfig::default();
+
+        let (initiator, responder) = tokio::join!(
+            pair(&mut a, PairingRole::Initiator, modulation.clone(), &config),
+            pair(&mut b, PairingRole::Responder, modulation, &config),
+        );
+        let (initiator, responder) = (initiator.unwrap(), responder.unwrap());
+
+        assert_eq!(initiator.sas, responder.sas);
+        assert_eq!(initiator.sas.len(), 7);
+        assert_eq!(initiator.session_key.id(), responder.session_key.id());
+    }
+
+    #[test]
+    fn test_substituted_key_changes_sas() {
+        let shared = [9u8; 32];
+        let honest = derive_session(&shared, &[1; 32], &[2; 32]).unwrap();
+        let swapped = derive_session(&shared, &[1; 32], &[3; 32]).unwrap();
+
+        assert_ne!(honest.sas, swapped.sas);
+        assert_ne!(honest.session_key.id(), swapped.session_key.id());
+    }
+}
@@ -1,21 +1,59 @@
+use crate::crypto::PresharedKey;
+use crate::identity::Identity;
 use crate::{UshError, UshResult};
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
 use crc::{CRC_32_ISO_HDLC, Crc};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const PROTOCOL_VERSION: u8 = 1;
 const PREAMBLE: &[u8] = &[0xAA, 0xAA, 0xAA, 0xAA]; // Alternating pattern for sync
 const START_DELIMITER: &[u8] = &[0x7E, 0x7E]; // Frame start marker
 const END_DELIMITER: &[u8] = &[0x7E, 0x7E]; // Frame end marker
-const MAX_MESSAGE_LENGTH: usize = 1024;
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024; // Largest payload before fragmentation
+const MAX_FRAME_LENGTH: usize = 2048; // Largest serialized frame the decoder accepts
+const AEAD_TAG_LENGTH: usize = 16;
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(300); // Silence before a partial message is dropped
+const MAX_PARTIAL_MESSAGES: usize = 16; // Fragmented messages reassembled at once
+const DUPLICATE_HISTORY: usize = 64; // Recently seen frames remembered for deduplication
+const REPLAY_HISTORY: usize = 4096; // Accepted frames remembered per peer for replay detection
+
+/// Payload bytes per frame. Larger messages are fragmented, and byte streams
+/// split, at this size. Payloads serialize to up to four JSON characters per
+/// byte, so this keeps each frame inside the length the decoder accepts.
+pub const DATA_CHUNK_SIZE: usize = 256;
+
+/// Destination node id that every node accepts
+pub const BROADCAST: u16 = 0xFFFF;
+
+/// Largest difference between a frame's timestamp and the local clock that
+/// a receiver accepts by default
+pub const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(120);
+
+/// Bytes every frame starts with: the doubled preamble and the start delimiter
+pub(crate) fn frame_sync() -> Vec<u8> {
+    [PREAMBLE, PREAMBLE, START_DELIMITER].concat()
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Length of a whole frame whose length field announces `message_length`
+pub(crate) fn frame_length(message_length: usize) -> usize {
+    2 * PREAMBLE.len() + START_DELIMITER.len() + 2 + message_length + END_DELIMITER.len()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageType {
     Text,
     File,
     Ack,
     Ping,
+    /// Opens a `ush pair` handshake; the payload is an X25519 public key
+    PairRequest,
+    /// Answers a [`MessageType::PairRequest`] with the responder's public key
+    PairResponse,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,7 +62,64 @@ pub struct MessageHeader {
     pub message_type: MessageType,
     pub sequence_number: u32,
     pub timestamp: u64,
-    pub payload_length: u16,
+    pub payload_length: u32,
+    /// Present only on frames that carry part of a larger message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fragment: Option<FragmentInfo>,
+    /// Payload is AES-256-GCM ciphertext followed by its tag
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    /// Which pre-shared key the payload was encrypted with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<u32>,
+    /// Random leading bytes of the AES-GCM nonce, drawn for each encryption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce_prefix: Option<u32>,
+    /// Ed25519 public key of the sender, present on signed messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<[u8; 32]>,
+    /// Node id of the sender, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<u16>,
+    /// Node id of the intended receiver; absent or [`BROADCAST`] for everyone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<u16>,
+    /// Group sharing the channel; frames only reach nodes in the same group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u16>,
+}
+
+/// Where a node sits on a shared channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Address {
+    /// This node's id, stamped as `source` on outgoing frames
+    pub node_id: Option<u16>,
+    pub group: Option<u16>,
+}
+
+impl Address {
+    /// Whether a frame is meant for this node: addressed to it or broadcast,
+    /// and in the same group
+    pub fn accepts(&self, header: &MessageHeader) -> bool {
+        let for_us = match header.destination {
+            None | Some(BROADCAST) => true,
+            Some(destination) => self.node_id == Some(destination),
+        };
+        for_us && header.group == self.group
+    }
+}
+
+/// Position of a frame within a fragmented message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FragmentInfo {
+    /// Shared by every fragment of one message
+    pub message_id: u32,
+    pub index: u16,
+    pub more_fragments: bool,
+    /// Length of the whole payload
+    pub total_length: u32,
+    /// CRC-32 of the whole payload, checked once it is put back together
+    pub total_checksum: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,12 +127,43 @@ pub struct Message {
     pub header: MessageHeader,
     pub payload: Vec<u8>,
     pub checksum: u32,
+    /// Ed25519 signature over the plaintext header and payload. On a
+    /// fragmented message only the last fragment carries it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
 }
 
 impl Message {
     pub fn new_text(text: &str, sequence_number: u32) -> UshResult<Self> {
-        let payload = text.as_bytes().to_vec();
+        Self::with_payload(MessageType::Text, text.as_bytes().to_vec(), sequence_number)
+    }
 
+    /// Arbitrary binary payload, carried as a `File` message
+    pub fn new_data(data: &[u8], sequence_number: u32) -> UshResult<Self> {
+        Self::with_payload(MessageType::File, data.to_vec(), sequence_number)
+    }
+
+    pub fn new_pair_request(public_key: &[u8; 32], sequence_number: u32) -> UshResult<Self> {
+        Self::with_payload(
+            MessageType::PairRequest,
+            public_key.to_vec(),
+            sequence_number,
+        )
+    }
+
+    pub fn new_pair_response(public_key: &[u8; 32], sequence_number: u32) -> UshResult<Self> {
+        Self::with_payload(
+            MessageType::PairResponse,
+            public_key.to_vec(),
+            sequence_number,
+        )
+    }
+
+    fn with_payload(
+        message_type: MessageType,
+        payload: Vec<u8>,
+        sequence_number: u32,
+    ) -> UshResult<Self> {
         if payload.len() > MAX_MESSAGE_LENGTH {
             return Err(UshError::Protocol {
                 message: format!(
@@ -50,13 +176,21 @@ impl Message {
 
         let header = MessageHeader {
             version: PROTOCOL_VERSION,
-            message_type: MessageType::Text,
+            message_type,
             sequence_number,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
-            payload_length: payload.len() as u16,
+            payload_length: payload.len() as u32,
+            fragment: None,
+            encrypted: false,
+            key_id: None,
+            nonce_prefix: None,
+            signer: None,
+            source: None,
+            destination: None,
+            group: None,
         };
 
         let checksum = Self::calculate_checksum(&header, &payload)?;
@@ -65,6 +199,7 @@ impl Message {
             header,
             payload,
             checksum,
+            signature: None,
         })
     }
 
@@ -80,6 +215,14 @@ impl Message {
                 .unwrap()
                 .as_secs(),
             payload_length: 0,
+            fragment: None,
+            encrypted: false,
+            key_id: None,
+            nonce_prefix: None,
+            signer: None,
+            source: None,
+            destination: None,
+            group: None,
         };
 
         let checksum = Self::calculate_checksum(&header, &payload)?;
@@ -88,6 +231,7 @@ impl Message {
             header,
             payload,
             checksum,
+            signature: None,
         })
     }
 
@@ -102,7 +246,15 @@ impl Message {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
-            payload_length: payload.len() as u16,
+            payload_length: payload.len() as u32,
+            fragment: None,
+            encrypted: false,
+            key_id: None,
+            nonce_prefix: None,
+            signer: None,
+            source: None,
+            destination: None,
+            group: None,
         };
 
         let checksum = Self::calculate_checksum(&header, &payload)?;
@@ -111,10 +263,11 @@ impl Message {
             header,
             payload,
             checksum,
+            signature: None,
         })
     }
 
-    fn calculate_checksum(header: &MessageHeader, payload: &[u8]) -> UshResult<u32> {
+    pub(crate) fn calculate_checksum(header: &MessageHeader, payload: &[u8]) -> UshResult<u32> {
         let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
         // Serialize header to bytes for checksum calculation
@@ -129,6 +282,164 @@ impl Message {
         Ok(digest.finalize())
     }
 
+    /// Split into frames of at most [`DATA_CHUNK_SIZE`] payload bytes that
+    /// [`ProtocolDecoder`] puts back together
+    fn into_fragments(self, message_id: u32) -> UshResult<Vec<Message>> {
+        let chunks: Vec<&[u8]> = self.payload.chunks(DATA_CHUNK_SIZE).collect();
+        if chunks.len() > u16::MAX as usize + 1 {
+            return Err(UshError::Protocol {
+                message: format!("Message too long to fragment: {} bytes", self.payload.len()),
+            });
+        }
+
+        let last = chunks.len() - 1;
+        let total_length = self.payload.len() as u32;
+        let total_checksum = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&self.payload);
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let header = MessageHeader {
+                    payload_length: chunk.len() as u32,
+                    fragment: Some(FragmentInfo {
+                        message_id,
+                        index: index as u16,
+                        more_fragments: index < last,
+                        total_length,
+                        total_checksum,
+                    }),
+                    ..self.header.clone()
+                };
+                let payload = chunk.to_vec();
+                let checksum = Self::calculate_checksum(&header, &payload)?;
+
+                Ok(Message {
+                    header,
+                    payload,
+                    checksum,
+                    signature: if index == last {
+                        self.signature.clone()
+                    } else {
+                        None
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Encrypt the payload. The header is authenticated as associated data,
+    /// and the nonce is a fresh random prefix followed by the sequence number
+    /// and timestamp, so replies that reuse a peer's sequence number, or
+    /// devices whose counters collide, never repeat one.
+    pub fn encrypt(&self, key: &PresharedKey) -> UshResult<Message> {
+        let prefix = rand::random();
+        let mut header = MessageHeader {
+            encrypted: true,
+            key_id: Some(key.id()),
+            nonce_prefix: Some(prefix),
+            fragment: None,
+            ..self.header.clone()
+        };
+        header.payload_length = (self.payload.len() + AEAD_TAG_LENGTH) as u32;
+
+        let aad = Self::associated_data(&header)?;
+        let payload = Aes256Gcm::new(key.as_bytes().into())
+            .encrypt(
+                &Self::nonce(&header, prefix),
+                Payload {
+                    msg: &self.payload,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| UshError::Encoding {
+                message: "Failed to encrypt payload".to_string(),
+            })?;
+        let checksum = Self::calculate_checksum(&header, &payload)?;
+
+        Ok(Message {
+            header,
+            payload,
+            checksum,
+            signature: self.signature.clone(),
+        })
+    }
+
+    /// Check and strip the encryption added by [`Message::encrypt`]
+    pub fn decrypt(&self, key: &PresharedKey) -> UshResult<Message> {
+        if !self.header.encrypted {
+            return Err(UshError::Protocol {
+                message: "Message is not encrypted".to_string(),
+            });
+        }
+        if self.header.key_id != Some(key.id()) {
+            return Err(UshError::Authentication {
+                message: format!(
+                    "sequence {} was encrypted with key {}, expected {:08x}",
+                    self.header.sequence_number,
+                    self.header
+                        .key_id
+                        .map_or("(none)".to_string(), |id| format!("{:08x}", id)),
+                    key.id()
+                ),
+            });
+        }
+
+        let Some(prefix) = self.header.nonce_prefix else {
+            return Err(UshError::Authentication {
+                message: format!(
+                    "sequence {} is encrypted without a nonce prefix",
+                    self.header.sequence_number
+                ),
+            });
+        };
+
+        let aad = Self::associated_data(&self.header)?;
+        let payload = Aes256Gcm::new(key.as_bytes().into())
+            .decrypt(
+                &Self::nonce(&self.header, prefix),
+                Payload {
+                    msg: &self.payload,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| UshError::Authentication {
+                message: format!(
+                    "sequence {} failed integrity check (tampered or wrong key)",
+                    self.header.sequence_number
+                ),
+            })?;
+
+        let header = MessageHeader {
+            payload_length: payload.len() as u32,
+            encrypted: false,
+            key_id: None,
+            nonce_prefix: None,
+            ..self.header.clone()
+        };
+        let checksum = Self::calculate_checksum(&header, &payload)?;
+
+        Ok(Message {
+            header,
+            payload,
+            checksum,
+            signature: self.signature.clone(),
+        })
+    }
+
+    fn nonce(header: &MessageHeader, prefix: u32) -> Nonce<U12> {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&prefix.to_be_bytes());
+        nonce[4..8].copy_from_slice(&header.sequence_number.to_be_bytes());
+        nonce[8..].copy_from_slice(&(header.timestamp as u32).to_be_bytes());
+        nonce.into()
+    }
+
+    fn associated_data(header: &MessageHeader) -> UshResult<Vec<u8>> {
+        serde_json::to_vec(header).map_err(|e| UshError::Protocol {
+            message: format!("Failed to serialize header: {}", e),
+        })
+    }
+
     pub fn verify_checksum(&self) -> UshResult<bool> {
         let calculated = Self::calculate_checksum(&self.header, &self.payload)?;
         Ok(calculated == self.checksum)
@@ -151,16 +462,129 @@ impl Message {
 #[derive(Debug)]
 pub struct ProtocolEncoder {
     sequence_counter: u32,
+    next_message_id: u32,
+    key: Option<PresharedKey>,
+    identity: Option<Identity>,
+    address: Address,
+    destination: Option<u16>,
 }
 
 impl ProtocolEncoder {
     pub fn new() -> Self {
         Self {
             sequence_counter: 0,
+            // Random so fragments from separate senders or runs don't mix
+            next_message_id: rand::random(),
+            key: None,
+            identity: None,
+            address: Address::default(),
+            destination: None,
         }
     }
 
+    /// Stamp our node id and group on every message
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Address every message to one node instead of broadcasting
+    pub fn with_destination(mut self, destination: u16) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Sign every message with this identity
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Encrypt every message with a pre-shared key.
+    ///
+    /// The counter starts at a random value, so two runs within the same
+    /// second don't send the same sequence numbers.
+    pub fn with_key(mut self, key: PresharedKey) -> Self {
+        self.sequence_counter = rand::random();
+        self.key = Some(key);
+        self
+    }
+
+    /// Encode a message, fragmenting it into back-to-back frames if its
+    /// payload is larger than [`DATA_CHUNK_SIZE`]
     pub fn encode_message(&mut self, message: &Message) -> UshResult<Vec<u8>> {
+        let addressed;
+        let message = match self.address_message(message)? {
+            Some(message) => {
+                addressed = message;
+                &addressed
+            }
+            None => message,
+        };
+
+        // Sign the plaintext first so the signature survives decryption
+        let signed;
+        let message = match &self.identity {
+            Some(identity) if message.signature.is_none() && !message.header.encrypted => {
+                signed = identity.sign(message)?;
+                &signed
+            }
+            _ => message,
+        };
+
+        let encrypted;
+        let message = match &self.key {
+            Some(key) if !message.header.encrypted => {
+                encrypted = message.encrypt(key)?;
+                &encrypted
+            }
+            _ => message,
+        };
+
+        if message.payload.len() <= DATA_CHUNK_SIZE {
+            return self.encode_frame(message);
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        let fragments = message.clone().into_fragments(message_id)?;
+        debug!(
+            "Fragmenting {} byte message into {} frames (id {:08x})",
+            message.payload.len(),
+            fragments.len(),
+            message_id
+        );
+
+        let mut frames = Vec::new();
+        for fragment in &fragments {
+            frames.extend(self.encode_frame(fragment)?);
+        }
+        Ok(frames)
+    }
+
+    /// Fill in addressing the message doesn't set itself; sealed (signed or
+    /// encrypted) messages are left alone
+    fn address_message(&self, message: &Message) -> UshResult<Option<Message>> {
+        let header = &message.header;
+        let sealed = message.signature.is_some() || header.encrypted;
+        let missing = (header.source.is_none() && self.address.node_id.is_some())
+            || (header.group.is_none() && self.address.group.is_some())
+            || (header.destination.is_none() && self.destination.is_some());
+        if sealed || !missing {
+            return Ok(None);
+        }
+
+        let mut addressed = message.clone();
+        let header = &mut addressed.header;
+        header.source = header.source.or(self.address.node_id);
+        header.group = header.group.or(self.address.group);
+        header.destination = header.destination.or(self.destination);
+        addressed.checksum = Message::calculate_checksum(&addressed.header, &addressed.payload)?;
+        Ok(Some(addressed))
+    }
+
+    fn encode_frame(&self, message: &Message) -> UshResult<Vec<u8>> {
         let mut frame = Vec::new();
 
         // Add preamble for synchronization
@@ -175,6 +599,16 @@ impl ProtocolEncoder {
             message: format!("Failed to serialize message: {}", e),
         })?;
 
+        if message_bytes.len() > MAX_FRAME_LENGTH {
+            return Err(UshError::Encoding {
+                message: format!(
+                    "Frame too long: {} bytes (max: {})",
+                    message_bytes.len(),
+                    MAX_FRAME_LENGTH
+                ),
+            });
+        }
+
         // Add message length (for framing)
         let length = message_bytes.len() as u16;
         frame.extend_from_slice(&length.to_be_bytes());
@@ -190,11 +624,28 @@ impl ProtocolEncoder {
     }
 
     pub fn encode_text(&mut self, text: &str) -> UshResult<Vec<u8>> {
-        let message = Message::new_text(text, self.sequence_counter)?;
-        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        let message = Message::new_text(text, self.next_sequence_number())?;
         self.encode_message(&message)
     }
 
+    /// Split a byte stream into `File` frames of at most [`DATA_CHUNK_SIZE`]
+    /// bytes each, returned back to back in transmission order
+    pub fn encode_data(&mut self, data: &[u8]) -> UshResult<Vec<u8>> {
+        let mut frames = Vec::new();
+        for chunk in data.chunks(DATA_CHUNK_SIZE) {
+            let message = Message::new_data(chunk, self.next_sequence_number())?;
+            frames.extend(self.encode_message(&message)?);
+        }
+        Ok(frames)
+    }
+
+    /// Claim a sequence number for a message built by the caller
+    pub fn next_sequence_number(&mut self) -> u32 {
+        let sequence_number = self.sequence_counter;
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        sequence_number
+    }
+
     pub fn get_next_sequence_number(&self) -> u32 {
         self.sequence_counter
     }
@@ -211,10 +662,64 @@ pub struct ProtocolDecoder {
     buffer: Vec<u8>,
     state: DecoderState,
     expected_length: usize,
+    partial: HashMap<u32, PartialMessage>,
+    fragment_timeout: Duration,
+    key: Option<PresharedKey>,
+    /// Bytes dropped from the front of `buffer` so far
+    consumed: u64,
+    trace: Option<Vec<DecoderTransition>>,
+}
+
+/// Fragments of one message received so far
+#[derive(Debug)]
+struct PartialMessage {
+    /// Header of fragment 0, once it has arrived
+    header: Option<MessageHeader>,
+    total_length: u32,
+    total_checksum: u32,
+    fragments: BTreeMap<u16, Vec<u8>>,
+    /// Payload bytes held in `fragments`
+    received: usize,
+    last_index: Option<u16>,
+    last_update: Instant,
+    signature: Option<Vec<u8>>,
+}
+
+impl PartialMessage {
+    fn new(info: &FragmentInfo) -> Self {
+        Self {
+            header: None,
+            total_length: info.total_length,
+            total_checksum: info.total_checksum,
+            fragments: BTreeMap::new(),
+            received: 0,
+            last_index: None,
+            last_update: Instant::now(),
+            signature: None,
+        }
+    }
+
+    /// Whether a fragment belongs to this message rather than another one
+    /// that happens to share its id
+    fn matches(&self, info: &FragmentInfo) -> bool {
+        self.total_length == info.total_length && self.total_checksum == info.total_checksum
+    }
+
+    /// Forget fragments past the last one
+    fn truncate(&mut self, last: u16) {
+        let stray = self.fragments.split_off(&(last + 1));
+        self.received -= stray.values().map(Vec::len).sum::<usize>();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.last_index
+            .is_some_and(|last| (0..=last).all(|index| self.fragments.contains_key(&index)))
+    }
 }
 
-#[derive(Debug, PartialEq)]
-enum DecoderState {
+/// Where [`ProtocolDecoder`] is within a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderState {
     WaitingForPreamble,
     WaitingForStart,
     ReadingLength,
@@ -222,17 +727,95 @@ enum DecoderState {
     WaitingForEnd,
 }
 
+impl std::fmt::Display for DecoderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DecoderState::WaitingForPreamble => "waiting for preamble",
+            DecoderState::WaitingForStart => "waiting for start delimiter",
+            DecoderState::ReadingLength => "reading length",
+            DecoderState::ReadingMessage => "reading message",
+            DecoderState::WaitingForEnd => "waiting for end delimiter",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A state change of [`ProtocolDecoder`], recorded when tracing is enabled
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecoderTransition {
+    /// Index of the first unread byte, counted from the first byte ever fed
+    pub byte_offset: u64,
+    pub from: DecoderState,
+    pub to: DecoderState,
+    /// What caused the change, e.g. "preamble found" or "length 212"
+    pub note: String,
+    /// Whether the frame in progress was abandoned
+    pub failure: bool,
+}
+
 impl ProtocolDecoder {
     pub fn new() -> Self {
         Self {
             buffer: Vec::new(),
             state: DecoderState::WaitingForPreamble,
             expected_length: 0,
+            partial: HashMap::new(),
+            fragment_timeout: FRAGMENT_TIMEOUT,
+            key: None,
+            consumed: 0,
+            trace: None,
         }
     }
 
+    /// Record every state transition for [`take_trace`](Self::take_trace)
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    /// Transitions recorded since the last call
+    pub fn take_trace(&mut self) -> Vec<DecoderTransition> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn state(&self) -> DecoderState {
+        self.state
+    }
+
+    /// Bytes of the frame in progress that have been fed but not yet parsed
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Payload length announced by the frame being read
+    pub fn expected_length(&self) -> usize {
+        self.expected_length
+    }
+
+    /// Decrypt messages with a pre-shared key and reject unencrypted ones
+    pub fn with_key(mut self, key: PresharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// How long a partially received message waits for its next fragment
+    pub fn with_fragment_timeout(mut self, timeout: Duration) -> Self {
+        self.fragment_timeout = timeout;
+        self
+    }
+
     pub fn feed_data(&mut self, data: &[u8]) -> Vec<Message> {
+        self.decode(data)
+            .into_iter()
+            .filter_map(|result| result.map_err(|e| warn!("Rejected message: {}", e)).ok())
+            .collect()
+    }
+
+    /// Like [`ProtocolDecoder::feed_data`], but also returns frames that
+    /// arrived intact yet failed decryption as [`UshError::Authentication`]
+    pub fn decode(&mut self, data: &[u8]) -> Vec<UshResult<Message>> {
         self.buffer.extend_from_slice(data);
+        self.expire_fragments();
         let mut messages = Vec::new();
 
         while let Some(message) = self.try_decode_message() {
@@ -240,15 +823,16 @@ impl ProtocolDecoder {
                 Ok(msg) => {
                     if msg.verify_checksum().unwrap_or(false) {
                         debug!("Successfully decoded message: {:?}", msg.header);
-                        messages.push(msg);
+                        if let Some(msg) = self.reassemble(msg) {
+                            messages.push(self.open(msg));
+                        }
                     } else {
                         warn!("Message failed checksum verification");
+                        self.fail("checksum mismatch".to_string());
                     }
                 }
                 Err(e) => {
                     warn!("Failed to decode message: {}", e);
-                    // Reset state on error
-                    self.state = DecoderState::WaitingForPreamble;
                 }
             }
         }
@@ -256,8 +840,8 @@ impl ProtocolDecoder {
         // Keep buffer size reasonable
         if self.buffer.len() > 10000 {
             let keep_size = 5000;
-            self.buffer.drain(..self.buffer.len() - keep_size);
-            self.state = DecoderState::WaitingForPreamble;
+            self.consume(self.buffer.len() - keep_size);
+            self.transition(DecoderState::WaitingForPreamble, "buffer trimmed");
         }
 
         messages
@@ -267,8 +851,8 @@ impl ProtocolDecoder {
         match self.state {
             DecoderState::WaitingForPreamble => {
                 if let Some(pos) = self.find_preamble() {
-                    self.buffer.drain(..pos);
-                    self.state = DecoderState::WaitingForStart;
+                    self.consume(pos);
+                    self.transition(DecoderState::WaitingForStart, "preamble found");
                     return self.try_decode_message();
                 }
                 None
@@ -278,13 +862,14 @@ impl ProtocolDecoder {
                     let start_pos = PREAMBLE.len() * 2;
                     if &self.buffer[start_pos..start_pos + START_DELIMITER.len()] == START_DELIMITER
                     {
-                        self.buffer.drain(..start_pos + START_DELIMITER.len());
-                        self.state = DecoderState::ReadingLength;
+                        self.consume(start_pos + START_DELIMITER.len());
+                        self.transition(DecoderState::ReadingLength, "start delimiter");
                         return self.try_decode_message();
                     } else {
                         // Invalid start, look for next preamble
-                        self.buffer.drain(..1);
-                        self.state = DecoderState::WaitingForPreamble;
+                        let found = self.buffer[start_pos];
+                        self.consume(1);
+                        self.fail(format!("expected start delimiter, found 0x{:02X}", found));
                         return self.try_decode_message();
                     }
                 }
@@ -295,15 +880,19 @@ impl ProtocolDecoder {
                     let length_bytes = [self.buffer[0], self.buffer[1]];
                     self.expected_length = u16::from_be_bytes(length_bytes) as usize;
 
-                    if self.expected_length > MAX_MESSAGE_LENGTH * 2 {
+                    if self.expected_length > MAX_FRAME_LENGTH {
                         // Invalid length, reset
-                        self.buffer.drain(..1);
-                        self.state = DecoderState::WaitingForPreamble;
+                        self.consume(1);
+                        self.fail(format!(
+                            "length {} exceeds the {} byte maximum",
+                            self.expected_length, MAX_FRAME_LENGTH
+                        ));
                         return self.try_decode_message();
                     }
 
-                    self.buffer.drain(..2);
-                    self.state = DecoderState::ReadingMessage;
+                    self.consume(2);
+                    let note = format!("length {}", self.expected_length);
+                    self.transition(DecoderState::ReadingMessage, note);
                     return self.try_decode_message();
                 }
                 None
@@ -311,44 +900,27 @@ impl ProtocolDecoder {
             DecoderState::ReadingMessage => {
                 if self.buffer.len() >= self.expected_length {
                     let message_bytes = self.buffer[..self.expected_length].to_vec();
-                    self.buffer.drain(..self.expected_length);
-                    self.state = DecoderState::WaitingForEnd;
+                    self.consume(self.expected_length);
 
                     match serde_json::from_slice::<Message>(&message_bytes) {
                         Ok(message) => {
-                            // Check if we have the end delimiter
-                            if self.buffer.len() >= END_DELIMITER.len()
-                                && &self.buffer[..END_DELIMITER.len()] == END_DELIMITER
-                            {
-                                self.buffer.drain(..END_DELIMITER.len());
-                                self.state = DecoderState::WaitingForPreamble;
-                                return Some(Ok(message));
-                            } else {
-                                // Missing end delimiter, but we have a valid message
-                                warn!("Missing end delimiter, but message appears valid");
-                                self.state = DecoderState::WaitingForPreamble;
-                                return Some(Ok(message));
-                            }
+                            // The end delimiter may not have arrived yet; it
+                            // is checked as soon as it does
+                            self.transition(DecoderState::WaitingForEnd, "message");
+                            self.check_end_delimiter();
+                            return Some(Ok(message));
                         }
                         Err(e) => {
-                            self.state = DecoderState::WaitingForPreamble;
-                            return Some(Err(UshError::Decoding {
-                                message: format!("Failed to deserialize message: {}", e),
-                            }));
+                            let message = format!("Failed to deserialize message: {}", e);
+                            self.fail(message.clone());
+                            return Some(Err(UshError::Decoding { message }));
                         }
                     }
                 }
                 None
             }
             DecoderState::WaitingForEnd => {
-                if self.buffer.len() >= END_DELIMITER.len() {
-                    if &self.buffer[..END_DELIMITER.len()] == END_DELIMITER {
-                        self.buffer.drain(..END_DELIMITER.len());
-                    } else {
-                        // Skip invalid end delimiter
-                        self.buffer.drain(..1);
-                    }
-                    self.state = DecoderState::WaitingForPreamble;
+                if self.check_end_delimiter() {
                     return self.try_decode_message();
                 }
                 None
@@ -356,6 +928,52 @@ impl ProtocolDecoder {
         }
     }
 
+    /// Finish the frame once its end delimiter is buffered; false while
+    /// still waiting for it
+    fn check_end_delimiter(&mut self) -> bool {
+        if self.buffer.len() < END_DELIMITER.len() {
+            return false;
+        }
+        if &self.buffer[..END_DELIMITER.len()] == END_DELIMITER {
+            self.consume(END_DELIMITER.len());
+            self.transition(DecoderState::WaitingForPreamble, "end delimiter");
+        } else {
+            // The message was valid, so keep these bytes in case they start
+            // the next frame
+            warn!("Missing end delimiter, but message appears valid");
+            self.transition(DecoderState::WaitingForPreamble, "missing end delimiter");
+        }
+        true
+    }
+
+    /// Drop `count` bytes from the front of the buffer
+    fn consume(&mut self, count: usize) {
+        self.buffer.drain(..count);
+        self.consumed += count as u64;
+    }
+
+    fn transition(&mut self, to: DecoderState, note: impl Into<String>) {
+        self.record(to, note.into(), false);
+    }
+
+    /// Give up on the current frame and search for the next preamble
+    fn fail(&mut self, reason: String) {
+        self.record(DecoderState::WaitingForPreamble, reason, true);
+    }
+
+    fn record(&mut self, to: DecoderState, note: String, failure: bool) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(DecoderTransition {
+                byte_offset: self.consumed,
+                from: self.state,
+                to,
+                note,
+                failure,
+            });
+        }
+        self.state = to;
+    }
+
     fn find_preamble(&self) -> Option<usize> {
         let double_preamble = [PREAMBLE, PREAMBLE].concat();
 
@@ -367,7 +985,162 @@ impl ProtocolDecoder {
             .find(|&i| self.buffer[i..i + double_preamble.len()] == double_preamble)
     }
 
+    /// Pass complete messages through and hold fragments until their message
+    /// is whole
+    fn reassemble(&mut self, message: Message) -> Option<Message> {
+        let Some(info) = message.header.fragment else {
+            return Some(message);
+        };
+
+        // Whole messages are held to the same limit as unfragmented ones
+        let total_length = info.total_length as usize;
+        if total_length > MAX_MESSAGE_LENGTH + AEAD_TAG_LENGTH
+            || info.index as usize >= total_length.div_ceil(DATA_CHUNK_SIZE)
+            || message.payload.len() > DATA_CHUNK_SIZE
+        {
+            warn!(
+                "Dropping fragment {} of message {:08x}: it doesn't fit a {} byte message",
+                info.index, info.message_id, total_length
+            );
+            return None;
+        }
+
+        if self
+            .partial
+            .get(&info.message_id)
+            .is_some_and(|partial| !partial.matches(&info))
+        {
+            warn!(
+                "Fragments of two messages share id {:08x}; dropping the older one",
+                info.message_id
+            );
+            self.partial.remove(&info.message_id);
+        }
+        if !self.partial.contains_key(&info.message_id)
+            && self.partial.len() >= MAX_PARTIAL_MESSAGES
+            && let Some((&stalest, _)) = self
+                .partial
+                .iter()
+                .min_by_key(|(_, partial)| partial.last_update)
+        {
+            warn!(
+                "Too many partial messages; dropping {:08x} to make room",
+                stalest
+            );
+            self.partial.remove(&stalest);
+        }
+
+        let partial = self
+            .partial
+            .entry(info.message_id)
+            .or_insert_with(|| PartialMessage::new(&info));
+        if partial.last_index.is_some_and(|last| info.index > last) {
+            return None;
+        }
+        if info.index == 0 {
+            partial.header = Some(message.header.clone());
+        }
+        partial.received += message.payload.len();
+        if let Some(previous) = partial.fragments.insert(info.index, message.payload) {
+            partial.received -= previous.len();
+        }
+        partial.last_update = Instant::now();
+        if !info.more_fragments {
+            partial.last_index = Some(info.index);
+            partial.signature = message.signature;
+            partial.truncate(info.index);
+        }
+        if partial.received > total_length {
+            warn!(
+                "Dropping message {:08x}: fragments add up to more than its {} bytes",
+                info.message_id, total_length
+            );
+            self.partial.remove(&info.message_id);
+            return None;
+        }
+
+        if !partial.is_complete() {
+            return None;
+        }
+
+        let partial = self.partial.remove(&info.message_id)?;
+        let payload: Vec<u8> = partial.fragments.into_values().flatten().collect();
+        if payload.len() != total_length
+            || Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&payload) != partial.total_checksum
+        {
+            warn!(
+                "Dropping message {:08x}: reassembled payload doesn't match its checksum",
+                info.message_id
+            );
+            return None;
+        }
+        let header = MessageHeader {
+            payload_length: payload.len() as u32,
+            fragment: None,
+            ..partial.header?
+        };
+
+        match Message::calculate_checksum(&header, &payload) {
+            Ok(checksum) => {
+                debug!(
+                    "Reassembled {} byte message (id {:08x})",
+                    payload.len(),
+                    info.message_id
+                );
+                Some(Message {
+                    header,
+                    payload,
+                    checksum,
+                    signature: partial.signature,
+                })
+            }
+            Err(e) => {
+                warn!("Failed to reassemble message: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Decrypt when a key is configured
+    fn open(&self, message: Message) -> UshResult<Message> {
+        match (&self.key, message.header.encrypted) {
+            (Some(key), true) => message.decrypt(key),
+            (Some(_), false) => Err(UshError::Authentication {
+                message: format!(
+                    "sequence {} is not encrypted but a key is configured",
+                    message.header.sequence_number
+                ),
+            }),
+            (None, true) => Err(UshError::Authentication {
+                message: format!(
+                    "sequence {} is encrypted with key {:08x} but no key is configured",
+                    message.header.sequence_number,
+                    message.header.key_id.unwrap_or_default()
+                ),
+            }),
+            (None, false) => Ok(message),
+        }
+    }
+
+    fn expire_fragments(&mut self) {
+        let timeout = self.fragment_timeout;
+        self.partial.retain(|id, partial| {
+            let alive = partial.last_update.elapsed() <= timeout;
+            if !alive {
+                warn!(
+                    "Dropping incomplete message {:08x} ({} fragment(s) received)",
+                    id,
+                    partial.fragments.len()
+                );
+            }
+            alive
+        });
+    }
+
+    /// Discard any partially decoded frame. Fragments of larger messages are
+    /// kept, since a later transmission may complete them.
     pub fn reset(&mut self) {
+        self.consumed += self.buffer.len() as u64;
         self.buffer.clear();
         self.state = DecoderState::WaitingForPreamble;
         self.expected_length = 0;
@@ -380,6 +1153,165 @@ impl Default for ProtocolDecoder {
     }
 }
 
+/// Drops repeated copies of a frame, as sent by `ush send --repeat`.
+///
+/// A repeated frame carries the same sequence number and checksum as the
+/// original, while two different messages that happen to share a sequence
+/// number (for example from separate `send` invocations) differ in checksum.
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    seen: VecDeque<(u32, u32)>,
+    capacity: usize,
+}
+
+impl DuplicateFilter {
+    pub fn new() -> Self {
+        Self::with_capacity(DUPLICATE_HISTORY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            seen: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Returns `true` the first time a frame is seen and `false` for repeats
+    pub fn is_new(&mut self, message: &Message) -> bool {
+        let key = (message.header.sequence_number, message.checksum);
+        if self.seen.contains(&key) {
+            return false;
+        }
+
+        if self.seen.len() >= self.capacity {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(key);
+        true
+    }
+}
+
+impl Default for DuplicateFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receiver-side replay protection settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayPolicy {
+    /// Reject frames stamped further than this from the local clock; `None`
+    /// accepts any timestamp, e.g. when analysing an old recording
+    pub max_skew: Option<Duration>,
+    /// Reject frames already accepted from the same peer
+    pub reject_replays: bool,
+}
+
+impl ReplayPolicy {
+    /// Accept every frame, as before replay protection existed
+    pub fn disabled() -> Self {
+        Self {
+            max_skew: None,
+            reject_replays: false,
+        }
+    }
+}
+
+impl Default for ReplayPolicy {
+    fn default() -> Self {
+        Self {
+            max_skew: Some(DEFAULT_MAX_SKEW),
+            reject_replays: true,
+        }
+    }
+}
+
+/// Timestamp, sequence number and, for unsigned frames, checksum
+type ReplayId = (u64, u32, Option<u32>);
+
+/// Rejects frames that were recorded and played back
+///
+/// Each peer gets a sliding window of the `(timestamp, sequence_number)` pairs
+/// it has sent. Frames older than the skew limit are refused outright, so the
+/// window only has to cover that span. Unsigned frames share one window and
+/// are keyed on their checksum as well, since nothing else tells their
+/// senders apart.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    policy: ReplayPolicy,
+    peers: HashMap<Option<[u8; 32]>, VecDeque<ReplayId>>,
+}
+
+impl ReplayGuard {
+    pub fn new(policy: ReplayPolicy) -> Self {
+        Self {
+            policy,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Check a frame from `peer` (its verified signing key, if any) against
+    /// the local clock `now`, in Unix seconds
+    pub fn check(&mut self, message: &Message, peer: Option<[u8; 32]>, now: u64) -> UshResult<()> {
+        let header = &message.header;
+
+        if let Some(max_skew) = self.policy.max_skew {
+            let skew = now.abs_diff(header.timestamp);
+            if skew > max_skew.as_secs() {
+                let direction = if header.timestamp < now {
+                    "behind"
+                } else {
+                    "ahead of"
+                };
+                return Err(UshError::Replay {
+                    message: format!(
+                        "sequence {} is stamped {}s {} the local clock (max: {}s)",
+                        header.sequence_number,
+                        skew,
+                        direction,
+                        max_skew.as_secs()
+                    ),
+                });
+            }
+        }
+
+        if !self.policy.reject_replays {
+            return Ok(());
+        }
+
+        let window = self.peers.entry(peer).or_default();
+        if let Some(max_skew) = self.policy.max_skew {
+            // Anything older is refused by the skew check, so stop tracking it
+            let oldest = now.saturating_sub(max_skew.as_secs());
+            window.retain(|(timestamp, _, _)| *timestamp >= oldest);
+        }
+
+        // Unsigned senders all count from zero, so only an identical frame is a replay
+        let checksum = peer.is_none().then_some(message.checksum);
+        let id = (header.timestamp, header.sequence_number, checksum);
+        if window.contains(&id) {
+            return Err(UshError::Replay {
+                message: format!(
+                    "sequence {} sent at {} was already received",
+                    header.sequence_number, header.timestamp
+                ),
+            });
+        }
+
+        if window.len() >= REPLAY_HISTORY {
+            window.pop_front();
+        }
+        window.push_back(id);
+        Ok(())
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(ReplayPolicy::default())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -434,4 +1366,455 @@ mod tests {
         assert_eq!(total_messages.len(), 1);
         assert_eq!(total_messages[0].get_text().unwrap(), "Test");
     }
+
+    #[test]
+    fn test_decoder_trace_byte_by_byte() {
+        let mut encoder = ProtocolEncoder::new();
+        let mut decoder = ProtocolDecoder::new().with_trace();
+
+        let mut encoded = encoder.encode_text("first").unwrap();
+        encoded.extend(encoder.encode_text("second").unwrap());
+        let messages: Vec<Message> = encoded
+            .iter()
+            .flat_map(|&byte| decoder.feed_data(&[byte]))
+            .collect();
+        assert_eq!(messages.len(), 2);
+
+        let trace = decoder.take_trace();
+        let notes: Vec<&str> = trace.iter().map(|t| t.note.as_str()).collect();
+        assert_eq!(
+            notes[..5],
+            [
+                "preamble found",
+                "start delimiter",
+                notes[2],
+                "message",
+                "end delimiter"
+            ]
+        );
+        assert!(notes[2].starts_with("length "));
+        assert_eq!(trace.len(), 10);
+        assert!(trace.iter().all(|t| !t.failure));
+        assert_eq!(trace[9].byte_offset, encoded.len() as u64);
+        assert_eq!(decoder.state(), DecoderState::WaitingForPreamble);
+    }
+
+    #[test]
+    fn test_encode_data_splits_into_frames() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(DATA_CHUNK_SIZE * 2 + 10).collect();
+
+        let mut encoder = ProtocolEncoder::new();
+        let frames = encoder.encode_data(&data).unwrap();
+
+        let mut decoder = ProtocolDecoder::new();
+        let messages = decoder.feed_data(&frames);
+
+        assert_eq!(messages.len(), 3);
+        assert!(
+            messages
+                .iter()
+                .all(|m| matches!(m.header.message_type, MessageType::File))
+        );
+        let received: Vec<u8> = messages.iter().flat_map(|m| m.payload.clone()).collect();
+        assert_eq!(received, data);
+        assert_eq!(encoder.get_next_sequence_number(), 3);
+    }
+
+    #[test]
+    fn test_large_message_is_fragmented_and_reassembled() {
+        let text = "fragmented ".repeat(300);
+
+        let mut encoder = ProtocolEncoder::new();
+        let frames = encoder.encode_text(&text).unwrap();
+
+        let mut decoder = ProtocolDecoder::new();
+        let mut messages = Vec::new();
+        for chunk in frames.chunks(100) {
+            messages.extend(decoder.feed_data(chunk));
+        }
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_text().unwrap(), text);
+        assert!(messages[0].header.fragment.is_none());
+        assert!(messages[0].verify_checksum().unwrap());
+    }
+
+    #[test]
+    fn test_fragments_reassemble_out_of_order() {
+        let message = Message::new_text(&"x".repeat(DATA_CHUNK_SIZE * 3), 5).unwrap();
+        let original_checksum = message.checksum;
+        let mut fragments = message.into_fragments(42).unwrap();
+        fragments.reverse();
+
+        let encoder = ProtocolEncoder::new();
+        let mut decoder = ProtocolDecoder::new();
+        let mut messages = Vec::new();
+        for fragment in &fragments {
+            messages.extend(decoder.feed_data(&encoder.encode_frame(fragment).unwrap()));
+        }
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].header.sequence_number, 5);
+        assert_eq!(messages[0].checksum, original_checksum);
+    }
+
+    #[test]
+    fn test_incomplete_message_times_out() {
+        let message = Message::new_text(&"y".repeat(DATA_CHUNK_SIZE * 2), 1).unwrap();
+        let fragments = message.into_fragments(7).unwrap();
+
+        let encoder = ProtocolEncoder::new();
+        let mut decoder = ProtocolDecoder::new().with_fragment_timeout(Duration::from_millis(1));
+        assert!(
+            decoder
+                .feed_data(&encoder.encode_frame(&fragments[0]).unwrap())
+                .is_empty()
+        );
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(
+            decoder
+                .feed_data(&encoder.encode_frame(&fragments[1]).unwrap())
+                .is_empty()
+        );
+    }
+
+    /// Frame `fragment` as sent and feed it to `decoder`
+    fn feed_fragment(decoder: &mut ProtocolDecoder, fragment: &Message) -> Vec<Message> {
+        decoder.feed_data(&ProtocolEncoder::new().encode_frame(fragment).unwrap())
+    }
+
+    /// `fragment` with its header changed and its checksum fixed up, as a
+    /// deliberate forger would send it
+    fn forged(fragment: &Message, change: impl FnOnce(&mut FragmentInfo)) -> Message {
+        let mut forged = fragment.clone();
+        change(forged.header.fragment.as_mut().unwrap());
+        forged.checksum = Message::calculate_checksum(&forged.header, &forged.payload).unwrap();
+        forged
+    }
+
+    #[test]
+    fn test_stray_fragment_past_the_last_does_not_complete() {
+        let message = Message::new_text(&"z".repeat(DATA_CHUNK_SIZE * 3), 1).unwrap();
+        let fragments = message.into_fragments(3).unwrap();
+        let mut partial = PartialMessage::new(&fragments[0].header.fragment.unwrap());
+        for index in [0, 2, 3] {
+            partial.fragments.insert(index, vec![0; 4]);
+        }
+        partial.last_index = Some(2);
+        assert!(!partial.is_complete());
+
+        // Fragment 1 is missing, so a stray index 3 must not stand in for it
+        let mut decoder = ProtocolDecoder::new();
+        let stray = forged(&fragments[1], |info| info.index = 3);
+        assert!(feed_fragment(&mut decoder, &fragments[0]).is_empty());
+        assert!(feed_fragment(&mut decoder, &stray).is_empty());
+        assert!(feed_fragment(&mut decoder, &fragments[2]).is_empty());
+        let messages = feed_fragment(&mut decoder, &fragments[1]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].get_text().unwrap(),
+            "z".repeat(DATA_CHUNK_SIZE * 3)
+        );
+    }
+
+    #[test]
+    fn test_messages_sharing_an_id_are_not_merged() {
+        let first = Message::new_text(&"a".repeat(DATA_CHUNK_SIZE * 2), 1).unwrap();
+        let second = Message::new_text(&"b".repeat(DATA_CHUNK_SIZE + 10), 2).unwrap();
+        let first = first.into_fragments(9).unwrap();
+        let second = second.into_fragments(9).unwrap();
+
+        let mut decoder = ProtocolDecoder::new();
+        assert!(feed_fragment(&mut decoder, &first[0]).is_empty());
+        assert!(feed_fragment(&mut decoder, &second[1]).is_empty());
+        let messages = feed_fragment(&mut decoder, &second[0]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].header.sequence_number, 2);
+        assert_eq!(
+            messages[0].get_text().unwrap(),
+            "b".repeat(DATA_CHUNK_SIZE + 10)
+        );
+
+        // A fragment whose bytes don't match the whole-message checksum
+        let message = Message::new_text(&"c".repeat(DATA_CHUNK_SIZE * 2), 3).unwrap();
+        let mut fragments = message.into_fragments(10).unwrap();
+        fragments[1].payload[0] = b'd';
+        fragments[1].checksum =
+            Message::calculate_checksum(&fragments[1].header, &fragments[1].payload).unwrap();
+        assert!(feed_fragment(&mut decoder, &fragments[0]).is_empty());
+        assert!(feed_fragment(&mut decoder, &fragments[1]).is_empty());
+        assert!(decoder.partial.is_empty());
+    }
+
+    #[test]
+    fn test_reassembly_is_bounded() {
+        let message = Message::new_text(&"e".repeat(DATA_CHUNK_SIZE * 2), 1).unwrap();
+        let fragment = message.into_fragments(0).unwrap().remove(0);
+        let mut decoder = ProtocolDecoder::new();
+
+        for id in 0..MAX_PARTIAL_MESSAGES as u32 * 2 {
+            feed_fragment(
+                &mut decoder,
+                &forged(&fragment, |info| info.message_id = id),
+            );
+        }
+        assert_eq!(decoder.partial.len(), MAX_PARTIAL_MESSAGES);
+        // The stalest were dropped to make room
+        assert!(!decoder.partial.contains_key(&0));
+
+        let mut decoder = ProtocolDecoder::new();
+        let oversized = forged(&fragment, |info| {
+            info.total_length = (MAX_MESSAGE_LENGTH + AEAD_TAG_LENGTH + 1) as u32
+        });
+        let out_of_range = forged(&fragment, |info| info.index = 2);
+        feed_fragment(&mut decoder, &oversized);
+        feed_fragment(&mut decoder, &out_of_range);
+        assert!(decoder.partial.is_empty());
+    }
+
+    fn key(passphrase: &str) -> PresharedKey {
+        use sha2::{Digest, Sha256};
+        PresharedKey::from_bytes(Sha256::digest(passphrase.as_bytes()).into())
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let mut encoder = ProtocolEncoder::new().with_key(key("secret"));
+        let frame = encoder.encode_text("for your ears only").unwrap();
+        assert!(!frame.windows(4).any(|w| w == b"ears"));
+
+        let mut decoder = ProtocolDecoder::new().with_key(key("secret"));
+        let messages = decoder.feed_data(&frame);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_text().unwrap(), "for your ears only");
+        assert!(!messages[0].header.encrypted);
+    }
+
+    #[test]
+    fn test_large_encrypted_message_is_fragmented() {
+        let text = "confidential ".repeat(100);
+        let mut encoder = ProtocolEncoder::new().with_key(key("secret"));
+        let frames = encoder.encode_text(&text).unwrap();
+
+        let mut decoder = ProtocolDecoder::new().with_key(key("secret"));
+        let messages = decoder.feed_data(&frames);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_text().unwrap(), text);
+    }
+
+    #[test]
+    fn test_wrong_key_is_authentication_error() {
+        let mut encoder = ProtocolEncoder::new().with_key(key("secret"));
+        let frame = encoder.encode_text("hello").unwrap();
+
+        let mut decoder = ProtocolDecoder::new().with_key(key("guess"));
+        let results = decoder.decode(&frame);
+        assert!(matches!(
+            results.as_slice(),
+            [Err(UshError::Authentication { .. })]
+        ));
+
+        let mut plain_decoder = ProtocolDecoder::new();
+        assert!(matches!(
+            plain_decoder.decode(&frame).as_slice(),
+            [Err(UshError::Authentication { .. })]
+        ));
+    }
+
+    #[test]
+    fn test_reply_reusing_a_sequence_number_gets_a_fresh_nonce() {
+        let secret = key("secret");
+        let text = Message::new_text("ping", 7).unwrap();
+        let mut ack = Message::new_ack(7).unwrap();
+        ack.header.timestamp = text.header.timestamp;
+
+        let sent = text.encrypt(&secret).unwrap();
+        let reply = ack.encrypt(&secret).unwrap();
+        assert_eq!(sent.header.sequence_number, reply.header.sequence_number);
+        assert_eq!(sent.header.timestamp, reply.header.timestamp);
+        let nonce = |message: &Message| {
+            Message::nonce(&message.header, message.header.nonce_prefix.unwrap())
+        };
+        assert_ne!(nonce(&sent), nonce(&reply));
+
+        // Encrypting the same message twice doesn't repeat one either
+        let again = text.encrypt(&secret).unwrap();
+        assert_ne!(nonce(&sent), nonce(&again));
+        assert_eq!(again.decrypt(&secret).unwrap().payload, b"ping");
+
+        let mut unprefixed = again;
+        unprefixed.header.nonce_prefix = None;
+        assert!(matches!(
+            unprefixed.decrypt(&secret),
+            Err(UshError::Authentication { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_is_rejected() {
+        let secret = key("secret");
+        let mut message = Message::new_text("pay alice 10", 1)
+            .unwrap()
+            .encrypt(&secret)
+            .unwrap();
+        // Flip a bit and fix up the CRC, as a deliberate forger would
+        message.payload[0] ^= 0x01;
+        message.checksum = Message::calculate_checksum(&message.header, &message.payload).unwrap();
+
+        assert!(matches!(
+            message.decrypt(&secret),
+            Err(UshError::Authentication { .. })
+        ));
+
+        let mut header_forged = Message::new_text("pay alice 10", 1)
+            .unwrap()
+            .encrypt(&secret)
+            .unwrap();
+        header_forged.header.message_type = MessageType::File;
+        assert!(matches!(
+            header_forged.decrypt(&secret),
+            Err(UshError::Authentication { .. })
+        ));
+    }
+
+    #[test]
+    fn test_signature_survives_encryption_and_fragmentation() {
+        use crate::identity::{Sender, TrustStore};
+
+        let alice = Identity::generate();
+        let mut trust = TrustStore::new();
+        trust.add("alice", alice.public_key()).unwrap();
+
+        let text = "signed and sealed ".repeat(40);
+        let mut encoder = ProtocolEncoder::new()
+            .with_identity(alice)
+            .with_key(key("secret"));
+        let frames = encoder.encode_text(&text).unwrap();
+
+        let mut decoder = ProtocolDecoder::new().with_key(key("secret"));
+        let messages = decoder.feed_data(&frames);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_text().unwrap(), text);
+        assert_eq!(
+            trust.verify(&messages[0]),
+            Sender::Verified("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_filter() {
+        let mut filter = DuplicateFilter::new();
+        let first = Message::new_text("Repeat me", 7).unwrap();
+        let other = Message::new_text("Different text", 7).unwrap();
+
+        assert!(filter.is_new(&first));
+        assert!(!filter.is_new(&first.clone()));
+        assert!(filter.is_new(&other));
+    }
+
+    #[test]
+    fn test_replay_guard_rejects_replays_per_peer() {
+        let mut guard = ReplayGuard::default();
+        let message = Message::new_text("open the door", 5).unwrap();
+        let now = message.header.timestamp;
+        let alice = Some([1; 32]);
+
+        assert!(guard.check(&message, alice, now).is_ok());
+        assert!(matches!(
+            guard.check(&message, alice, now + 30),
+            Err(UshError::Replay { .. })
+        ));
+        // Another peer may use the same sequence number in the same second
+        assert!(guard.check(&message, Some([2; 32]), now).is_ok());
+        assert!(guard.check(&message, None, now).is_ok());
+    }
+
+    #[test]
+    fn test_replay_guard_tells_unsigned_senders_apart() {
+        let mut guard = ReplayGuard::default();
+        let mut decoder = ProtocolDecoder::new();
+        let mut alice = ProtocolEncoder::new();
+        let mut bob = ProtocolEncoder::new();
+
+        let first = decoder.feed_data(&alice.encode_text("from alice").unwrap());
+        let second = decoder.feed_data(&bob.encode_text("from bob").unwrap());
+        let (first, second) = (&first[0], &second[0]);
+        assert_eq!(first.header.sequence_number, second.header.sequence_number);
+        let now = first.header.timestamp.max(second.header.timestamp);
+
+        assert!(guard.check(first, None, now).is_ok());
+        assert!(guard.check(second, None, now).is_ok());
+        assert!(matches!(
+            guard.check(first, None, now),
+            Err(UshError::Replay { .. })
+        ));
+    }
+
+    #[test]
+    fn test_replay_guard_enforces_clock_skew() {
+        let mut guard = ReplayGuard::default();
+        let message = Message::new_text("stale", 1).unwrap();
+        let sent = message.header.timestamp;
+        let limit = DEFAULT_MAX_SKEW.as_secs();
+
+        assert!(guard.check(&message, None, sent + limit + 1).is_err());
+        assert!(guard.check(&message, None, sent - limit - 1).is_err());
+        assert!(guard.check(&message, None, sent + limit).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_replay_policy_accepts_old_recordings() {
+        let mut guard = ReplayGuard::new(ReplayPolicy::disabled());
+        let message = Message::new_text("from last year", 1).unwrap();
+        let later = message.header.timestamp + 365 * 24 * 3600;
+
+        assert!(guard.check(&message, None, later).is_ok());
+        assert!(guard.check(&message, None, later).is_ok());
+    }
+
+    #[test]
+    fn test_addressing_filters_frames() {
+        let alice = Address {
+            node_id: Some(1),
+            group: None,
+        };
+        let bob = Address {
+            node_id: Some(2),
+            group: None,
+        };
+        let mut decoder = ProtocolDecoder::new();
+
+        let mut encoder = ProtocolEncoder::new()
+            .with_address(alice)
+            .with_destination(2);
+        let direct = decoder.feed_data(&encoder.encode_text("for bob").unwrap());
+        assert_eq!(direct.len(), 1);
+        assert_eq!(direct[0].header.source, Some(1));
+        assert!(bob.accepts(&direct[0].header));
+        assert!(!alice.accepts(&direct[0].header));
+
+        let mut encoder = ProtocolEncoder::new().with_address(alice);
+        let broadcast = decoder.feed_data(&encoder.encode_text("for all").unwrap());
+        assert!(bob.accepts(&broadcast[0].header));
+        assert!(Address::default().accepts(&broadcast[0].header));
+
+        let team = Address {
+            node_id: Some(3),
+            group: Some(7),
+        };
+        let mut encoder = ProtocolEncoder::new().with_address(team);
+        let grouped = decoder.feed_data(&encoder.encode_text("team only").unwrap());
+        assert!(!bob.accepts(&grouped[0].header));
+        assert!(
+            Address {
+                node_id: None,
+                group: Some(7)
+            }
+            .accepts(&grouped[0].header)
+        );
+    }
 }
@@ -0,0 +1,322 @@
+//! Streaming link quality measurement
+//!
+//! [`LinkQualityMonitor`] is fed the same samples as the receiver, in chunks
+//! of any size, along with the events the receiver reports. Spectral
+//! measurements are made over fixed blocks and smoothed, so
+//! [`LinkQualityMonitor::quality`] can be polled at any time during a session.
+
+use crate::carrier::{band_power, noise_floor};
+use crate::receiver::ReceiverEvent;
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+/// Samples per spectral measurement: about 46 ms at 44.1 kHz
+const BLOCK_SIZE: usize = 2048;
+/// Weight of each new block in the smoothed measurements
+const SMOOTHING: f32 = 0.2;
+/// Bins searched either side of each tone
+const TONE_RANGE: usize = 2;
+/// Tone to noise power ratio above which a tone counts as present (10 dB);
+/// the strongest of several noise-only bins often exceeds 6 dB
+const PRESENT_RATIO: f32 = 10.0;
+/// Reported level of silence, in dB
+const FLOOR_DB: f32 = -120.0;
+
+/// A snapshot of link quality
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct LinkQuality {
+    /// Smoothed input level relative to full scale
+    pub rms_db: f32,
+    /// Smoothed noise power outside the tones, as the level of white noise
+    /// with the same power per bin, comparable to `rms_db`
+    pub noise_floor_db: f32,
+    /// SNR of the `freq_0` tone while it was present
+    pub snr_0_db: Option<f32>,
+    /// SNR of the `freq_1` tone while it was present
+    pub snr_1_db: Option<f32>,
+    /// Mean carrier offset over every transmission received
+    pub frequency_offset_ppm: Option<f32>,
+    pub frequency_offset_hz: Option<f32>,
+    /// Mean of how much weaker `freq_1` arrived than `freq_0`, in dB
+    pub tone_balance_db: Option<f32>,
+    /// Frames that arrived intact, including ones later rejected
+    pub frames_decoded: u64,
+    /// Transmissions that ended without an intact frame
+    pub frames_failed: u64,
+}
+
+impl LinkQuality {
+    /// Share of transmissions that produced an intact frame
+    pub fn frame_success_rate(&self) -> Option<f32> {
+        let total = self.frames_decoded + self.frames_failed;
+        (total > 0).then(|| self.frames_decoded as f32 / total as f32)
+    }
+}
+
+impl fmt::Display for LinkQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let db = |value: Option<f32>| match value {
+            Some(value) => format!("{:.1}", value),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "RMS {:.1} dB, noise {:.1} dB, SNR {}/{} dB",
+            self.rms_db,
+            self.noise_floor_db,
+            db(self.snr_0_db),
+            db(self.snr_1_db)
+        )?;
+        if let Some(ppm) = self.frequency_offset_ppm {
+            write!(f, ", offset {:+.0} ppm", ppm)?;
+        }
+        if let Some(balance) = self.tone_balance_db {
+            write!(f, ", tone balance {:+.1} dB", balance)?;
+        }
+        write!(
+            f,
+            ", {}/{} frames",
+            self.frames_decoded,
+            self.frames_decoded + self.frames_failed
+        )?;
+        if let Some(rate) = self.frame_success_rate() {
+            write!(f, " ({:.0}%)", rate * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exponential moving average that starts at its first value
+#[derive(Debug, Default, Clone, Copy)]
+struct Smoothed(Option<f32>);
+
+impl Smoothed {
+    fn update(&mut self, value: f32) {
+        self.0 = Some(match self.0 {
+            Some(previous) => previous + SMOOTHING * (value - previous),
+            None => value,
+        });
+    }
+}
+
+pub struct LinkQualityMonitor {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Power a unit-variance white noise block puts in each bin
+    window_energy: f32,
+    tone_bins: [usize; 2],
+    /// Bins left out of the noise floor
+    excluded_bins: Vec<usize>,
+    /// Samples not yet making up a whole block
+    pending: Vec<f32>,
+    mean_square: Smoothed,
+    noise: Smoothed,
+    tone_ratio: [Smoothed; 2],
+    /// Carrier offsets and tone balance weighted by symbols
+    offset_ppm_sum: f64,
+    offset_hz_sum: f64,
+    tone_balance_sum: f64,
+    offset_symbols: usize,
+    frames_decoded: u64,
+    frames_failed: u64,
+}
+
+impl LinkQualityMonitor {
+    pub fn new(sample_rate: u32, freq_0: f32, freq_1: f32) -> Self {
+        let bin_width = sample_rate as f32 / BLOCK_SIZE as f32;
+        let tone_bins = [
+            (freq_0 / bin_width).round() as usize,
+            (freq_1 / bin_width).round() as usize,
+        ];
+        let excluded_bins = tone_bins
+            .iter()
+            .flat_map(|&bin| bin.saturating_sub(TONE_RANGE)..=bin + TONE_RANGE)
+            .collect();
+        let window: Vec<f32> = (0..BLOCK_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / BLOCK_SIZE as f32).cos())
+            .collect();
+
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(BLOCK_SIZE),
+            window_energy: window.iter().map(|w| w * w).sum(),
+            window,
+            tone_bins,
+            excluded_bins,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            mean_square: Smoothed::default(),
+            noise: Smoothed::default(),
+            tone_ratio: [Smoothed::default(); 2],
+            offset_ppm_sum: 0.0,
+            offset_hz_sum: 0.0,
+            tone_balance_sum: 0.0,
+            offset_symbols: 0,
+            frames_decoded: 0,
+            frames_failed: 0,
+        }
+    }
+
+    /// Feed newly captured samples
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let take = (BLOCK_SIZE - self.pending.len()).min(samples.len());
+            self.pending.extend_from_slice(&samples[..take]);
+            samples = &samples[take..];
+
+            if self.pending.len() == BLOCK_SIZE {
+                self.measure_block();
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// Count a receiver event towards the frame statistics
+    pub fn observe(&mut self, event: &ReceiverEvent) {
+        let quality = match event {
+            ReceiverEvent::SignalDetected { .. } => return,
+            ReceiverEvent::Message { quality, .. } | ReceiverEvent::Rejected { quality, .. } => {
+                self.frames_decoded += 1;
+                quality
+            }
+            ReceiverEvent::DecodeFailed { quality, .. } => {
+                self.frames_failed += 1;
+                quality
+            }
+        };
+        self.offset_ppm_sum += quality.frequency_offset_ppm as f64 * quality.symbols as f64;
+        self.offset_hz_sum += quality.frequency_offset_hz as f64 * quality.symbols as f64;
+        self.tone_balance_sum += quality.tone_balance_db as f64 * quality.symbols as f64;
+        self.offset_symbols += quality.symbols;
+    }
+
+    pub fn quality(&self) -> LinkQuality {
+        let noise = self.noise.0.unwrap_or(0.0);
+        let tone_snr = |ratio: Smoothed| ratio.0.map(to_db);
+        let symbol_mean =
+            |sum: f64| (self.offset_symbols > 0).then(|| (sum / self.offset_symbols as f64) as f32);
+
+        LinkQuality {
+            rms_db: to_db(self.mean_square.0.unwrap_or(0.0)),
+            noise_floor_db: to_db(noise / self.window_energy),
+            snr_0_db: tone_snr(self.tone_ratio[0]),
+            snr_1_db: tone_snr(self.tone_ratio[1]),
+            frequency_offset_ppm: symbol_mean(self.offset_ppm_sum),
+            frequency_offset_hz: symbol_mean(self.offset_hz_sum),
+            tone_balance_db: symbol_mean(self.tone_balance_sum),
+            frames_decoded: self.frames_decoded,
+            frames_failed: self.frames_failed,
+        }
+    }
+
+    fn measure_block(&mut self) {
+        let mean_square =
+            self.pending.iter().map(|&s| s * s).sum::<f32>() / self.pending.len() as f32;
+        self.mean_square.update(mean_square);
+
+        let mut spectrum: Vec<Complex<f32>> = self
+            .pending
+            .iter()
+            .zip(&self.window)
+            .map(|(&s, &w)| Complex::new(s * w, 0.0))
+            .collect();
+        self.fft.process(&mut spectrum);
+
+        let noise = noise_floor(&spectrum, &self.excluded_bins);
+        if noise <= 0.0 {
+            return;
+        }
+        self.noise.update(noise);
+
+        // Only blocks carrying a tone say anything about its SNR
+        for (ratio, &bin) in self.tone_ratio.iter_mut().zip(&self.tone_bins) {
+            let tone = band_power(&spectrum, bin, TONE_RANGE) / noise;
+            if tone >= PRESENT_RATIO {
+                ratio.update(tone);
+            }
+        }
+    }
+}
+
+fn to_db(power: f32) -> f32 {
+    if power > 0.0 {
+        (10.0 * power.log10()).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::SignalQuality;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn tone_in_noise(freq: f32, seconds: f32) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(45);
+        (0..(44100.0 * seconds) as usize)
+            .map(|i| {
+                let t = i as f32 / 44100.0;
+                0.3 * (2.0 * std::f32::consts::PI * freq * t).sin() + rng.gen_range(-0.01..0.01)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_monitor_measures_the_present_tone() {
+        let mut monitor = LinkQualityMonitor::new(44100, 18000.0, 20000.0);
+        monitor.push_samples(&tone_in_noise(18000.0, 1.0));
+        let quality = monitor.quality();
+
+        // A 0.3 amplitude sine is about -13.5 dB
+        assert!((quality.rms_db + 13.5).abs() < 0.5, "{}", quality);
+        assert!(quality.snr_0_db.unwrap() > 30.0, "{}", quality);
+        assert_eq!(quality.snr_1_db, None);
+        // Uniform noise of ±0.01 has a mean square of 3.3e-5, about -45 dB
+        assert!((quality.noise_floor_db + 45.0).abs() < 2.0, "{}", quality);
+        assert_eq!(quality.frame_success_rate(), None);
+    }
+
+    #[test]
+    fn test_monitor_is_independent_of_chunk_size() {
+        let samples = tone_in_noise(20000.0, 0.5);
+        let mut whole = LinkQualityMonitor::new(44100, 18000.0, 20000.0);
+        whole.push_samples(&samples);
+        let mut chunked = LinkQualityMonitor::new(44100, 18000.0, 20000.0);
+        for chunk in samples.chunks(333) {
+            chunked.push_samples(chunk);
+        }
+
+        assert_eq!(whole.quality(), chunked.quality());
+    }
+
+    #[test]
+    fn test_monitor_counts_frames_and_offset() {
+        let mut monitor = LinkQualityMonitor::new(44100, 18000.0, 20000.0);
+        let quality = |ppm: f32, symbols: usize| SignalQuality {
+            snr_db: 30.0,
+            frequency_offset_hz: ppm * 0.019,
+            frequency_offset_ppm: ppm,
+            tone_balance_db: 3.0,
+            symbols,
+        };
+        monitor.observe(&ReceiverEvent::SignalDetected { sample_offset: 0 });
+        monitor.observe(&ReceiverEvent::Rejected {
+            reason: "replayed".to_string(),
+            quality: quality(100.0, 300),
+        });
+        monitor.observe(&ReceiverEvent::DecodeFailed {
+            sample_offset: 0,
+            bytes: 3,
+            quality: quality(200.0, 100),
+        });
+
+        let link = monitor.quality();
+        assert_eq!((link.frames_decoded, link.frames_failed), (1, 1));
+        assert_eq!(link.frame_success_rate(), Some(0.5));
+        assert!((link.frequency_offset_ppm.unwrap() - 125.0).abs() < 1e-3);
+        assert!((link.tone_balance_db.unwrap() - 3.0).abs() < 1e-3);
+    }
+}
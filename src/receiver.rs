@@ -0,0 +1,408 @@
+//! Persistent receive pipeline: stream in, detect, demodulate, decode
+//!
+//! [`MessageReceiver`] owns a [`StreamDemodulator`] and a single long-lived
+//! [`ProtocolDecoder`], so every frame in a capture is reported in the order
+//! it was received, no matter how many transmissions follow each other.
+
+use crate::crypto::PresharedKey;
+use crate::fec::ConvolutionalCode;
+use crate::identity::{Sender, TrustStore};
+use crate::modulation::ModulationConfig;
+use crate::protocol::{
+    Address, DuplicateFilter, Message, ProtocolDecoder, ReplayGuard, ReplayPolicy,
+};
+use crate::stream::{SignalQuality, StreamDemodulator, StreamEvent};
+use log::debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Events reported by the receive pipeline
+#[derive(Debug, Clone)]
+pub enum ReceiverEvent {
+    /// A transmission started at this absolute sample offset
+    SignalDetected { sample_offset: u64 },
+    /// A frame passed its checksum and has not been seen before
+    Message {
+        message: Message,
+        sender: Sender,
+        quality: SignalQuality,
+    },
+    /// A frame arrived intact but failed authentication (wrong key, forged,
+    /// or unencrypted while a key is configured) or was replayed
+    Rejected {
+        reason: String,
+        quality: SignalQuality,
+    },
+    /// A transmission ended without producing a valid frame
+    DecodeFailed {
+        sample_offset: u64,
+        bytes: usize,
+        quality: SignalQuality,
+    },
+}
+
+pub struct MessageReceiver {
+    stream: StreamDemodulator,
+    decoder: ProtocolDecoder,
+    duplicates: DuplicateFilter,
+    trust: TrustStore,
+    replay: ReplayGuard,
+    /// `None` accepts frames for every node and group
+    address: Option<Address>,
+    /// Frames decoded (including duplicates) in the current transmission
+    frames_in_transmission: usize,
+    duplicates_dropped: u64,
+    replays_rejected: u64,
+    not_addressed: u64,
+}
+
+impl MessageReceiver {
+    pub fn new(config: ModulationConfig, threshold: f32) -> Self {
+        Self {
+            stream: StreamDemodulator::new(config, threshold),
+            decoder: ProtocolDecoder::new(),
+            duplicates: DuplicateFilter::new(),
+            trust: TrustStore::new(),
+            replay: ReplayGuard::default(),
+            address: Some(Address::default()),
+            frames_in_transmission: 0,
+            duplicates_dropped: 0,
+            replays_rejected: 0,
+            not_addressed: 0,
+        }
+    }
+
+    /// Only accept messages encrypted with this pre-shared key
+    pub fn with_key(mut self, key: PresharedKey) -> Self {
+        self.decoder = ProtocolDecoder::new().with_key(key);
+        self
+    }
+
+    /// Name signed messages from these peers as verified
+    pub fn with_trust_store(mut self, trust: TrustStore) -> Self {
+        self.trust = trust;
+        self
+    }
+
+    /// How to treat replayed frames and frames with old timestamps
+    pub fn with_replay_policy(mut self, policy: ReplayPolicy) -> Self {
+        self.replay = ReplayGuard::new(policy);
+        self
+    }
+
+    /// Only report frames addressed to this node and group
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Report frames for every node and group
+    pub fn monitor_all(mut self) -> Self {
+        self.address = None;
+        self
+    }
+
+    /// Expect transmissions encoded with this convolutional code
+    pub fn with_fec(mut self, code: ConvolutionalCode) -> Self {
+        self.stream = self.stream.with_fec(code);
+        self
+    }
+
+    /// Feed newly captured samples through the whole pipeline
+    pub fn push_samples(&mut self, samples: &[f32]) -> Vec<ReceiverEvent> {
+        let events = self.stream.push_samples(samples);
+        self.process(events)
+    }
+
+    /// Close out any transmission still in progress
+    pub fn flush(&mut self) -> Vec<ReceiverEvent> {
+        let events = self.stream.flush();
+        self.process(events)
+    }
+
+    /// Total number of samples pushed so far
+    pub fn samples_seen(&self) -> u64 {
+        self.stream.samples_seen()
+    }
+
+    /// Number of repeated frames that were suppressed
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.duplicates_dropped
+    }
+
+    /// Number of replayed or stale frames that were rejected
+    pub fn replays_rejected(&self) -> u64 {
+        self.replays_rejected
+    }
+
+    /// Number of frames ignored because they were meant for another node or group
+    pub fn not_addressed(&self) -> u64 {
+        self.not_addressed
+    }
+
+    fn process(&mut self, events: Vec<StreamEvent>) -> Vec<ReceiverEvent> {
+        let mut output = Vec::new();
+
+        for event in events {
+            match event {
+                StreamEvent::SignalDetected { sample_offset } => {
+                    self.frames_in_transmission = 0;
+                    output.push(ReceiverEvent::SignalDetected { sample_offset });
+                }
+                StreamEvent::Data { bytes, quality } => {
+                    for result in self.decoder.decode(&bytes) {
+                        self.frames_in_transmission += 1;
+                        let message = match result {
+                            Ok(message) => message,
+                            Err(e) => {
+                                output.push(ReceiverEvent::Rejected {
+                                    reason: e.to_string(),
+                                    quality,
+                                });
+                                continue;
+                            }
+                        };
+                        if let Some(address) = &self.address
+                            && !address.accepts(&message.header)
+                        {
+                            debug!(
+                                "Ignoring frame for node {:?} in group {:?}",
+                                message.header.destination, message.header.group
+                            );
+                            self.not_addressed += 1;
+                            continue;
+                        }
+                        if self.duplicates.is_new(&message) {
+                            let sender = self.trust.verify(&message);
+                            let peer = match sender {
+                                Sender::Verified(_) | Sender::Untrusted(_) => message.header.signer,
+                                Sender::Unsigned | Sender::Invalid => None,
+                            };
+                            if let Err(e) = self.replay.check(&message, peer, unix_time()) {
+                                self.replays_rejected += 1;
+                                output.push(ReceiverEvent::Rejected {
+                                    reason: e.to_string(),
+                                    quality,
+                                });
+                                continue;
+                            }
+                            output.push(ReceiverEvent::Message {
+                                message,
+                                sender,
+                                quality,
+                            });
+                        } else {
+                            debug!(
+                                "Dropping repeated frame (sequence {})",
+                                message.header.sequence_number
+                            );
+                            self.duplicates_dropped += 1;
+                        }
+                    }
+                }
+                StreamEvent::SignalLost {
+                    sample_offset,
+                    bytes,
+                    quality,
+                } => {
+                    // A frame cut off by the end of a transmission can never
+                    // complete, so don't let it swallow the next one.
+                    self.decoder.reset();
+                    if self.frames_in_transmission == 0 {
+                        output.push(ReceiverEvent::DecodeFailed {
+                            sample_offset,
+                            bytes,
+                            quality,
+                        });
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::FskModulator;
+    use crate::protocol::{Message, ProtocolEncoder};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn messages(events: &[ReceiverEvent]) -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                ReceiverEvent::Message { message, .. } => message.get_text().ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_receiver_reports_every_frame_in_order() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut encoder = ProtocolEncoder::new();
+        let mut receiver = MessageReceiver::new(config, 0.1);
+
+        let mut samples = vec![0.0; 1000];
+        for text in ["first", "second", "third"] {
+            samples.extend(modulator.encode_bytes(&encoder.encode_text(text).unwrap()));
+            samples.extend(vec![0.0; 4410]);
+        }
+
+        let mut events = Vec::new();
+        for chunk in samples.chunks(4096) {
+            events.extend(receiver.push_samples(chunk));
+        }
+        events.extend(receiver.flush());
+
+        assert_eq!(messages(&events), vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_receiver_drops_repeated_frames() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut encoder = ProtocolEncoder::new();
+        let mut receiver = MessageReceiver::new(config, 0.1);
+
+        let message = Message::new_text("say it twice", 3).unwrap();
+        let frame = modulator.encode_bytes(&encoder.encode_message(&message).unwrap());
+
+        let mut samples = frame.clone();
+        samples.extend(vec![0.0; 22050]);
+        samples.extend(frame);
+
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+
+        assert_eq!(messages(&events), vec!["say it twice"]);
+        assert_eq!(receiver.duplicates_dropped(), 1);
+    }
+
+    #[test]
+    fn test_receiver_rejects_wrong_key() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut encoder = ProtocolEncoder::new().with_key(PresharedKey::from_bytes([1; 32]));
+        let mut receiver =
+            MessageReceiver::new(config, 0.1).with_key(PresharedKey::from_bytes([2; 32]));
+
+        let samples = modulator.encode_bytes(&encoder.encode_text("secret").unwrap());
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+
+        assert!(messages(&events).is_empty());
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, ReceiverEvent::Rejected { .. }))
+        );
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, ReceiverEvent::DecodeFailed { .. }))
+        );
+    }
+
+    #[test]
+    fn test_receiver_rejects_stale_frames_unless_allowed() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut encoder = ProtocolEncoder::new();
+
+        let mut message = Message::new_text("recorded last week", 1).unwrap();
+        message.header.timestamp -= 7 * 24 * 3600;
+        message.checksum = Message::calculate_checksum(&message.header, &message.payload).unwrap();
+        let samples = modulator.encode_bytes(&encoder.encode_message(&message).unwrap());
+
+        let mut receiver = MessageReceiver::new(config.clone(), 0.1);
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+        assert!(messages(&events).is_empty());
+        assert_eq!(receiver.replays_rejected(), 1);
+
+        let mut receiver =
+            MessageReceiver::new(config, 0.1).with_replay_policy(ReplayPolicy::disabled());
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+        assert_eq!(messages(&events), vec!["recorded last week"]);
+    }
+
+    #[test]
+    fn test_receiver_ignores_frames_for_other_nodes() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut encoder = ProtocolEncoder::new().with_destination(2);
+        let samples = modulator.encode_bytes(&encoder.encode_text("for node 2").unwrap());
+        let node = |id| Address {
+            node_id: Some(id),
+            group: None,
+        };
+
+        let mut receiver = MessageReceiver::new(config.clone(), 0.1).with_address(node(1));
+        let mut events = receiver.push_samples(&samples);
+        events.extend(receiver.flush());
+        assert!(messages(&events).is_empty());
+        assert_eq!(receiver.not_addressed(), 1);
+
+        for mut receiver in [
+            MessageReceiver::new(config.clone(), 0.1).with_address(node(2)),
+            MessageReceiver::new(config.clone(), 0.1)
+                .with_address(node(1))
+                .monitor_all(),
+        ] {
+            let mut events = receiver.push_samples(&samples);
+            events.extend(receiver.flush());
+            assert_eq!(messages(&events), vec!["for node 2"]);
+        }
+    }
+
+    #[test]
+    fn test_coded_frame_survives_noise() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let code = ConvolutionalCode::new(7).unwrap();
+        // A fixed timestamp keeps the frame, and so the outcome, the same every run
+        let mut message = Message::new_text("through the noise", 1).unwrap();
+        message.header.timestamp = 1_700_000_000;
+        message.checksum = Message::calculate_checksum(&message.header, &message.payload).unwrap();
+        let frame = ProtocolEncoder::new().encode_message(&message).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut noisy = |samples: Vec<f32>| -> Vec<f32> {
+            let mut padded = vec![0.0; 2000];
+            padded.extend(samples);
+            padded.extend(vec![0.0; 4410]);
+            padded
+                .into_iter()
+                .map(|s| s + rng.gen_range(-0.8..0.8))
+                .collect()
+        };
+
+        let uncoded = noisy(modulator.encode_bytes(&frame));
+        let mut receiver =
+            MessageReceiver::new(config.clone(), 0.1).with_replay_policy(ReplayPolicy::disabled());
+        let mut events = receiver.push_samples(&uncoded);
+        events.extend(receiver.flush());
+        assert!(messages(&events).is_empty());
+
+        let coded = noisy(modulator.encode_bits(&code.encode_bytes(&frame)));
+        let mut receiver = MessageReceiver::new(config, 0.1)
+            .with_fec(code)
+            .with_replay_policy(ReplayPolicy::disabled());
+        let mut events = receiver.push_samples(&coded);
+        events.extend(receiver.flush());
+        assert_eq!(messages(&events), vec!["through the noise"]);
+    }
+}
@@ -0,0 +1,903 @@
+//! Hiding frames inside existing audio
+//!
+//! [`Embedder`] mixes a modulated frame into a host track. Each symbol is
+//! sent at the loudest level the host is estimated to mask at that moment:
+//! the host spectrum around the symbol is spread across the Bark scale with
+//! Schroeder's spreading function and read off at the symbol's tone. Quiet
+//! passages leave a floor the receiver can still decode, and symbols the host
+//! would flip are read back and raised until they decode.
+//!
+//! With a secret, symbols hop between the channels of the channel plan in an
+//! order derived from it, so the frame is spread over the whole band and can
+//! only be followed by someone who knows the secret. [`Extractor`] searches a
+//! mixed recording for the frame sync pattern along the same hops and decodes
+//! what follows.
+
+use crate::crypto::PresharedKey;
+use crate::error::{UshError, UshResult};
+use crate::fec::ConvolutionalCode;
+use crate::modulation::{CHANNEL_COUNT, Channel, MAX_LLR, ModulationConfig, SoftBit};
+use crate::protocol::{DecoderState, Message, ProtocolDecoder, frame_length, frame_sync};
+use log::debug;
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use sha2::{Digest, Sha256};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Samples the host is analysed over for each symbol: about 23 ms at 44.1 kHz
+const MASKING_BLOCK: usize = 1024;
+/// Mean sync correlation (-1.0 to 1.0) at which a frame is assumed to start
+const SYNC_THRESHOLD: f32 = 0.5;
+/// Candidate start offsets per symbol in the coarse sync search
+const SYNC_STEPS: usize = 8;
+/// Domain separation for the hop sequence
+const HOP_CONTEXT: &[u8] = b"ush stego hops v1";
+/// Symbol contrast (0.0 to 1.0) the embedder makes sure the extractor will see
+const BOOST_CONFIDENCE: f32 = 0.5;
+/// Level added to a symbol each time it reads back too weakly
+const BOOST_STEP_DB: f32 = 2.0;
+/// Times a symbol can be raised, so at most 12 dB over its first level
+const BOOST_ROUNDS: usize = 6;
+
+/// How a frame is shaped into the host
+#[derive(Debug, Clone)]
+pub struct StegoConfig {
+    pub modulation: ModulationConfig,
+    /// How far below the estimated masking threshold symbols are kept, in dB
+    pub margin_db: f32,
+    /// Quietest symbol amplitude, so the frame still decodes over silence
+    pub min_level: f32,
+    /// Loudest symbol amplitude, however much the host masks
+    pub max_level: f32,
+}
+
+impl Default for StegoConfig {
+    fn default() -> Self {
+        Self {
+            modulation: ModulationConfig::default(),
+            margin_db: 6.0,
+            min_level: 0.003,
+            max_level: 0.3,
+        }
+    }
+}
+
+/// A host track with a frame mixed in
+#[derive(Debug, Clone)]
+pub struct Embedding {
+    pub samples: Vec<f32>,
+    /// Sample the frame starts at
+    pub start: usize,
+    /// Amplitude of each symbol
+    pub levels: Vec<f32>,
+    /// Symbols sent louder than the host masks: at `min_level` in quiet
+    /// passages, or raised where the host would otherwise corrupt them
+    pub unmasked: usize,
+    /// Gain applied to the whole mix to keep it from clipping
+    pub gain: f32,
+}
+
+impl Embedding {
+    /// Mean symbol level in dB relative to full scale
+    pub fn mean_level_db(&self) -> f32 {
+        let mean = self.levels.iter().sum::<f32>() / self.levels.len().max(1) as f32;
+        20.0 * mean.max(1e-6).log10()
+    }
+}
+
+/// A frame found by [`Extractor::extract`]. A fragmented message is reported
+/// once, when its last fragment has been read, at its first fragment.
+#[derive(Debug)]
+pub struct ExtractedFrame {
+    pub sample_offset: usize,
+    /// Mean correlation with the frame sync pattern (-1.0 to 1.0)
+    pub sync_score: f32,
+    pub message: UshResult<Message>,
+}
+
+/// Tone pair each symbol of a frame is sent on
+#[derive(Debug, Clone)]
+struct HopPlan {
+    pairs: Vec<(f32, f32)>,
+    seed: Option<[u8; 32]>,
+}
+
+impl HopPlan {
+    fn new(config: &ModulationConfig, secret: Option<&str>) -> Self {
+        match secret {
+            None => Self {
+                pairs: vec![(config.freq_0, config.freq_1)],
+                seed: None,
+            },
+            Some(secret) => Self {
+                pairs: Channel::all().map(Channel::frequencies).collect(),
+                seed: Some(
+                    Sha256::new()
+                        .chain_update(HOP_CONTEXT)
+                        .chain_update(secret.as_bytes())
+                        .finalize()
+                        .into(),
+                ),
+            },
+        }
+    }
+
+    /// Index into `pairs` of the first `count` symbols
+    fn sequence(&self, count: usize) -> Vec<usize> {
+        let Some(seed) = self.seed else {
+            return vec![0; count];
+        };
+        // SHA-256 in counter mode, one byte per symbol; the modulo bias is
+        // one part in 256
+        (0..count.div_ceil(32))
+            .flat_map(|block| {
+                Sha256::new()
+                    .chain_update(seed)
+                    .chain_update((block as u64).to_be_bytes())
+                    .finalize()
+            })
+            .take(count)
+            .map(|byte| (byte % CHANNEL_COUNT) as usize)
+            .collect()
+    }
+
+    /// Highest tone in the plan
+    fn highest(&self) -> f32 {
+        self.pairs
+            .iter()
+            .map(|&(f0, f1)| f0.max(f1))
+            .fold(0.0, f32::max)
+    }
+
+    /// Tone halfway through the plan, which the placement search is done at
+    fn centre(&self) -> f32 {
+        let sum: f32 = self.pairs.iter().map(|&(f0, f1)| f0 + f1).sum();
+        sum / (2 * self.pairs.len()) as f32
+    }
+}
+
+fn check_sample_rate(config: &ModulationConfig, hops: &HopPlan) -> UshResult<()> {
+    if hops.highest() >= config.sample_rate as f32 / 2.0 {
+        return Err(UshError::Config {
+            message: format!(
+                "A sample rate of {} Hz can't carry tones up to {} Hz",
+                config.sample_rate,
+                hops.highest()
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn samples_per_symbol(config: &ModulationConfig) -> usize {
+    (config.sample_rate as f32 * config.symbol_duration) as usize
+}
+
+/// Back-to-back frames, split at the lengths their headers announce
+fn split_frames(data: &[u8]) -> Vec<&[u8]> {
+    let length_field = frame_sync().len();
+    let mut frames = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = match rest.get(length_field..length_field + 2) {
+            Some(&[high, low]) => frame_length(u16::from_be_bytes([high, low]) as usize),
+            _ => rest.len(),
+        };
+        let (frame, tail) = rest.split_at(end.min(rest.len()));
+        frames.push(frame);
+        rest = tail;
+    }
+    frames
+}
+
+/// Bits as sent on air: MSB first, through the convolutional code if any
+fn channel_bits(bytes: &[u8], fec: Option<&ConvolutionalCode>) -> Vec<bool> {
+    match fec {
+        Some(code) => code.encode_bytes(bytes),
+        None => bytes
+            .iter()
+            .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .collect(),
+    }
+}
+
+/// Critical band rate of a frequency, in Bark
+fn bark(freq: f32) -> f32 {
+    13.0 * (0.00076 * freq).atan() + 3.5 * (freq / 7500.0).powi(2).atan()
+}
+
+/// Schroeder's spreading function: level in dB at which a masker `dz` Bark
+/// below the maskee still masks it
+fn spreading_db(dz: f32) -> f32 {
+    let x = dz + 0.474;
+    15.81 + 7.5 * x - 17.5 * (1.0 + x * x).sqrt()
+}
+
+/// Masking threshold of the host at a given moment and frequency
+struct MaskingModel {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Scale from |X|² to mean square, so the bins sum to the block's power
+    scale: f32,
+    bin_bark: Vec<f32>,
+}
+
+impl MaskingModel {
+    fn new(sample_rate: u32) -> Self {
+        let window: Vec<f32> = (0..MASKING_BLOCK)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / MASKING_BLOCK as f32).cos())
+            .collect();
+        let window_energy: f32 = window.iter().map(|w| w * w).sum();
+        let bin_hz = sample_rate as f32 / MASKING_BLOCK as f32;
+
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(MASKING_BLOCK),
+            window,
+            scale: 2.0 / (MASKING_BLOCK as f32 * window_energy),
+            bin_bark: (0..MASKING_BLOCK / 2)
+                .map(|bin| bark(bin as f32 * bin_hz))
+                .collect(),
+        }
+    }
+
+    /// Largest sine amplitude at each of `freqs` the host masks around
+    /// sample `centre`, less `margin_db`
+    fn thresholds(&self, host: &[f32], centre: usize, freqs: &[f32], margin_db: f32) -> Vec<f32> {
+        let start = centre as isize - MASKING_BLOCK as isize / 2;
+        let mut spectrum: Vec<Complex<f32>> = self
+            .window
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| {
+                let sample = usize::try_from(start + i as isize)
+                    .ok()
+                    .and_then(|index| host.get(index))
+                    .copied()
+                    .unwrap_or(0.0);
+                Complex::new(sample * w, 0.0)
+            })
+            .collect();
+        self.fft.process(&mut spectrum);
+
+        let margin = 10f32.powf(-margin_db / 10.0);
+        freqs
+            .iter()
+            .map(|&freq| {
+                let z = bark(freq);
+                let masked: f32 = spectrum[..MASKING_BLOCK / 2]
+                    .iter()
+                    .zip(&self.bin_bark)
+                    .map(|(bin, &masker)| {
+                        bin.norm_sqr() * self.scale * 10f32.powf(spreading_db(z - masker) / 10.0)
+                    })
+                    .sum();
+                // A sine of amplitude A has a mean square of A²/2
+                (2.0 * masked * margin).sqrt()
+            })
+            .collect()
+    }
+}
+
+pub struct Embedder {
+    config: StegoConfig,
+    hops: HopPlan,
+    fec: Option<ConvolutionalCode>,
+}
+
+impl Embedder {
+    pub fn new(config: StegoConfig) -> Self {
+        let hops = HopPlan::new(&config.modulation, None);
+        Self {
+            config,
+            hops,
+            fec: None,
+        }
+    }
+
+    /// Hop between the channels of the channel plan in an order derived from `secret`
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.hops = HopPlan::new(&self.config.modulation, Some(secret));
+        self
+    }
+
+    /// Encode frames with this convolutional code
+    pub fn with_fec(mut self, code: ConvolutionalCode) -> Self {
+        self.fec = Some(code);
+        self
+    }
+
+    /// Mix `frames` into `host` starting at sample `at`, or wherever the host
+    /// masks them best if `at` is `None`. The frames of a fragmented message
+    /// follow each other back to back.
+    pub fn embed(&self, host: &[f32], frames: &[u8], at: Option<usize>) -> UshResult<Embedding> {
+        let modulation = &self.config.modulation;
+        check_sample_rate(modulation, &self.hops)?;
+        let sps = samples_per_symbol(modulation);
+        // Each frame gets its own code tail and hop sequence, as the
+        // extractor reads every frame on its own
+        let (bits, sequence): (Vec<bool>, Vec<usize>) = split_frames(frames)
+            .into_iter()
+            .flat_map(|frame| {
+                let bits = channel_bits(frame, self.fec.as_ref());
+                let sequence = self.hops.sequence(bits.len());
+                bits.into_iter().zip(sequence)
+            })
+            .unzip();
+        let length = bits.len() * sps;
+        if length > host.len() {
+            return Err(UshError::Config {
+                message: format!(
+                    "The host track is {:.1}s long but the frame needs {:.1}s",
+                    host.len() as f32 / modulation.sample_rate as f32,
+                    length as f32 / modulation.sample_rate as f32
+                ),
+            });
+        }
+
+        let masking = MaskingModel::new(modulation.sample_rate);
+        let start = match at {
+            Some(at) => at.min(host.len() - length),
+            None => self.best_start(&masking, host, bits.len()),
+        };
+
+        let tones: Vec<f32> = bits
+            .iter()
+            .zip(&sequence)
+            .map(|(&bit, &pair)| {
+                let (freq_0, freq_1) = self.hops.pairs[pair];
+                if bit { freq_1 } else { freq_0 }
+            })
+            .collect();
+        let masked: Vec<f32> = tones
+            .iter()
+            .enumerate()
+            .map(|(i, &tone)| {
+                let centre = start + i * sps + sps / 2;
+                masking.thresholds(host, centre, &[tone], self.config.margin_db)[0]
+            })
+            .collect();
+
+        // The quieter neighbour bounds each symbol, so a loud masker starting
+        // just after doesn't expose the end of a symbol
+        let bounds: Vec<f32> = (0..masked.len())
+            .map(|i| {
+                let neighbours = &masked[i.saturating_sub(1)..(i + 2).min(masked.len())];
+                neighbours.iter().copied().fold(f32::INFINITY, f32::min)
+            })
+            .collect();
+        let mut levels: Vec<f32> = bounds
+            .iter()
+            .map(|bound| bound.clamp(self.config.min_level, self.config.max_level))
+            .collect();
+
+        // Where the host itself would flip a symbol, raise just that symbol
+        let reader = Extractor {
+            hops: self.hops.clone(),
+            ..Extractor::new(modulation.clone())
+        };
+        let step = 10f32.powf(BOOST_STEP_DB / 20.0);
+        let mut samples = host.to_vec();
+        for round in 0..=BOOST_ROUNDS {
+            let signal = self.synthesize(&tones, &levels, sps);
+            samples[start..start + length].copy_from_slice(&host[start..start + length]);
+            for (sample, &frame_sample) in samples[start..].iter_mut().zip(&signal) {
+                *sample += frame_sample;
+            }
+            if round == BOOST_ROUNDS {
+                break;
+            }
+
+            let mut boosted = false;
+            for (i, (&bit, &pair)) in bits.iter().zip(&sequence).enumerate() {
+                let powers = reader.spectrum(&samples, start + i * sps);
+                let contrast = reader.contrast(&powers, self.hops.pairs[pair]);
+                let margin = if bit { contrast } else { -contrast };
+                if margin < BOOST_CONFIDENCE && levels[i] < self.config.max_level {
+                    levels[i] = (levels[i] * step).min(self.config.max_level);
+                    boosted = true;
+                }
+            }
+            if !boosted {
+                break;
+            }
+        }
+        let unmasked = levels
+            .iter()
+            .zip(&bounds)
+            .filter(|&(level, bound)| level > bound)
+            .count();
+
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let gain = if peak > 1.0 { 1.0 / peak } else { 1.0 };
+        if gain < 1.0 {
+            samples.iter_mut().for_each(|s| *s *= gain);
+        }
+
+        debug!(
+            "Embedded {} symbols at sample {}, {} unmasked",
+            bits.len(),
+            start,
+            unmasked
+        );
+        Ok(Embedding {
+            samples,
+            start,
+            levels,
+            unmasked,
+            gain,
+        })
+    }
+
+    /// Start on the symbol grid where the frame is masked best overall
+    fn best_start(&self, masking: &MaskingModel, host: &[f32], symbols: usize) -> usize {
+        let sps = samples_per_symbol(&self.config.modulation);
+        let slots = host.len() / sps;
+        let freq = self.hops.centre();
+        let (floor, ceiling) = (self.config.min_level, self.config.max_level);
+        let scores: Vec<f32> = (0..slots)
+            .map(|slot| {
+                let level = masking.thresholds(host, slot * sps + sps / 2, &[freq], 0.0)[0];
+                level.clamp(floor, ceiling).ln()
+            })
+            .collect();
+
+        let mut window: f32 = scores[..symbols].iter().sum();
+        let (mut best, mut best_score) = (0, window);
+        for first in 1..=slots - symbols {
+            window += scores[first + symbols - 1] - scores[first - 1];
+            if window > best_score {
+                (best, best_score) = (first, window);
+            }
+        }
+        best * sps
+    }
+
+    /// Sine bursts at each tone, phase continuous while the tone stays the
+    /// same and ramped where it changes, under a level envelope interpolated
+    /// between symbol centres
+    fn synthesize(&self, tones: &[f32], levels: &[f32], sps: usize) -> Vec<f32> {
+        let modulation = &self.config.modulation;
+        let ramp = ((modulation.sample_rate as f32 * modulation.ramp_duration) as usize).max(1);
+        let mut samples = Vec::with_capacity(tones.len() * sps);
+
+        for (i, &tone) in tones.iter().enumerate() {
+            let ramp_in = i == 0 || tones[i - 1] != tone;
+            let ramp_out = i + 1 == tones.len() || tones[i + 1] != tone;
+            for j in 0..sps {
+                let n = i * sps + j;
+                let position = n as f32 / sps as f32 - 0.5;
+                let level = if position <= 0.0 {
+                    levels[0]
+                } else {
+                    let k = (position as usize).min(levels.len() - 1);
+                    let next = levels[(k + 1).min(levels.len() - 1)];
+                    levels[k] + (next - levels[k]) * position.fract()
+                };
+                let mut envelope = level;
+                if ramp_in && j < ramp {
+                    envelope *= j as f32 / ramp as f32;
+                }
+                if ramp_out && j >= sps - ramp {
+                    envelope *= (sps - j) as f32 / ramp as f32;
+                }
+                let t = n as f32 / modulation.sample_rate as f32;
+                samples.push(envelope * (2.0 * PI * tone * t).sin());
+            }
+        }
+        samples
+    }
+}
+
+pub struct Extractor {
+    config: ModulationConfig,
+    hops: HopPlan,
+    fec: Option<ConvolutionalCode>,
+    key: Option<PresharedKey>,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+}
+
+impl Extractor {
+    pub fn new(config: ModulationConfig) -> Self {
+        let sps = samples_per_symbol(&config);
+        let window = (0..sps)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / sps as f32).cos())
+            .collect();
+        Self {
+            hops: HopPlan::new(&config, None),
+            fft: FftPlanner::new().plan_fft_forward(sps.next_power_of_two()),
+            window,
+            config,
+            fec: None,
+            key: None,
+        }
+    }
+
+    /// Follow the hops derived from `secret`
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.hops = HopPlan::new(&self.config, Some(secret));
+        self
+    }
+
+    /// Expect frames encoded with this convolutional code
+    pub fn with_fec(mut self, code: ConvolutionalCode) -> Self {
+        self.fec = Some(code);
+        self
+    }
+
+    /// Decrypt frames with this pre-shared key
+    pub fn with_key(mut self, key: PresharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Every frame found in `samples`, in order
+    pub fn extract(&self, samples: &[f32]) -> UshResult<Vec<ExtractedFrame>> {
+        check_sample_rate(&self.config, &self.hops)?;
+        let sps = samples_per_symbol(&self.config);
+        let step = (sps / SYNC_STEPS).max(1);
+
+        // The sync pattern as sent, less the coded bits the tail would change
+        let mut sync = channel_bits(&frame_sync(), self.fec.as_ref());
+        if let Some(code) = &self.fec {
+            sync.truncate(
+                sync.len()
+                    .saturating_sub(2 * code.constraint_length() as usize),
+            );
+        }
+        let sync_sequence = self.hops.sequence(sync.len());
+        let sync_span = sync.len() * sps;
+        if samples.len() < sync_span {
+            return Ok(Vec::new());
+        }
+
+        // Contrast of every tone pair at every step of the coarse grid
+        let grid: Vec<Vec<f32>> = (0..=(samples.len() - sps) / step)
+            .map(|position| {
+                let powers = self.spectrum(samples, position * step);
+                self.hops
+                    .pairs
+                    .iter()
+                    .map(|&pair| self.contrast(&powers, pair))
+                    .collect()
+            })
+            .collect();
+        let coarse_score = |offset: usize| -> f32 {
+            let total: f32 = sync
+                .iter()
+                .zip(&sync_sequence)
+                .enumerate()
+                .map(|(i, (&bit, &pair))| {
+                    let position = ((offset + i * sps) as f32 / step as f32).round() as usize;
+                    let contrast = grid.get(position).map_or(0.0, |row| row[pair]);
+                    if bit { contrast } else { -contrast }
+                })
+                .sum();
+            total / sync.len() as f32
+        };
+
+        // One decoder for every frame, so fragments are put back together
+        let mut decoder = match &self.key {
+            Some(key) => ProtocolDecoder::new().with_key(key.clone()),
+            None => ProtocolDecoder::new(),
+        }
+        .with_trace();
+        let mut message_start = None;
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset + sync_span <= samples.len() {
+            if coarse_score(offset) < SYNC_THRESHOLD {
+                offset += step;
+                continue;
+            }
+
+            // The repeating preamble also correlates a few symbols early, so
+            // take the best alignment over the length of the sync pattern
+            let search_end = (offset + sync_span).min(samples.len() - sync_span);
+            let coarse = (offset..=search_end)
+                .step_by(step)
+                .max_by(|&a, &b| coarse_score(a).total_cmp(&coarse_score(b)))
+                .unwrap_or(offset);
+            let fine_step = (step / 4).max(1);
+            let (start, sync_score) = (coarse.saturating_sub(step)..=coarse + step)
+                .step_by(fine_step)
+                .filter(|&start| start + sync_span <= samples.len())
+                .map(|start| {
+                    (
+                        start,
+                        self.sync_score(samples, start, &sync, &sync_sequence),
+                    )
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap_or((coarse, 0.0));
+
+            match self.decode_frame(&mut decoder, samples, start) {
+                Some((messages, symbols)) => {
+                    debug!("Frame at sample {} (sync {:.2})", start, sync_score);
+                    let (sample_offset, sync_score) =
+                        *message_start.get_or_insert((start, sync_score));
+                    if !messages.is_empty() {
+                        message_start = None;
+                    }
+                    frames.extend(messages.into_iter().map(|message| ExtractedFrame {
+                        sample_offset,
+                        sync_score,
+                        message,
+                    }));
+                    offset = start + symbols * sps;
+                }
+                None => offset = start + sps,
+            }
+        }
+        Ok(frames)
+    }
+
+    fn sync_score(&self, samples: &[f32], start: usize, sync: &[bool], sequence: &[usize]) -> f32 {
+        let sps = samples_per_symbol(&self.config);
+        let total: f32 = sync
+            .iter()
+            .zip(sequence)
+            .enumerate()
+            .map(|(i, (&bit, &pair))| {
+                let powers = self.spectrum(samples, start + i * sps);
+                let contrast = self.contrast(&powers, self.hops.pairs[pair]);
+                if bit { contrast } else { -contrast }
+            })
+            .sum();
+        total / sync.len() as f32
+    }
+
+    /// Decode the frame starting at `start` through `decoder`, returning the
+    /// messages it completed (none for a fragment held back) with the number
+    /// of symbols it took, or `None` if no frame could be read there
+    fn decode_frame(
+        &self,
+        decoder: &mut ProtocolDecoder,
+        samples: &[f32],
+        start: usize,
+    ) -> Option<(Vec<UshResult<Message>>, usize)> {
+        // Read far enough for the length field first, then the whole frame
+        let header = frame_sync().len() + 2;
+        let (bytes, _) = self.read_bytes(samples, start, header)?;
+        let mut probe = ProtocolDecoder::new();
+        probe.decode(&bytes);
+        if probe.state() != DecoderState::ReadingMessage {
+            return None;
+        }
+
+        let length = frame_length(probe.expected_length());
+        let (bytes, symbols) = self.read_bytes(samples, start, length)?;
+        let mut messages = decoder.decode(&bytes);
+        // A fragment produces nothing either, but without a failure
+        let failure = decoder.take_trace().into_iter().find(|step| step.failure);
+        if messages.is_empty()
+            && let Some(failure) = failure
+        {
+            messages.push(Err(UshError::Decoding {
+                message: format!("frame of {} bytes did not decode: {}", length, failure.note),
+            }));
+        }
+        Some((messages, symbols))
+    }
+
+    /// Demodulate `count` bytes from `start`, or as many as the recording
+    /// holds, along with the symbols they took
+    fn read_bytes(&self, samples: &[f32], start: usize, count: usize) -> Option<(Vec<u8>, usize)> {
+        let sps = samples_per_symbol(&self.config);
+        // With FEC, read a little past the end so the last bytes aren't
+        // decided by a truncated trellis
+        let symbols = match &self.fec {
+            Some(code) => channel_bits(&vec![0; count + 2], Some(code)).len(),
+            None => count * 8,
+        };
+        let available = (samples.len().saturating_sub(start) / sps).min(symbols);
+        let sequence = self.hops.sequence(available);
+        let contrasts: Vec<f32> = sequence
+            .iter()
+            .enumerate()
+            .map(|(i, &pair)| {
+                let powers = self.spectrum(samples, start + i * sps);
+                self.contrast(&powers, self.hops.pairs[pair])
+            })
+            .collect();
+
+        let bytes: Vec<u8> = match &self.fec {
+            Some(code) => {
+                let soft: Vec<SoftBit> = contrasts
+                    .iter()
+                    .map(|&contrast| SoftBit::Llr(contrast * MAX_LLR))
+                    .collect();
+                let mut bytes = code.decode_soft_bytes(&soft);
+                bytes.truncate(count);
+                bytes
+            }
+            None => contrasts
+                .chunks_exact(8)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .fold(0u8, |acc, &c| (acc << 1) | (c > 0.0) as u8)
+                })
+                .collect(),
+        };
+        if bytes.len() < count {
+            return None;
+        }
+        let used = match &self.fec {
+            Some(code) => channel_bits(&vec![0; count], Some(code)).len(),
+            None => count * 8,
+        };
+        Some((bytes, used))
+    }
+
+    /// Power spectrum of the Hann-windowed symbol starting at `offset`
+    fn spectrum(&self, samples: &[f32], offset: usize) -> Vec<f32> {
+        let mut buffer: Vec<Complex<f32>> = self
+            .window
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| Complex::new(samples.get(offset + i).copied().unwrap_or(0.0) * w, 0.0))
+            .collect();
+        buffer.resize(self.fft.len(), Complex::new(0.0, 0.0));
+        self.fft.process(&mut buffer);
+        buffer[..self.fft.len() / 2]
+            .iter()
+            .map(|bin| bin.norm_sqr())
+            .collect()
+    }
+
+    /// How much more power `freq_1` has than `freq_0`, from -1.0 to 1.0
+    fn contrast(&self, powers: &[f32], (freq_0, freq_1): (f32, f32)) -> f32 {
+        let bin_hz = self.config.sample_rate as f32 / self.fft.len() as f32;
+        let tone = |freq: f32| {
+            let centre = (freq / bin_hz).round() as usize;
+            (centre.saturating_sub(1)..=(centre + 1).min(powers.len() - 1))
+                .map(|bin| powers[bin])
+                .fold(0.0f32, f32::max)
+        };
+        let (power_0, power_1) = (tone(freq_0), tone(freq_1));
+        if power_0 + power_1 > 0.0 {
+            (power_1 - power_0) / (power_0 + power_1)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolEncoder;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Something music-like: a harmonic tone with a beat, white noise of
+    /// the given amplitude, and half a second of silence in the middle
+    fn host(seconds: f32, noise: f32) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(48);
+        let len = (44100.0 * seconds) as usize;
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / 44100.0;
+                let tone: f32 = (1..=12)
+                    .map(|h| (2.0 * PI * 220.0 * h as f32 * t).sin() * 0.3 / h as f32)
+                    .sum();
+                let beat = 0.6 + 0.4 * (2.0 * PI * 2.0 * t).sin();
+                let gap = if (len / 2..len / 2 + 22050).contains(&n) {
+                    0.0
+                } else {
+                    1.0
+                };
+                gap * (beat * tone + rng.gen_range(-noise..noise))
+            })
+            .collect()
+    }
+
+    fn frame(text: &str) -> Vec<u8> {
+        ProtocolEncoder::new().encode_text(text).unwrap()
+    }
+
+    fn texts(frames: &[ExtractedFrame]) -> Vec<String> {
+        frames
+            .iter()
+            .filter_map(|frame| frame.message.as_ref().ok()?.get_text().ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_embedded_frame_is_extracted() {
+        let host = host(20.0, 0.01);
+        let embedding = Embedder::new(StegoConfig::default())
+            .embed(&host, &frame("hidden"), None)
+            .unwrap();
+        assert_eq!(embedding.samples.len(), host.len());
+
+        let frames = Extractor::new(ModulationConfig::default())
+            .extract(&embedding.samples)
+            .unwrap();
+        assert_eq!(texts(&frames), vec!["hidden".to_string()]);
+        assert!(frames[0].sample_offset.abs_diff(embedding.start) < 100);
+    }
+
+    #[test]
+    fn test_fragmented_message_is_reassembled() {
+        // Digits keep the serialized fragments, and so the test, short
+        let text: String = (0..260).map(|i| (b'0' + (i % 10) as u8) as char).collect();
+        let frames = frame(&text);
+        assert!(split_frames(&frames).len() > 1);
+
+        let host = host(110.0, 0.01);
+        let embedding = Embedder::new(StegoConfig::default())
+            .with_secret("long")
+            .embed(&host, &frames, Some(44100))
+            .unwrap();
+
+        let extracted = Extractor::new(ModulationConfig::default())
+            .with_secret("long")
+            .extract(&embedding.samples)
+            .unwrap();
+        assert_eq!(texts(&extracted), vec![text]);
+        assert!(extracted[0].sample_offset.abs_diff(embedding.start) < 100);
+    }
+
+    #[test]
+    fn test_levels_follow_the_host() {
+        let host = host(30.0, 0.05);
+        let config = StegoConfig::default();
+        let sps = samples_per_symbol(&config.modulation);
+        let embedder = Embedder::new(config.clone());
+        // Starts just before the gap in the middle of the host
+        let start = host.len() / 2 - 100 * sps;
+        let embedding = embedder
+            .embed(&host, &frame("masked"), Some(start))
+            .unwrap();
+
+        let loud = embedding.levels[20..80]
+            .iter()
+            .copied()
+            .fold(f32::INFINITY, f32::min);
+        let quiet = embedding.levels[110..140]
+            .iter()
+            .copied()
+            .fold(0.0, f32::max);
+        assert_eq!(quiet, config.min_level);
+        assert!(loud > 3.0 * quiet, "loud {} quiet {}", loud, quiet);
+        assert!(embedding.unmasked >= 30);
+        assert!(
+            embedding
+                .levels
+                .iter()
+                .all(|&level| level <= config.max_level)
+        );
+    }
+
+    #[test]
+    fn test_spreading_needs_the_secret() {
+        let host = host(20.0, 0.01);
+        let embedding = Embedder::new(StegoConfig::default())
+            .with_secret("open sesame")
+            .embed(&host, &frame("spread"), None)
+            .unwrap();
+
+        let keyed = Extractor::new(ModulationConfig::default()).with_secret("open sesame");
+        assert_eq!(
+            texts(&keyed.extract(&embedding.samples).unwrap()),
+            vec!["spread".to_string()]
+        );
+
+        let plain = Extractor::new(ModulationConfig::default());
+        assert!(texts(&plain.extract(&embedding.samples).unwrap()).is_empty());
+        let wrong = Extractor::new(ModulationConfig::default()).with_secret("open barley");
+        assert!(texts(&wrong.extract(&embedding.samples).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_hop_sequence_is_keyed() {
+        let config = ModulationConfig::default();
+        let a = HopPlan::new(&config, Some("a")).sequence(100);
+        assert_eq!(a, HopPlan::new(&config, Some("a")).sequence(100));
+        assert_ne!(a, HopPlan::new(&config, Some("b")).sequence(100));
+        assert!(a.iter().all(|&pair| pair < CHANNEL_COUNT as usize));
+        assert_eq!(HopPlan::new(&config, None).sequence(3), vec![0, 0, 0]);
+    }
+}
@@ -0,0 +1,725 @@
+//! Continuous FSK demodulation of streaming audio
+//!
+//! Samples are pushed in arbitrarily sized chunks as they arrive from the
+//! input ring. The demodulator keeps every unconsumed sample between calls,
+//! so a transmission that straddles two chunks is decoded exactly as if it
+//! had arrived in one piece.
+//!
+//! Doppler shift from a moving sender and mismatched sound card clocks move
+//! both tones by the same relative amount. The offset is estimated in ppm from
+//! the preamble when a transmission is acquired, then tracked symbol by symbol
+//! so the tone windows follow the signal for the rest of the frame.
+//!
+//! Speakers and microphones roll off towards 20 kHz, so one tone often arrives
+//! weaker than the other and noise flips its symbols far more often. The
+//! preamble carries both tones equally often, so their powers over it measure
+//! the imbalance. Bits are then decided with the weaker tone weighted up by
+//! half of it in dB, which leaves both tones equally likely to be flipped;
+//! weighting it up by all of it would just move the errors onto the other.
+//!
+//! With a convolutional code configured, soft bits are collected for the
+//! whole transmission and Viterbi-decoded once it ends.
+
+use crate::fec::ConvolutionalCode;
+use crate::modulation::{FskDemodulator, ModulationConfig, SoftBit, SymbolPowers, tone_balance};
+use log::debug;
+use serde::Serialize;
+
+/// Number of preamble symbols used to lock onto the symbol clock
+const ALIGNMENT_SYMBOLS: usize = 16;
+/// Alignment candidates tried per symbol period
+const ALIGNMENT_STEPS: usize = 16;
+/// Consecutive silent symbols that end a transmission
+const END_OF_SIGNAL_SYMBOLS: usize = 4;
+/// Weight of each new symbol in the tracked carrier offset
+const TRACKING_GAIN: f32 = 0.1;
+/// Largest tone imbalance corrected for, in dB
+const MAX_TONE_BALANCE_DB: f32 = 20.0;
+
+/// Upper bound reported for SNR when no noise could be measured
+const MAX_SNR_DB: f32 = 99.0;
+
+/// Link quality measured over the symbols of one transmission so far
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct SignalQuality {
+    /// Dominant tone power relative to the per-bin noise floor, in dB
+    pub snr_db: f32,
+    /// Average frequency error of the received tones, in Hz
+    pub frequency_offset_hz: f32,
+    /// Average carrier offset relative to the nominal tones, in ppm
+    pub frequency_offset_ppm: f32,
+    /// How much weaker `freq_1` arrived than `freq_0` over the preamble, in
+    /// dB. The receiver evens this out; the sender can with pre-emphasis.
+    pub tone_balance_db: f32,
+    /// Symbols the measurement is based on
+    pub symbols: usize,
+}
+
+/// Running totals behind a [`SignalQuality`]
+#[derive(Debug, Default)]
+struct QualityAccumulator {
+    signal_power: f64,
+    noise_power: f64,
+    frequency_offset: f64,
+    offset_ppm: f64,
+    tone_balance_db: f32,
+    symbols: usize,
+}
+
+impl QualityAccumulator {
+    fn add(&mut self, powers: &SymbolPowers) {
+        self.signal_power += powers.signal_power() as f64;
+        self.noise_power += powers.noise_power as f64;
+        self.frequency_offset += powers.frequency_offset as f64;
+        self.offset_ppm += powers.offset_ppm() as f64;
+        self.symbols += 1;
+    }
+
+    fn quality(&self) -> SignalQuality {
+        if self.symbols == 0 {
+            return SignalQuality::default();
+        }
+
+        let snr_db = if self.noise_power > 0.0 {
+            (10.0 * (self.signal_power / self.noise_power).log10()) as f32
+        } else {
+            MAX_SNR_DB
+        };
+
+        SignalQuality {
+            snr_db: snr_db.min(MAX_SNR_DB),
+            frequency_offset_hz: (self.frequency_offset / self.symbols as f64) as f32,
+            frequency_offset_ppm: (self.offset_ppm / self.symbols as f64) as f32,
+            tone_balance_db: self.tone_balance_db,
+            symbols: self.symbols,
+        }
+    }
+}
+
+/// Events produced while demodulating a sample stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// A transmission was detected and aligned at this absolute sample offset
+    SignalDetected { sample_offset: u64 },
+    /// Newly demodulated bytes of the current transmission; with FEC, all of
+    /// them at once just before the transmission ends
+    Data {
+        bytes: Vec<u8>,
+        quality: SignalQuality,
+    },
+    /// The current transmission ended at this absolute sample offset
+    SignalLost {
+        sample_offset: u64,
+        bytes: usize,
+        quality: SignalQuality,
+    },
+}
+
+/// One demodulated symbol of a transmission
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SymbolTrace {
+    /// Absolute sample offset of the symbol's first sample
+    pub sample_offset: u64,
+    pub power_0: f32,
+    pub power_1: f32,
+    /// Per-bin noise floor around the tones
+    pub noise_power: f32,
+    pub bit: bool,
+    /// Soft decision for the symbol, 0 for an erasure
+    pub llr: f32,
+    /// Whether the symbol was strong enough to count as signal
+    pub is_signal: bool,
+}
+
+/// One assembled byte of a transmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteTrace {
+    /// Absolute sample offset of the byte's first symbol
+    pub sample_offset: u64,
+    pub value: u8,
+}
+
+/// Symbols and bytes recorded while tracing is enabled
+#[derive(Debug, Clone, Default)]
+pub struct StreamTrace {
+    pub symbols: Vec<SymbolTrace>,
+    pub bytes: Vec<ByteTrace>,
+}
+
+#[derive(Debug)]
+enum StreamState {
+    Searching,
+    Receiving {
+        /// Absolute sample offset of the first symbol
+        start: u64,
+        bits: Vec<bool>,
+        /// Weak symbols, held back until the signal resumes
+        held: Vec<SymbolPowers>,
+        /// Soft bits of the whole transmission, kept only when decoding FEC
+        soft_bits: Vec<SoftBit>,
+        bytes: usize,
+        quality: QualityAccumulator,
+    },
+}
+
+/// Demodulates a continuous sample stream into transmissions
+pub struct StreamDemodulator {
+    demodulator: FskDemodulator,
+    samples_per_symbol: usize,
+    threshold: f32,
+    buffer: Vec<f32>,
+    /// Absolute sample offset of `buffer[0]`
+    buffer_offset: u64,
+    /// Next unconsumed position within `buffer`
+    position: usize,
+    state: StreamState,
+    fec: Option<ConvolutionalCode>,
+    trace: Option<StreamTrace>,
+}
+
+impl StreamDemodulator {
+    /// Create a stream demodulator.
+    ///
+    /// `threshold` is the fraction of a symbol's power that must be
+    /// concentrated in one of the FSK tones for it to count as signal.
+    pub fn new(config: ModulationConfig, threshold: f32) -> Self {
+        let demodulator = FskDemodulator::new(config);
+        let samples_per_symbol = demodulator.samples_per_symbol();
+
+        Self {
+            demodulator,
+            samples_per_symbol,
+            threshold,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+            position: 0,
+            state: StreamState::Searching,
+            fec: None,
+            trace: None,
+        }
+    }
+
+    /// Record every symbol and byte for [`take_trace`](Self::take_trace)
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(StreamTrace::default());
+        self
+    }
+
+    /// Symbols and bytes recorded since the last call
+    pub fn take_trace(&mut self) -> StreamTrace {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn samples_per_symbol(&self) -> usize {
+        self.samples_per_symbol
+    }
+
+    /// Expect transmissions encoded with this convolutional code
+    pub fn with_fec(mut self, code: ConvolutionalCode) -> Self {
+        self.fec = Some(code);
+        self
+    }
+
+    /// Feed newly captured samples and return everything decoded so far
+    pub fn push_samples(&mut self, samples: &[f32]) -> Vec<StreamEvent> {
+        self.buffer.extend_from_slice(samples);
+        let mut events = Vec::new();
+
+        loop {
+            let progressed = match self.state {
+                StreamState::Searching => self.search(&mut events),
+                StreamState::Receiving { .. } => self.receive(&mut events),
+            };
+
+            if !progressed {
+                break;
+            }
+        }
+
+        self.compact();
+        events
+    }
+
+    /// Finish the current transmission, if any, as though the stream went silent
+    pub fn flush(&mut self) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        if matches!(self.state, StreamState::Receiving { .. }) {
+            self.finish_transmission(&mut events);
+        }
+        events
+    }
+
+    /// Total number of samples pushed so far
+    pub fn samples_seen(&self) -> u64 {
+        self.buffer_offset + self.buffer.len() as u64
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        matches!(self.state, StreamState::Receiving { .. })
+    }
+
+    /// Link quality of the transmission currently being received
+    pub fn quality(&self) -> Option<SignalQuality> {
+        match &self.state {
+            StreamState::Receiving { quality, .. } => Some(quality.quality()),
+            StreamState::Searching => None,
+        }
+    }
+
+    fn is_signal(&self, powers: &SymbolPowers) -> bool {
+        powers.has_signal() && powers.tone_ratio() >= self.threshold
+    }
+
+    fn measure_at(&self, start: usize) -> SymbolPowers {
+        self.demodulator
+            .measure_symbol(&self.buffer[start..start + self.samples_per_symbol])
+    }
+
+    /// Scan for the onset of a transmission and lock onto its symbol clock
+    fn search(&mut self, events: &mut Vec<StreamEvent>) -> bool {
+        let sps = self.samples_per_symbol;
+        let hop = (sps / 4).max(1);
+
+        while self.position + sps <= self.buffer.len() {
+            let powers = self.measure_at(self.position);
+            if !self.is_signal(&powers) {
+                self.position += hop;
+                continue;
+            }
+
+            // The onset lies somewhere around this window; wait until enough
+            // of the preamble has arrived to pick the best symbol alignment.
+            let earliest = self.position.saturating_sub(sps / 2);
+            let latest = self.position + sps;
+            if latest + ALIGNMENT_SYMBOLS * sps > self.buffer.len() {
+                return false;
+            }
+
+            let start = self.best_alignment(earliest, latest);
+            let sample_offset = self.buffer_offset + start as u64;
+            let offset_ppm = self.estimate_offset(start);
+            self.demodulator.set_frequency_offset(Some(offset_ppm));
+            let balance_db = self.estimate_balance(start);
+            self.demodulator
+                .set_tone_balance(Some(10f32.powf(balance_db / 20.0)));
+            debug!(
+                "Signal detected at sample {}, carrier offset {:+.0} ppm, tone balance {:+.1} dB",
+                sample_offset, offset_ppm, balance_db
+            );
+
+            self.position = start;
+            self.state = StreamState::Receiving {
+                start: sample_offset,
+                bits: Vec::with_capacity(8),
+                held: Vec::with_capacity(END_OF_SIGNAL_SYMBOLS),
+                soft_bits: Vec::new(),
+                bytes: 0,
+                quality: QualityAccumulator {
+                    tone_balance_db: balance_db,
+                    ..QualityAccumulator::default()
+                },
+            };
+            events.push(StreamEvent::SignalDetected { sample_offset });
+            return true;
+        }
+
+        false
+    }
+
+    /// Pick the symbol boundary that gives the cleanest tone separation
+    fn best_alignment(&self, earliest: usize, latest: usize) -> usize {
+        let sps = self.samples_per_symbol;
+        let step = (sps / ALIGNMENT_STEPS).max(1);
+        let mut best_start = latest;
+        let mut best_score = f32::MIN;
+
+        for start in (earliest..=latest).step_by(step) {
+            let score: f32 = (0..ALIGNMENT_SYMBOLS)
+                .map(|i| self.measure_at(start + i * sps))
+                .map(|powers| {
+                    if self.is_signal(&powers) {
+                        powers.contrast()
+                    } else {
+                        0.0
+                    }
+                })
+                .sum();
+
+            if score > best_score {
+                best_score = score;
+                best_start = start;
+            }
+        }
+
+        best_start
+    }
+
+    /// Average carrier offset over the preamble symbols starting at `start`
+    fn estimate_offset(&self, start: usize) -> f32 {
+        let sps = self.samples_per_symbol;
+        let offsets: Vec<f32> = (0..ALIGNMENT_SYMBOLS)
+            .map(|i| self.measure_at(start + i * sps))
+            .filter(|powers| self.is_signal(powers))
+            .map(|powers| powers.offset_ppm())
+            .collect();
+
+        if offsets.is_empty() {
+            0.0
+        } else {
+            offsets.iter().sum::<f32>() / offsets.len() as f32
+        }
+    }
+
+    /// How much weaker `freq_1` is than `freq_0` over the preamble symbols
+    /// starting at `start`, in dB
+    fn estimate_balance(&self, start: usize) -> f32 {
+        let sps = self.samples_per_symbol;
+        let symbols: Vec<SymbolPowers> = (0..ALIGNMENT_SYMBOLS)
+            .map(|i| self.measure_at(start + i * sps))
+            .filter(|powers| self.is_signal(powers))
+            .collect();
+
+        tone_balance(&symbols)
+            .map(|balance| {
+                (10.0 * balance.log10()).clamp(-MAX_TONE_BALANCE_DB, MAX_TONE_BALANCE_DB)
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Follow slow drift in the carrier offset, e.g. a sender walking away
+    fn track_offset(&mut self, powers: &SymbolPowers) {
+        let current = self.demodulator.frequency_offset().unwrap_or_default();
+        let updated = current + TRACKING_GAIN * (powers.offset_ppm() - current);
+        self.demodulator.set_frequency_offset(Some(updated));
+    }
+
+    /// Demodulate whole symbols until the buffer runs dry or the signal ends
+    fn receive(&mut self, events: &mut Vec<StreamEvent>) -> bool {
+        let sps = self.samples_per_symbol;
+        let mut new_bytes = Vec::new();
+        let mut ended = false;
+
+        while self.position + sps <= self.buffer.len() {
+            let symbol_offset = self.buffer_offset + self.position as u64;
+            let powers = self.measure_at(self.position);
+            let is_signal = self.is_signal(&powers);
+            self.position += sps;
+            if is_signal {
+                self.track_offset(&powers);
+            }
+            if let Some(trace) = &mut self.trace {
+                trace.symbols.push(SymbolTrace {
+                    sample_offset: symbol_offset,
+                    power_0: powers.power_0,
+                    power_1: powers.power_1,
+                    noise_power: powers.noise_power,
+                    bit: powers.bit(),
+                    llr: powers.soft_bit().llr(),
+                    is_signal,
+                });
+            }
+
+            let StreamState::Receiving {
+                start,
+                bits,
+                held,
+                soft_bits,
+                bytes,
+                quality,
+            } = &mut self.state
+            else {
+                break;
+            };
+
+            if !is_signal {
+                held.push(powers);
+                if held.len() >= END_OF_SIGNAL_SYMBOLS {
+                    ended = true;
+                    break;
+                }
+                continue;
+            }
+
+            quality.add(&powers);
+            if self.fec.is_some() {
+                // Weak symbols inside the frame still carry some information
+                soft_bits.extend(held.drain(..).map(|weak| weak.soft_bit()));
+                soft_bits.push(powers.soft_bit());
+                continue;
+            }
+
+            // A short dropout inside the frame keeps its best-guess bits
+            bits.extend(held.drain(..).map(|weak| weak.bit()));
+            bits.push(powers.bit());
+
+            while bits.len() >= 8 {
+                let byte = bits.drain(..8).fold(0u8, |acc, bit| (acc << 1) | bit as u8);
+                if let Some(trace) = &mut self.trace {
+                    // Every symbol since the start carries one bit
+                    trace.bytes.push(ByteTrace {
+                        sample_offset: *start + (*bytes * 8 * sps) as u64,
+                        value: byte,
+                    });
+                }
+                new_bytes.push(byte);
+                *bytes += 1;
+            }
+        }
+
+        if !new_bytes.is_empty() {
+            events.push(StreamEvent::Data {
+                bytes: new_bytes,
+                quality: self.quality().unwrap_or_default(),
+            });
+        }
+
+        if ended {
+            self.finish_transmission(events);
+            return true;
+        }
+
+        false
+    }
+
+    fn finish_transmission(&mut self, events: &mut Vec<StreamEvent>) {
+        if let (
+            Some(code),
+            StreamState::Receiving {
+                start,
+                soft_bits,
+                bytes,
+                quality,
+                ..
+            },
+        ) = (&self.fec, &mut self.state)
+        {
+            let decoded = code.decode_soft_bytes(soft_bits);
+            if let Some(trace) = &mut self.trace {
+                // Coded bits are spread over 1/rate symbols per data bit
+                let symbols_per_byte = 8.0 / code.rate().ratio();
+                trace
+                    .bytes
+                    .extend(decoded.iter().enumerate().map(|(i, &value)| ByteTrace {
+                        sample_offset: *start
+                            + (i as f32 * symbols_per_byte) as u64 * self.samples_per_symbol as u64,
+                        value,
+                    }));
+            }
+            debug!(
+                "Decoded {} soft bits into {} bytes (rate {}, K={})",
+                soft_bits.len(),
+                decoded.len(),
+                code.rate(),
+                code.constraint_length()
+            );
+            *bytes = decoded.len();
+            if !decoded.is_empty() {
+                events.push(StreamEvent::Data {
+                    bytes: decoded,
+                    quality: quality.quality(),
+                });
+            }
+        }
+
+        if let StreamState::Receiving { bytes, quality, .. } = &self.state {
+            let sample_offset = self.buffer_offset + self.position.min(self.buffer.len()) as u64;
+            debug!(
+                "Signal lost at sample {} after {} bytes",
+                sample_offset, bytes
+            );
+            events.push(StreamEvent::SignalLost {
+                sample_offset,
+                bytes: *bytes,
+                quality: quality.quality(),
+            });
+        }
+        self.state = StreamState::Searching;
+        self.demodulator.set_frequency_offset(None);
+        self.demodulator.set_tone_balance(None);
+    }
+
+    /// Drop consumed samples, keeping enough history to re-align on an onset
+    fn compact(&mut self) {
+        let keep_from = match self.state {
+            StreamState::Searching => self.position.saturating_sub(self.samples_per_symbol),
+            StreamState::Receiving { .. } => self.position,
+        };
+
+        if keep_from > 0 {
+            self.buffer.drain(..keep_from);
+            self.buffer_offset += keep_from as u64;
+            self.position -= keep_from;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::FskModulator;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn collect_data(events: &[StreamEvent]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                StreamEvent::Data { bytes, .. } => Some(bytes.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_decodes_across_chunk_boundaries() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut stream = StreamDemodulator::new(config, 0.1);
+
+        let payload = [0xAA, 0xAA, 0xAA, 0xAA, 0x7E, 0x7E, b'h', b'i'];
+        let mut samples = vec![0.0; 1234];
+        samples.extend(modulator.encode_bytes(&payload));
+        samples.extend(vec![0.0; 4410]);
+
+        // Odd chunk size so that symbols straddle every push
+        let mut events = Vec::new();
+        for chunk in samples.chunks(377) {
+            events.extend(stream.push_samples(chunk));
+        }
+
+        assert!(matches!(
+            events.first(),
+            Some(StreamEvent::SignalDetected { .. })
+        ));
+        assert_eq!(collect_data(&events), payload);
+        match events.last() {
+            Some(StreamEvent::SignalLost { bytes, quality, .. }) => {
+                assert_eq!(*bytes, 8);
+                assert_eq!(quality.symbols, 64);
+                assert!(quality.snr_db > 20.0, "snr {} dB", quality.snr_db);
+                assert!(quality.frequency_offset_hz.abs() < 20.0);
+            }
+            other => panic!("expected SignalLost, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stream_separates_transmissions() {
+        let config = ModulationConfig::default();
+        let modulator = FskModulator::new(config.clone());
+        let mut stream = StreamDemodulator::new(config, 0.1);
+
+        let first = [0xAA, 0xAA, 0x01, 0x02];
+        let second = [0xAA, 0xAA, 0x03, 0x04];
+
+        let mut samples = vec![0.0; 500];
+        samples.extend(modulator.encode_bytes(&first));
+        samples.extend(vec![0.0; 22050]);
+        samples.extend(modulator.encode_bytes(&second));
+        samples.extend(vec![0.0; 2000]);
+
+        let mut events = Vec::new();
+        for chunk in samples.chunks(1000) {
+            events.extend(stream.push_samples(chunk));
+        }
+
+        let detections = events
+            .iter()
+            .filter(|e| matches!(e, StreamEvent::SignalDetected { .. }))
+            .count();
+        assert_eq!(detections, 2);
+        assert_eq!(collect_data(&events), [first, second].concat());
+    }
+
+    /// Modulator whose tones are shifted by `ppm`, as heard from a moving sender
+    fn shifted_modulator(config: &ModulationConfig, ppm: f32) -> FskModulator {
+        let scale = 1.0 + ppm * 1e-6;
+        FskModulator::new(ModulationConfig {
+            freq_0: config.freq_0 * scale,
+            freq_1: config.freq_1 * scale,
+            ..config.clone()
+        })
+    }
+
+    #[test]
+    fn test_stream_estimates_carrier_offset() {
+        let config = ModulationConfig::default();
+        let mut stream = StreamDemodulator::new(config.clone(), 0.1);
+
+        let payload = [0xAA, 0xAA, 0xAA, 0xAA, 0x7E, 0x7E, b'd', b'o', b'p'];
+        let mut samples = vec![0.0; 700];
+        samples.extend(shifted_modulator(&config, 6000.0).encode_bytes(&payload));
+        samples.extend(vec![0.0; 4410]);
+
+        let events = stream.push_samples(&samples);
+
+        assert_eq!(collect_data(&events), payload);
+        match events.last() {
+            Some(StreamEvent::SignalLost { quality, .. }) => {
+                assert!(
+                    (quality.frequency_offset_ppm - 6000.0).abs() < 500.0,
+                    "offset {} ppm",
+                    quality.frequency_offset_ppm
+                );
+            }
+            other => panic!("expected SignalLost, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stream_tracks_drifting_offset() {
+        let config = ModulationConfig::default();
+        let mut stream = StreamDemodulator::new(config.clone(), 0.1);
+
+        // Preamble at +2000 ppm, then the offset keeps growing through the
+        // frame until the tones sit almost three bins from where they began
+        let mut payload = vec![0xAA, 0xAA, 0x7E, 0x7E];
+        let mut samples = vec![0.0; 300];
+        samples.extend(shifted_modulator(&config, 2000.0).encode_bytes(&payload));
+        for (step, chunk) in [b"dr", b"if", b"ti", b"ng", b"!!"].iter().enumerate() {
+            let ppm = 4000.0 + 2000.0 * step as f32;
+            samples.extend(shifted_modulator(&config, ppm).encode_bytes(*chunk));
+            payload.extend_from_slice(*chunk);
+        }
+        samples.extend(vec![0.0; 4410]);
+
+        let mut events = Vec::new();
+        for chunk in samples.chunks(512) {
+            events.extend(stream.push_samples(chunk));
+        }
+
+        assert_eq!(collect_data(&events), payload);
+    }
+
+    #[test]
+    fn test_stream_evens_out_a_weak_tone() {
+        let config = ModulationConfig::default();
+        let mut stream = StreamDemodulator::new(config.clone(), 0.1);
+
+        // freq_1 15 dB down, in noise
+        let modulator = FskModulator::new(config).with_pre_emphasis(-15.0);
+        let payload = [0xAA, 0xAA, 0xAA, 0xAA, 0x7E, 0x7E, b't', b'i', b'l', b't'];
+        let mut samples = vec![0.0; 600];
+        samples.extend(modulator.encode_bytes(&payload));
+        samples.extend(vec![0.0; 4410]);
+        let mut rng = StdRng::seed_from_u64(50);
+        for sample in samples.iter_mut() {
+            *sample += rng.gen_range(-0.05..0.05);
+        }
+
+        let events = stream.push_samples(&samples);
+
+        assert_eq!(collect_data(&events), payload);
+        match events.last() {
+            Some(StreamEvent::SignalLost { quality, .. }) => {
+                assert!(
+                    (quality.tone_balance_db - 15.0).abs() < 2.0,
+                    "balance {} dB",
+                    quality.tone_balance_db
+                );
+            }
+            other => panic!("expected SignalLost, got {:?}", other),
+        }
+    }
+}
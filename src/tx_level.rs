@@ -0,0 +1,207 @@
+//! Adaptive transmit level
+//!
+//! Before each transmission the sender listens to the room and measures the
+//! noise around `freq_0` and `freq_1` with spectrum-analyzer. It then sends at
+//! the quietest level that clears that noise by a target margin, between a
+//! floor and a loudness cap, so a quiet room isn't filled with more
+//! ultrasound than the link needs.
+
+use crate::modulation::{DEFAULT_AMPLITUDE, ModulationConfig};
+use spectrum_analyzer::scaling::divide_by_N;
+use spectrum_analyzer::windows::hann_window;
+use spectrum_analyzer::{FrequencyLimit, samples_fft_to_spectrum};
+use std::time::Duration;
+
+/// How long to listen when carrier sense isn't already doing so
+pub const LISTEN_WINDOW: Duration = Duration::from_millis(200);
+/// Samples per noise measurement: about 46 ms at 44.1 kHz
+const BLOCK_SIZE: usize = 2048;
+/// Noise within this distance of either tone counts
+const BAND_HALF_WIDTH_HZ: f32 = 250.0;
+/// Peak bin magnitude of a unit sine in a Hann-windowed spectrum divided by N
+const SINE_PEAK: f32 = 0.25;
+
+/// How loud frames are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxLevel {
+    /// Always send at this amplitude (0.0-1.0)
+    Fixed(f32),
+    /// Measure the room before each transmission
+    Adaptive(AdaptiveLevel),
+}
+
+impl Default for TxLevel {
+    fn default() -> Self {
+        TxLevel::Adaptive(AdaptiveLevel::default())
+    }
+}
+
+impl TxLevel {
+    /// Amplitude frames are modulated at. Adaptive transmissions are scaled
+    /// down from it once the room has been measured.
+    pub fn amplitude(&self) -> f32 {
+        match self {
+            TxLevel::Fixed(level) => *level,
+            TxLevel::Adaptive(adaptive) => adaptive.max_level,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveLevel {
+    /// How far the tones should stand above the noise around them, as heard
+    /// by this device. It has to cover the loss on the way to the receiver.
+    pub margin_db: f32,
+    /// Quietest amplitude sent, however quiet the room
+    pub min_level: f32,
+    /// Loudest amplitude sent, however noisy the room
+    pub max_level: f32,
+}
+
+impl Default for AdaptiveLevel {
+    fn default() -> Self {
+        Self {
+            margin_db: 40.0,
+            min_level: 0.02,
+            max_level: DEFAULT_AMPLITUDE,
+        }
+    }
+}
+
+/// Noise heard around the tones and the level picked to clear it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelEstimate {
+    /// Amplitude of a sine that would peak as high as the noise
+    pub noise_level: f32,
+    pub level: f32,
+}
+
+impl LevelEstimate {
+    pub fn noise_db(&self) -> f32 {
+        20.0 * self.noise_level.max(1e-9).log10()
+    }
+
+    pub fn level_db(&self) -> f32 {
+        20.0 * self.level.max(1e-9).log10()
+    }
+}
+
+impl AdaptiveLevel {
+    /// Level to send at after hearing `samples`, or `None` if they are too
+    /// short to measure
+    pub fn estimate(
+        &self,
+        samples: &[f32],
+        modulation: &ModulationConfig,
+    ) -> Option<LevelEstimate> {
+        let noise_level = noise_level(samples, modulation)?;
+        // The cap wins if it is set below the floor
+        let level = (noise_level * 10f32.powf(self.margin_db / 20.0))
+            .max(self.min_level)
+            .min(self.max_level);
+        Some(LevelEstimate { noise_level, level })
+    }
+}
+
+/// RMS magnitude of the bins around the tones, as a sine amplitude
+fn noise_level(samples: &[f32], modulation: &ModulationConfig) -> Option<f32> {
+    let tones = [modulation.freq_0, modulation.freq_1];
+    let nyquist = modulation.sample_rate as f32 / 2.0;
+    let low = (modulation.freq_0.min(modulation.freq_1) - BAND_HALF_WIDTH_HZ).max(0.0);
+    let high = (modulation.freq_0.max(modulation.freq_1) + BAND_HALF_WIDTH_HZ).min(nyquist);
+
+    let mut power = 0.0;
+    let mut bins = 0;
+    for block in samples.chunks_exact(BLOCK_SIZE) {
+        let Ok(spectrum) = samples_fft_to_spectrum(
+            &hann_window(block),
+            modulation.sample_rate,
+            FrequencyLimit::Range(low, high),
+            Some(&divide_by_N),
+        ) else {
+            continue;
+        };
+        for (freq, value) in spectrum.data() {
+            if tones
+                .iter()
+                .any(|tone| (freq.val() - tone).abs() <= BAND_HALF_WIDTH_HZ)
+            {
+                power += value.val() * value.val();
+                bins += 1;
+            }
+        }
+    }
+
+    (bins > 0).then(|| (power / bins as f32).sqrt() / SINE_PEAK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn config() -> ModulationConfig {
+        ModulationConfig {
+            sample_rate: 44100,
+            freq_0: 18000.0,
+            freq_1: 20000.0,
+            symbol_duration: 0.01,
+            ramp_duration: 0.002,
+        }
+    }
+
+    fn noise(amplitude: f32) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(49);
+        (0..8820)
+            .map(|_| rng.gen_range(-amplitude..amplitude))
+            .collect()
+    }
+
+    #[test]
+    fn test_level_follows_the_noise() {
+        let adaptive = AdaptiveLevel {
+            margin_db: 20.0,
+            min_level: 0.0,
+            ..AdaptiveLevel::default()
+        };
+        let quiet = adaptive.estimate(&noise(0.001), &config()).unwrap();
+        let loud = adaptive.estimate(&noise(0.004), &config()).unwrap();
+
+        // Four times the noise is 12 dB more
+        assert!((loud.noise_db() - quiet.noise_db() - 12.0).abs() < 1.0);
+        assert!((quiet.level_db() - quiet.noise_db() - 20.0).abs() < 1e-3);
+        assert!(loud.level > quiet.level);
+    }
+
+    #[test]
+    fn test_level_stays_between_floor_and_cap() {
+        let adaptive = AdaptiveLevel::default();
+        let silent = adaptive.estimate(&vec![0.0; 8820], &config()).unwrap();
+        assert_eq!(silent.level, adaptive.min_level);
+        let noisy = adaptive.estimate(&noise(0.5), &config()).unwrap();
+        assert_eq!(noisy.level, adaptive.max_level);
+
+        assert_eq!(adaptive.estimate(&noise(0.5)[..100], &config()), None);
+    }
+
+    #[test]
+    fn test_tone_in_band_reads_at_its_amplitude() {
+        let tone: Vec<f32> = (0..BLOCK_SIZE)
+            .map(|i| 0.1 * (2.0 * std::f32::consts::PI * 18000.0 * i as f32 / 44100.0).sin())
+            .collect();
+        let spectrum = samples_fft_to_spectrum(
+            &hann_window(&tone),
+            44100,
+            FrequencyLimit::Range(17000.0, 19000.0),
+            Some(&divide_by_N),
+        )
+        .unwrap();
+        let peak = spectrum.max().1.val();
+        assert!(
+            (peak / SINE_PEAK - 0.1).abs() < 0.01,
+            "{}",
+            peak / SINE_PEAK
+        );
+    }
+}
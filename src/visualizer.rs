@@ -0,0 +1,438 @@
+//! Text rendering of live audio for `ush debug`
+//!
+//! [`Visualizer`] keeps the most recent samples and turns them into plain
+//! text lines: a bar-graph spectrum of the ultrasonic band with `freq_0` and
+//! `freq_1` marked, a scrolling waterfall, an oscilloscope trace and a log of
+//! receive events. Drawing the lines on the terminal is left to the caller.
+
+use crate::carrier::{band_power, noise_floor};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+const FFT_SIZE: usize = 2048;
+/// Lowest frequency shown in the spectrum and waterfall
+const BAND_START: f32 = 16000.0;
+/// Bins either side of each tone counted as in-band for the SNR
+const BAND_HALF_WIDTH: usize = 3;
+/// Levels shown between the bottom and the top of a spectrum bar
+const FLOOR_DB: f32 = -90.0;
+const CEILING_DB: f32 = -10.0;
+/// Characters of increasing intensity for the waterfall
+const WATERFALL_RAMP: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+const BAR_BLOCKS: &[char] = &[' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const MAX_EVENTS: usize = 64;
+/// Time span of the oscilloscope trace, long enough to see symbol bursts
+const SCOPE_SECONDS: f32 = 0.5;
+/// Smallest full-scale of the oscilloscope, so silence stays flat
+const MIN_SCOPE_SCALE: f32 = 0.02;
+
+/// Which panels to draw
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Panels {
+    pub spectrum: bool,
+    pub waveform: bool,
+}
+
+impl Panels {
+    /// The panels picked with `--spectrum` and `--waveform`; neither means both
+    pub fn from_flags(spectrum: bool, waveform: bool) -> Self {
+        if spectrum || waveform {
+            Self { spectrum, waveform }
+        } else {
+            Self {
+                spectrum: true,
+                waveform: true,
+            }
+        }
+    }
+}
+
+pub struct Visualizer {
+    sample_rate: u32,
+    freq_0: f32,
+    freq_1: f32,
+    panels: Panels,
+    fft: Arc<dyn Fft<f32>>,
+    /// The last `FFT_SIZE` samples
+    recent: VecDeque<f32>,
+    /// The last `SCOPE_SECONDS` of samples
+    scope: VecDeque<f32>,
+    scope_len: usize,
+    /// Power per FFT bin of the latest frame
+    spectrum: Vec<f32>,
+    /// Past spectra in dB, newest first, already reduced to screen columns
+    waterfall: VecDeque<Vec<f32>>,
+    waterfall_width: usize,
+    events: VecDeque<String>,
+    rms_db: f32,
+    snr_db: f32,
+}
+
+impl Visualizer {
+    pub fn new(sample_rate: u32, freq_0: f32, freq_1: f32, panels: Panels) -> Self {
+        Self {
+            sample_rate,
+            freq_0,
+            freq_1,
+            panels,
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            recent: VecDeque::with_capacity(FFT_SIZE),
+            scope: VecDeque::new(),
+            scope_len: (sample_rate as f32 * SCOPE_SECONDS) as usize,
+            spectrum: vec![0.0; FFT_SIZE / 2],
+            waterfall: VecDeque::new(),
+            waterfall_width: 0,
+            events: VecDeque::new(),
+            rms_db: FLOOR_DB,
+            snr_db: 0.0,
+        }
+    }
+
+    /// Keep the newest samples for the next [`update`](Self::update)
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        keep_latest(&mut self.recent, samples, FFT_SIZE);
+        keep_latest(&mut self.scope, samples, self.scope_len);
+    }
+
+    /// Add a line to the event log
+    pub fn log_event(&mut self, event: String) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Recompute the spectrum, levels and waterfall from the newest samples
+    pub fn update(&mut self) {
+        let mut buffer: Vec<Complex<f32>> = self
+            .recent
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| {
+                // Hann window against leakage from the audible band
+                let window =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos();
+                Complex::new(s * window, 0.0)
+            })
+            .collect();
+        buffer.resize(FFT_SIZE, Complex::new(0.0, 0.0));
+        self.fft.process(&mut buffer);
+
+        let tone_bins = [self.bin_of(self.freq_0), self.bin_of(self.freq_1)];
+        let signal = band_power(&buffer, tone_bins[0], BAND_HALF_WIDTH)
+            + band_power(&buffer, tone_bins[1], BAND_HALF_WIDTH);
+        let noise = noise_floor(&buffer, &tone_bins);
+        self.snr_db = if signal > 0.0 && noise > 0.0 {
+            10.0 * (signal / noise).log10()
+        } else {
+            0.0
+        };
+
+        let mean_square = if self.recent.is_empty() {
+            0.0
+        } else {
+            self.recent.iter().map(|s| s * s).sum::<f32>() / self.recent.len() as f32
+        };
+        self.rms_db = to_db(mean_square).max(FLOOR_DB);
+
+        // Scale so a full-scale sine reads about 0 dB after the Hann window
+        let scale = (FFT_SIZE as f32 / 4.0).powi(2);
+        for (power, bin) in self.spectrum.iter_mut().zip(&buffer) {
+            *power = bin.norm_sqr() / scale;
+        }
+
+        if self.waterfall_width > 0 {
+            let row = self.columns(self.waterfall_width);
+            self.waterfall.push_front(row);
+        }
+    }
+
+    /// RMS level of the newest samples, in dBFS
+    pub fn rms_db(&self) -> f32 {
+        self.rms_db
+    }
+
+    /// Tone power over the noise floor, in dB
+    pub fn snr_db(&self) -> f32 {
+        self.snr_db
+    }
+
+    fn bin_of(&self, freq: f32) -> usize {
+        (freq * FFT_SIZE as f32 / self.sample_rate as f32).round() as usize
+    }
+
+    fn band(&self) -> (f32, f32) {
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let start = BAND_START
+            .min(self.freq_0.min(self.freq_1) - 1000.0)
+            .max(0.0);
+        (start, nyquist)
+    }
+
+    fn column_of(&self, freq: f32, width: usize) -> usize {
+        let (start, end) = self.band();
+        let fraction = ((freq - start) / (end - start)).clamp(0.0, 1.0);
+        ((fraction * width as f32) as usize).min(width.saturating_sub(1))
+    }
+
+    /// Peak level in dB of the bins that fall into each of `width` columns
+    fn columns(&self, width: usize) -> Vec<f32> {
+        let (start, end) = self.band();
+        let bin_hz = self.sample_rate as f32 / FFT_SIZE as f32;
+        (0..width)
+            .map(|column| {
+                let low = start + (end - start) * column as f32 / width as f32;
+                let high = start + (end - start) * (column + 1) as f32 / width as f32;
+                let first = (low / bin_hz) as usize;
+                let last = ((high / bin_hz) as usize).max(first + 1);
+                let peak = self.spectrum
+                    [first.min(self.spectrum.len() - 1)..last.min(self.spectrum.len())]
+                    .iter()
+                    .fold(0.0f32, |a, &b| a.max(b));
+                to_db(peak)
+            })
+            .collect()
+    }
+
+    /// Render a full screen of `width` x `height` characters, one string per line
+    pub fn render(&mut self, width: usize, height: usize) -> Vec<String> {
+        let width = width.max(20);
+        let mut lines = vec![format!(
+            "ush debug  {} Hz  tones {:.0}/{:.0} Hz  RMS {:6.1} dBFS  SNR {:5.1} dB  (q to quit)",
+            self.sample_rate, self.freq_0, self.freq_1, self.rms_db, self.snr_db
+        )];
+
+        let event_rows = 5.min(height / 4);
+        let available = height.saturating_sub(lines.len() + event_rows + 1);
+        let (spectrum_rows, waterfall_rows, waveform_rows) =
+            match (self.panels.spectrum, self.panels.waveform) {
+                (true, true) => {
+                    let spectrum = available * 2 / 5;
+                    let waterfall = available / 5;
+                    (spectrum, waterfall, available - spectrum - waterfall)
+                }
+                (true, false) => (available * 3 / 5, available - available * 3 / 5, 0),
+                (false, true) => (0, 0, available),
+                (false, false) => (0, 0, 0),
+            };
+
+        if self.panels.spectrum && spectrum_rows > 2 {
+            self.waterfall_width = width;
+            lines.extend(self.render_spectrum(width, spectrum_rows - 1));
+            lines.push(self.render_markers(width));
+            lines.extend(self.render_waterfall(width, waterfall_rows));
+        }
+        if self.panels.waveform && waveform_rows > 1 {
+            lines.extend(self.render_waveform(width, waveform_rows));
+        }
+
+        // Keep the event log at the bottom of the screen
+        lines.resize(height.saturating_sub(event_rows + 1), String::new());
+        lines.push("─".repeat(width));
+        let skip = self.events.len().saturating_sub(event_rows);
+        lines.extend(self.events.iter().skip(skip).cloned());
+        lines.resize(height, String::new());
+
+        lines.into_iter().map(|line| fit(&line, width)).collect()
+    }
+
+    fn render_spectrum(&self, width: usize, rows: usize) -> Vec<String> {
+        let levels: Vec<f32> = self
+            .columns(width)
+            .into_iter()
+            .map(|db| ((db - FLOOR_DB) / (CEILING_DB - FLOOR_DB)).clamp(0.0, 1.0) * rows as f32)
+            .collect();
+
+        (0..rows)
+            .rev()
+            .map(|row| {
+                levels
+                    .iter()
+                    .map(|&level| {
+                        let fill = ((level - row as f32) * 8.0).clamp(0.0, 8.0) as usize;
+                        BAR_BLOCKS[fill]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Frequency axis with the two FSK tones marked
+    fn render_markers(&self, width: usize) -> String {
+        let (start, end) = self.band();
+        let mut axis: Vec<char> = vec!['─'; width];
+        let mut label = |freq: f32, mark: char| {
+            axis[self.column_of(freq, width)] = mark;
+        };
+        label(self.freq_0, '0');
+        label(self.freq_1, '1');
+
+        let left = format!("{:.1}k", start / 1000.0);
+        let right = format!("{:.1}k", end / 1000.0);
+        for (i, c) in left.chars().enumerate().take(width) {
+            if axis[i] == '─' {
+                axis[i] = c;
+            }
+        }
+        for (i, c) in right.chars().enumerate() {
+            let column = width.saturating_sub(right.len()) + i;
+            if column < width && axis[column] == '─' {
+                axis[column] = c;
+            }
+        }
+        axis.into_iter().collect()
+    }
+
+    fn render_waterfall(&mut self, width: usize, rows: usize) -> Vec<String> {
+        self.waterfall.truncate(rows);
+        let mut lines: Vec<String> = self
+            .waterfall
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .take(width)
+                    .map(|&db| {
+                        let level = ((db - FLOOR_DB) / (CEILING_DB - FLOOR_DB)).clamp(0.0, 1.0);
+                        WATERFALL_RAMP[(level * (WATERFALL_RAMP.len() - 1) as f32).round() as usize]
+                    })
+                    .collect()
+            })
+            .collect();
+        lines.resize(rows, String::new());
+        lines
+    }
+
+    /// Oscilloscope trace of the last half second, min to max per column
+    fn render_waveform(&self, width: usize, rows: usize) -> Vec<String> {
+        let samples: Vec<f32> = self.scope.iter().copied().collect();
+        let peak = samples
+            .iter()
+            .fold(0.0f32, |a, &b| a.max(b.abs()))
+            .max(MIN_SCOPE_SCALE);
+        let slice = samples.len().div_ceil(width).max(1);
+        let row_of = |value: f32| {
+            let fraction = (1.0 - value / peak) / 2.0;
+            ((fraction * (rows - 1) as f32).round() as usize).min(rows - 1)
+        };
+
+        let mut grid = vec![vec![' '; width]; rows];
+        for (column, chunk) in samples.chunks(slice).enumerate().take(width) {
+            let (low, high) = chunk
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(lo, hi), &s| (lo.min(s), hi.max(s)));
+            let (top, bottom) = (row_of(high), row_of(low));
+            let mark = if top == bottom { '─' } else { '│' };
+            for row in grid.iter_mut().take(bottom + 1).skip(top) {
+                row[column] = mark;
+            }
+        }
+        if rows > 2 {
+            let middle = rows / 2;
+            for cell in grid[middle].iter_mut().filter(|c| **c == ' ') {
+                *cell = '·';
+            }
+        }
+
+        grid.into_iter()
+            .map(|row| row.into_iter().collect())
+            .collect()
+    }
+}
+
+/// Append `samples` to `buffer`, dropping the oldest beyond `capacity`
+fn keep_latest(buffer: &mut VecDeque<f32>, samples: &[f32], capacity: usize) {
+    let skip = samples.len().saturating_sub(capacity);
+    buffer.extend(&samples[skip..]);
+    let excess = buffer.len().saturating_sub(capacity);
+    buffer.drain(..excess);
+}
+
+fn to_db(power: f32) -> f32 {
+    if power > 0.0 {
+        10.0 * power.log10()
+    } else {
+        FLOOR_DB
+    }
+}
+
+/// Pad or cut a line to exactly `width` characters
+fn fit(line: &str, width: usize) -> String {
+    let mut fitted: String = line.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * freq * i as f32 / 44100.0).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn test_levels_of_a_tone() {
+        let mut visualizer =
+            Visualizer::new(44100, 18000.0, 20000.0, Panels::from_flags(false, false));
+        visualizer.push_samples(&tone(20000.0, 0.3, 4096));
+        visualizer.update();
+
+        // A sine's RMS is 3 dB below its peak
+        assert!((visualizer.rms_db() - 20.0 * 0.3f32.log10() + 3.0).abs() < 0.5);
+        assert!(visualizer.snr_db() > 30.0, "snr {}", visualizer.snr_db());
+    }
+
+    #[test]
+    fn test_render_marks_tones_and_peaks() {
+        let mut visualizer =
+            Visualizer::new(44100, 18000.0, 20000.0, Panels::from_flags(true, false));
+        visualizer.render(80, 30);
+        visualizer.push_samples(&tone(20000.0, 0.3, 2048));
+        visualizer.update();
+        let lines = visualizer.render(80, 30);
+
+        assert_eq!(lines.len(), 30);
+        assert!(lines.iter().all(|line| line.chars().count() == 80));
+
+        // Bars sit between the header and the frequency axis
+        let column_0 = visualizer.column_of(18000.0, 80);
+        let column_1 = visualizer.column_of(20000.0, 80);
+        let axis = lines.iter().position(|line| line.contains('─')).unwrap();
+        assert_eq!(lines[axis].chars().nth(column_0), Some('0'));
+        assert_eq!(lines[axis].chars().nth(column_1), Some('1'));
+
+        let height_at = |column: usize| {
+            lines[1..axis]
+                .iter()
+                .filter(|line| line.chars().nth(column) != Some(' '))
+                .count()
+        };
+        assert!(height_at(column_1) > (axis - 1) / 2);
+        assert!(height_at(column_1) > height_at(column_0) + 3);
+    }
+
+    #[test]
+    fn test_waterfall_scrolls_and_events_are_kept() {
+        let mut visualizer =
+            Visualizer::new(44100, 18000.0, 20000.0, Panels::from_flags(true, true));
+        visualizer.render(60, 40);
+        for _ in 0..100 {
+            visualizer.push_samples(&tone(18000.0, 0.3, 441));
+            visualizer.update();
+        }
+        for i in 0..10 {
+            visualizer.log_event(format!("event {}", i));
+        }
+
+        let lines = visualizer.render(60, 40);
+        assert_eq!(lines.len(), 40);
+        assert!(visualizer.waterfall.len() < 100);
+        assert!(lines.last().unwrap().starts_with("event 9"));
+        assert!(!lines.iter().any(|line| line.starts_with("event 0")));
+    }
+}
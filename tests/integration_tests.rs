@@ -136,7 +136,7 @@ async fn test_different_message_lengths() -> UshResult<()> {
     let mut encoder = ProtocolEncoder::new();
     let mut decoder = ProtocolDecoder::new();
 
-    let test_messages = vec![
+    let test_messages = [
         "A",                                                     // Very short
         "Hello",                                                 // Short
         "This is a medium-length message for testing purposes.", // Medium
@@ -168,7 +168,7 @@ async fn test_different_message_lengths() -> UshResult<()> {
 #[tokio::test]
 async fn test_frequency_separation() -> UshResult<()> {
     // Test different frequency configurations
-    let configs = vec![
+    let configs = [
         (17000.0, 19000.0), // Wide separation
         (18500.0, 19500.0), // Narrow separation
         (19000.0, 21000.0), // High frequencies